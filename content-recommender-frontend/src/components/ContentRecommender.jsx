@@ -94,10 +94,6 @@ export default function ContentRecommender() {
                 method: 'POST',
                 headers: {
                     'Content-Type': 'application/json',
-                    'Access-Control-Allow-Origin': '*',
-                    'Access-Control-Allow-Credentials': 'true',
-                    'Access-Control-Allow-Headers': 'Content-Type',
-                    'Access-Control-Allow-Methods': 'POST,DELETE',
                 },
                 body: JSON.stringify(preferences),
             });
@@ -109,7 +105,7 @@ export default function ContentRecommender() {
             const data = await response.json();
             setRecommendations(prev => ({
                 ...prev,
-                [preferences.content_type]: data
+                [preferences.content_type]: data.items
             }));
         } catch (err) {
             setError(err.message);
@@ -135,7 +131,7 @@ export default function ContentRecommender() {
                 {isActive && content.length > 0 && (
                     <div className="grid gap-6 sm:grid-cols-2">
                         {content.map((item, index) => (
-                            <Card key={index} className="backdrop-blur bg-card/50">
+                            <Card key={item.id ?? index} className="backdrop-blur bg-card/50">
                                 <CardHeader>
                                     <CardTitle className="text-lg line-clamp-1">{item.title}</CardTitle>
                                     <div className="text-sm text-muted-foreground">
@@ -1,5 +1,8 @@
-use futures_util::StreamExt;
-use actix_web::{web, App, HttpResponse, HttpServer};
+use futures_util::{StreamExt, TryStreamExt};
+use actix_web::{web, App, HttpRequest, HttpResponse, HttpServer};
+use actix_web::body::MessageBody;
+use actix_web::dev::{ServiceRequest, ServiceResponse};
+use actix_web::middleware::{from_fn, Next};
 use actix_cors::Cors;
 use azure_storage_blobs::prelude::*;
 use azure_storage_blobs::prelude::*;
@@ -9,469 +12,3282 @@ use tokio;
 use std::env;
 use std::collections::{HashMap, HashSet};
 use std::sync::Arc;
-use parking_lot::RwLock;
+use parking_lot::{Mutex, RwLock};
 use anyhow::Result;
 use dotenv::dotenv;
 use std::io::Write;
 use azure_identity;
-use azure_storage::StorageCredentials;
+use azure_storage::{CloudLocation, StorageCredentials};
 use url::Url;
 
 #[derive(Debug, Serialize, Deserialize, Clone)]
 struct Content {
+    // Stable key unique across media types, e.g. "movie:12345"
+    #[serde(default)]
+    id: String,
     title: String,
+    // Title in the original language, when TMDB has one
+    #[serde(default)]
+    original_title: Option<String>,
+    // The localized title, set only in responses where prefer_original_title
+    // replaced it
+    #[serde(default, skip_deserializing, skip_serializing_if = "Option::is_none")]
+    localized_title: Option<String>,
     year: Option<String>,
+    // Full YYYY-MM-DD release (movies) or first air (tv) date
+    #[serde(default)]
+    release_date: Option<String>,
     rating: Option<f32>,
+    // Rating pulled toward the catalog mean when there are few votes; see weighted_rating
+    #[serde(default)]
+    quality_score: f32,
     genre: Vec<String>,
+    // ISO 639-1 codes of the languages spoken in the item, including dubs TMDB
+    // knows about. Empty for items that weren't fully enriched.
+    #[serde(default)]
+    spoken_languages: Vec<String>,
     description: String,
     where_to_watch: Vec<String>,
+    #[serde(default)]
+    in_theaters: bool,
+    #[serde(default)]
+    tmdb_id: i64,
+    // e.g. "tt0111161", for items that were fully enriched and that IMDb lists
+    #[serde(default)]
+    imdb_id: Option<String>,
+    // Top billed cast and TMDB keywords of fully enriched items
+    #[serde(default)]
+    cast: Vec<String>,
+    #[serde(default)]
+    keywords: Vec<String>,
+    // TMDB production status of fully enriched items, e.g. "Released",
+    // "Post Production", "Returning Series" or "Ended"
+    #[serde(default)]
+    status: Option<String>,
+    #[serde(default)]
+    media_type: String,
+    #[serde(default)]
+    providers: Vec<Provider>,
+    trailer_url: Option<String>,
+    // Full poster URL, or DEFAULT_POSTER_URL when TMDB has no poster
+    #[serde(default)]
+    poster_url: Option<String>,
+    // TMDB watch page per region code, for every region the item has providers in.
+    // Ordered so the catalog's content hash is stable.
+    #[serde(default)]
+    watch_links: std::collections::BTreeMap<String, String>,
+    // Streaming provider names per region, for WATCH_REGION and PROVIDER_REGIONS.
+    // where_to_watch is these flattened by PROVIDER_FLATTENING.
+    #[serde(default)]
+    region_providers: std::collections::BTreeMap<String, Vec<String>>,
+    popularity: Option<f32>,
+    #[serde(default)]
+    vote_count: Option<i64>,
+    #[serde(default)]
+    fetched_at: Option<chrono::DateTime<chrono::Utc>>,
+    // TMDB lists the item was scraped from, e.g. "trending/movie/week"
+    #[serde(default)]
+    sources: Vec<String>,
+    // Provenance and scoring, only present in ?debug=true responses
+    #[serde(default, skip_deserializing, skip_serializing_if = "Option::is_none")]
+    debug: Option<ContentDebug>,
 }
 
-#[derive(Debug, Serialize, Deserialize)]
+#[derive(Debug, Serialize, Clone)]
+struct ContentDebug {
+    tmdb_id: i64,
+    sources: Vec<String>,
+    fetched_at: Option<chrono::DateTime<chrono::Utc>>,
+    score: ScoreBreakdown,
+    // Whether the user had already been shown this item, which only happens after
+    // their used set is reset for running low
+    previously_shown: bool,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+struct Provider {
+    name: String,
+    provider_type: String,
+    provider_link: Option<String>,
+    // TMDB's ranking of the provider in this region, lower is more prominent
+    display_priority: Option<i64>,
+    // Full URL of the service's logo
+    #[serde(default)]
+    logo_path: Option<String>,
+}
+
+fn content_id(media_type: &str, tmdb_id: i64) -> String {
+    format!("{}:{}", media_type, tmdb_id)
+}
+
+// Read a numeric TMDB field whether it arrives as a float, an integer or a numeric string
+fn json_number(value: &Value) -> Option<f32> {
+    match value {
+        Value::Number(n) => n.as_f64().or_else(|| n.as_i64().map(|i| i as f64)).map(|n| n as f32),
+        Value::String(s) => s.trim().parse::<f32>().ok(),
+        _ => None,
+    }
+}
+
+// Names of the providers an item can be streamed on for free or by subscription
+fn streaming_provider_names(providers: &[Provider]) -> Vec<String> {
+    providers.iter()
+        .filter(|p| p.provider_type == "flatrate" || p.provider_type == "free")
+        .map(|p| p.name.clone())
+        .collect()
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
 struct UserPreferences {
     favorite_genres: Vec<String>,
+    #[serde(default)]
     minimum_rating: f32,
+    #[serde(default)]
+    in_theaters_only: bool,
+    #[serde(default = "default_true")]
+    allow_fallback: bool,
+    #[serde(default)]
+    user_id: Option<String>,
+    #[serde(default)]
+    exclude_watchlisted: bool,
+    // Drop items with no overview text
+    #[serde(default)]
+    require_description: bool,
+    // Drop items whose overview is shorter than this many characters
+    #[serde(default)]
+    min_description_len: Option<usize>,
+    // Also match genres adjacent to the favorites, e.g. Adventure for Action
+    #[serde(default)]
+    expand_genres: bool,
+    // At most this many results whose primary (most prominent) streaming provider is
+    // the same service. Items with no streaming provider aren't capped.
+    #[serde(default)]
+    max_per_provider: Option<usize>,
+    // How results are chosen, overriding RECOMMENDATION_STRATEGY
+    #[serde(default)]
+    strategy: Option<StrategyKind>,
+    // Release year bounds, inclusive. YEARLESS_ITEMS decides whether undated items pass.
+    #[serde(default)]
+    min_year: Option<i32>,
+    #[serde(default)]
+    max_year: Option<i32>,
+    // Favor items that have been shown less often across all users
+    #[serde(default)]
+    fair_exposure: bool,
+    // Drop items whose quality_score is below this
+    #[serde(default)]
+    min_quality: Option<f32>,
+    // Only items spoken in at least one of these ISO 639-1 languages, e.g. ["en", "es"].
+    // Items with no known spoken languages are dropped when this is set.
+    #[serde(default)]
+    spoken_languages: Vec<String>,
+    // Drop items with any of these TMDB statuses, e.g. ["Ended", "Canceled"].
+    // Items with no known status are kept.
+    #[serde(default)]
+    exclude_statuses: Vec<String>,
+    // Show titles in their original language instead of the localized title
+    #[serde(default)]
+    prefer_original_title: bool,
 }
 
-// Add this new struct for tracking already seen content
-#[derive(Debug)]
-struct ContentTracker {
-    seen_ids: std::collections::HashSet<i64>,
+fn default_true() -> bool {
+    true
 }
 
-impl ContentTracker {
-    fn new() -> Self {
-        Self {
-            seen_ids: std::collections::HashSet::new(),
-        }
-    }
+// Read a boolean environment variable, accepting "true" or "1"
+fn env_flag(name: &str, default: bool) -> bool {
+    env::var(name)
+        .map(|v| v == "true" || v == "1")
+        .unwrap_or(default)
+}
 
-    fn is_new(&mut self, id: i64) -> bool {
-        self.seen_ids.insert(id)
-    }
+#[derive(Debug, Serialize)]
+struct RecommendationResponse {
+    items: Vec<Content>,
+    // Set when nothing matched and the items are the popular fallback set instead
+    fallback: bool,
+    // Set when served from an out of date catalog while a refresh runs in the background
+    stale: bool,
+    // Items matching the preferences before the 20 item limit, for "20 of N" displays.
+    // total_unseen leaves out those the user has already been shown.
+    total_matching: usize,
+    total_unseen: usize,
+    // ?debug=true only: genre_entropy of the returned items
+    #[serde(skip_serializing_if = "Option::is_none")]
+    diversity: Option<f64>,
 }
 
-// First, modify the ContentCache struct to track used recommendations
-struct ContentCache {
-    data: HashMap<String, Vec<Content>>,
-    used_recommendations: HashMap<String, HashSet<String>>, // Track used content by user
-    last_updated: chrono::DateTime<chrono::Utc>,
+// Shannon entropy in bits of the items' dominant (first listed) genres, for
+// comparing how varied different strategies' results are. 0 when every item
+// shares one genre, log2(n) when n items all differ. Items without genres are
+// left out.
+fn genre_entropy(items: &[Content]) -> f64 {
+    let mut counts: HashMap<&str, usize> = HashMap::new();
+    for genre in items.iter().filter_map(|c| c.genre.first()) {
+        *counts.entry(genre.as_str()).or_insert(0) += 1;
+    }
+
+    let total = counts.values().sum::<usize>() as f64;
+    counts.values()
+        .map(|&count| {
+            let p = count as f64 / total;
+            -p * p.log2()
+        })
+        .sum()
 }
 
-#[derive(Debug, Serialize, Deserialize)]
-struct CacheData {
-    content: Vec<Content>,
-    used_recommendations: HashMap<String, HashSet<String>>,
-    last_updated: chrono::DateTime<chrono::Utc>,
+// Selected items with the size of the pool they were drawn from
+struct FilteredRecommendations {
+    items: Vec<Content>,
+    total_matching: usize,
+    total_unseen: usize,
 }
 
+// Query string for GET /recommendations: either a saved profile or the preferences
+// themselves, with genres comma-separated (?genres=Action,Comedy&minimum_rating=7)
+#[derive(Debug, Deserialize)]
+struct RecommendationQuery {
+    profile: Option<String>,
+    #[serde(default, deserialize_with = "comma_separated")]
+    genres: Vec<String>,
+    minimum_rating: Option<f32>,
+    #[serde(default)]
+    in_theaters_only: bool,
+    user_id: Option<String>,
+    #[serde(default)]
+    exclude_watchlisted: bool,
+    #[serde(default)]
+    require_description: bool,
+    min_description_len: Option<usize>,
+    #[serde(default)]
+    expand_genres: bool,
+    max_per_provider: Option<usize>,
+    strategy: Option<StrategyKind>,
+    min_year: Option<i32>,
+    max_year: Option<i32>,
+    #[serde(default)]
+    fair_exposure: bool,
+    min_quality: Option<f32>,
+    #[serde(default, deserialize_with = "comma_separated")]
+    spoken_languages: Vec<String>,
+    #[serde(default, deserialize_with = "comma_separated")]
+    exclude_statuses: Vec<String>,
+    #[serde(default)]
+    prefer_original_title: bool,
+    #[serde(default)]
+    debug: bool,
+    // /recommendations only: response body shape
+    #[serde(default)]
+    format: ResponseFormat,
+    // Refresh first if the catalog is older than this, down to MIN_MAX_AGE_HOURS
+    max_age_hours: Option<i64>,
+    // /recommendations/rows only: items per genre row, and whether an item may
+    // appear in more than one row
+    per_row: Option<usize>,
+    #[serde(default)]
+    allow_duplicates: bool,
+    // /recommendations/page only: shuffle seed and 1-based page
+    seed: Option<u64>,
+    page: Option<usize>,
+    page_size: Option<usize>,
+}
 
-impl ContentCache {
-    fn new() -> Self {
-        Self {
-            data: HashMap::new(),
-            used_recommendations: HashMap::new(),
-            last_updated: chrono::Utc::now(),
+impl RecommendationQuery {
+    fn preferences(&self) -> UserPreferences {
+        UserPreferences {
+            favorite_genres: self.genres.clone(),
+            minimum_rating: self.minimum_rating.unwrap_or(0.0),
+            in_theaters_only: self.in_theaters_only,
+            allow_fallback: true,
+            user_id: self.user_id.clone(),
+            exclude_watchlisted: self.exclude_watchlisted,
+            require_description: self.require_description,
+            min_description_len: self.min_description_len,
+            expand_genres: self.expand_genres,
+            max_per_provider: self.max_per_provider,
+            strategy: self.strategy,
+            min_year: self.min_year,
+            max_year: self.max_year,
+            fair_exposure: self.fair_exposure,
+            min_quality: self.min_quality,
+            spoken_languages: self.spoken_languages.clone(),
+            exclude_statuses: self.exclude_statuses.clone(),
+            prefer_original_title: self.prefer_original_title,
         }
     }
+}
 
-    fn needs_update(&self) -> bool {
-        let now = chrono::Utc::now();
-        now.signed_duration_since(self.last_updated).num_hours() > 12
-    }
+// Deserialize "a,b,,c" into ["a", "b", "c"], trimming whitespace and dropping empty entries
+fn comma_separated<'de, D>(deserializer: D) -> std::result::Result<Vec<String>, D::Error>
+where
+    D: serde::Deserializer<'de>,
+{
+    let value = Option::<String>::deserialize(deserializer)?;
+    Ok(value.map(|v| v.split(',')
+            .map(|item| item.trim().to_string())
+            .filter(|item| !item.is_empty())
+            .collect())
+        .unwrap_or_default())
 }
 
-struct ContentService {
-    blob_client: ContainerClient,
-    cache: Arc<RwLock<ContentCache>>,
-    tmdb_api_key: String,
+#[derive(Debug, Deserialize)]
+struct BatchRequest {
+    profiles: Vec<String>,
+    // Don't give two profiles in the same call the same item
+    #[serde(default = "default_true")]
+    shared_dedup: bool,
 }
 
-impl ContentService {
-    async fn new() -> Result<Self> {
-        // Get TMDB API key
-        let tmdb_api_key = env::var("TMDB_API_KEY")?;
+#[derive(Debug, Deserialize)]
+struct NewSinceQuery {
+    since: chrono::DateTime<chrono::Utc>,
+}
 
-        // Get Azure Storage connection string
-        let connection_string = env::var("AZURE_STORAGE_CONNECTION_STRING")
-            .expect("AZURE_STORAGE_CONNECTION_STRING must be set");
+#[derive(Debug, Deserialize)]
+struct DebugQuery {
+    #[serde(default)]
+    debug: bool,
+    #[serde(default)]
+    format: ResponseFormat,
+}
 
-        println!("Parsing connection string...");
+// Body shape for /recommendations: the usual envelope, a bare array of items, or
+// newline-delimited JSON with one item per line for line-oriented consumers
+#[derive(Debug, Clone, Copy, Default, PartialEq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+enum ResponseFormat {
+    #[default]
+    Json,
+    Array,
+    Ndjson,
+}
 
-        // Parse connection string components
-        let mut parts = std::collections::HashMap::new();
-        for part in connection_string.split(';') {
-            if let Some((key, value)) = part.split_once('=') {
-                println!("Found connection string part: {} = {}", key,
-                         if key == "AccountKey" { "***" } else { value });
-                parts.insert(key, value);
-            }
-        }
+#[derive(Debug, Deserialize)]
+struct SearchQuery {
+    query: String,
+    #[serde(default = "default_media_type")]
+    media_type: String,
+    // Restricts movie results to titles released in this region
+    region: Option<String>,
+}
 
-        // Extract required values
-        let account = parts.get("AccountName")
-            .ok_or_else(|| anyhow::anyhow!("AccountName not found"))?;
-        let key = parts.get("AccountKey")
-            .ok_or_else(|| anyhow::anyhow!("AccountKey not found"))?;
+// ISO 3166-1 alpha-2 codes, which TMDB uses to key regions
+const REGION_CODES: &[&str] = &[
+    "AD", "AE", "AF", "AG", "AI", "AL", "AM", "AO", "AQ", "AR", "AS", "AT", "AU", "AW", "AX", "AZ",
+    "BA", "BB", "BD", "BE", "BF", "BG", "BH", "BI", "BJ", "BL", "BM", "BN", "BO", "BQ", "BR", "BS",
+    "BT", "BV", "BW", "BY", "BZ", "CA", "CC", "CD", "CF", "CG", "CH", "CI", "CK", "CL", "CM", "CN",
+    "CO", "CR", "CU", "CV", "CW", "CX", "CY", "CZ", "DE", "DJ", "DK", "DM", "DO", "DZ", "EC", "EE",
+    "EG", "EH", "ER", "ES", "ET", "FI", "FJ", "FK", "FM", "FO", "FR", "GA", "GB", "GD", "GE", "GF",
+    "GG", "GH", "GI", "GL", "GM", "GN", "GP", "GQ", "GR", "GS", "GT", "GU", "GW", "GY", "HK", "HM",
+    "HN", "HR", "HT", "HU", "ID", "IE", "IL", "IM", "IN", "IO", "IQ", "IR", "IS", "IT", "JE", "JM",
+    "JO", "JP", "KE", "KG", "KH", "KI", "KM", "KN", "KP", "KR", "KW", "KY", "KZ", "LA", "LB", "LC",
+    "LI", "LK", "LR", "LS", "LT", "LU", "LV", "LY", "MA", "MC", "MD", "ME", "MF", "MG", "MH", "MK",
+    "ML", "MM", "MN", "MO", "MP", "MQ", "MR", "MS", "MT", "MU", "MV", "MW", "MX", "MY", "MZ", "NA",
+    "NC", "NE", "NF", "NG", "NI", "NL", "NO", "NP", "NR", "NU", "NZ", "OM", "PA", "PE", "PF", "PG",
+    "PH", "PK", "PL", "PM", "PN", "PR", "PS", "PT", "PW", "PY", "QA", "RE", "RO", "RS", "RU", "RW",
+    "SA", "SB", "SC", "SD", "SE", "SG", "SH", "SI", "SJ", "SK", "SL", "SM", "SN", "SO", "SR", "SS",
+    "ST", "SV", "SX", "SY", "SZ", "TC", "TD", "TF", "TG", "TH", "TJ", "TK", "TL", "TM", "TN", "TO",
+    "TR", "TT", "TV", "TW", "TZ", "UA", "UG", "UM", "US", "UY", "UZ", "VA", "VC", "VE", "VG", "VI",
+    "VN", "VU", "WF", "WS", "YE", "YT", "ZA", "ZM", "ZW",
+];
 
-        println!("Account: {}", account);
-        println!("Key length: {}", key.len());
+// Uppercased region code, or None if it isn't a known ISO 3166-1 alpha-2 code.
+// "us" becomes "US"; "USA" is rejected rather than silently matching nothing.
+fn normalize_region(value: &str) -> Option<String> {
+    let code = value.trim().to_ascii_uppercase();
+    REGION_CODES.contains(&code.as_str()).then_some(code)
+}
 
-        // Create credentials first
-        let credentials = StorageCredentials::access_key(account.to_string(), key.to_string());
-        println!("Created credentials");
+fn default_media_type() -> String {
+    "movie".to_string()
+}
 
-        // Create the service client with just the account name
-        let blob_service_client = BlobServiceClient::new(account.to_string(), credentials);
-        println!("Created blob service client");
+#[derive(Debug, Deserialize)]
+struct TrendingQuery {
+    #[serde(rename = "type", default = "default_media_type")]
+    media_type: String,
+    #[serde(default = "default_trending_window")]
+    window: String,
+}
 
-        let container_name = "content-data";
-        let container_client = blob_service_client.container_client(container_name);
-        println!("Created container client for: {}", container_name);
+fn default_trending_window() -> String {
+    "week".to_string()
+}
 
-        // Try to list containers first
-        println!("\nListing all containers to test connectivity...");
-        let mut containers = blob_service_client.list_containers()
-            .into_stream();
+#[derive(Debug, Deserialize)]
+struct WatchlistEntry {
+    #[serde(default = "default_media_type")]
+    media_type: String,
+    tmdb_id: i64,
+}
 
-        let mut found = false;
-        while let Some(container_result) = containers.next().await {
-            match container_result {
-                Ok(response) => {
-                    for container in response.containers {
-                        println!("Found container: {}", container.name);
-                        println!("  Last modified: {}", container.last_modified);
-                        println!("  Public access: {:?}", container.public_access);
-                        println!("  Lease status: {:?}", container.lease_status);
+// Body of POST /watched. user_id is the one sent with recommendation requests, not
+// the hashed user key.
+#[derive(Debug, Deserialize)]
+struct WatchedEntry {
+    #[serde(alias = "user_key")]
+    user_id: String,
+    #[serde(default = "default_media_type")]
+    media_type: String,
+    tmdb_id: i64,
+}
 
-                        if container.name == container_name {
-                            found = true;
-                            println!("Target container already exists");
-                        }
-                    }
+#[derive(Debug, Deserialize)]
+struct PinRequest {
+    #[serde(default = "default_media_type")]
+    media_type: String,
+    tmdb_id: i64,
+}
 
-                    if let Some(marker) = response.next_marker {
-                        println!("More containers available, next marker: {}", marker);
-                    }
-                },
-                Err(e) => {
-                    println!("Error listing containers: {}", e);
-                    println!("Full error details: {:?}", e);
-                }
-            }
-        }
+#[derive(Debug, Deserialize)]
+struct HydrateId {
+    media_type: String,
+    tmdb_id: i64,
+}
 
-        if !found {
-            println!("\nTarget container not found, attempting to create it...");
-            match container_client.create()
-                .public_access(PublicAccess::None)
-                .await
-            {
-                Ok(_) => println!("Container created successfully"),
-                Err(e) => {
-                    println!("Error creating container: {}", e);
-                    println!("Full error details: {:?}", e);
-                    return Err(anyhow::anyhow!("Failed to create container: {}", e));
-                }
-            }
-        }
+#[derive(Debug, Deserialize)]
+struct HydrateRequest {
+    ids: Vec<HydrateId>,
+}
 
-        Ok(Self {
-            blob_client: container_client,
-            cache: Arc::new(RwLock::new(ContentCache::new())),
-            tmdb_api_key,
-        })
-    }
+#[derive(Debug, Deserialize)]
+struct PickRequest {
+    #[serde(flatten)]
+    preferences: UserPreferences,
+    // Makes the pick reproducible for the same catalog and used items
+    seed: Option<u64>,
+}
 
-    async fn fetch_movies(&self, client: &reqwest::Client, auth_header: &str,
-                          tracker: &mut ContentTracker, url: String) -> Result<Vec<Content>> {
-        let mut movies = Vec::new();
+#[derive(Debug, Deserialize)]
+struct ExplainRequest {
+    #[serde(flatten)]
+    preferences: UserPreferences,
+    tmdb_id: i64,
+    media_type: Option<String>,
+}
 
-        println!("Fetching movies from: {}", url);
-        let response = client.get(&url)
-            .header("Authorization", auth_header)
-            .header("accept", "application/json")
-            .send()
-            .await?;
+// An extra TMDB discover query scraped into the catalog, from DISCOVER_CONFIG
+#[derive(Debug, Deserialize)]
+struct DiscoverSource {
+    media_type: String,
+    #[serde(default)]
+    params: std::collections::BTreeMap<String, Value>,
+}
 
-        if response.status().is_success() {
-            let data: Value = response.json().await?;
-            if let Some(results) = data["results"].as_array() {
-                for movie in results {
-                    let movie_id = movie["id"].as_i64().unwrap_or_default();
+// Discover parameters operators may set. Anything else is rejected at startup
+// rather than silently producing a malformed or ignored query.
+const DISCOVER_PARAMS: &[&str] = &[
+    "sort_by",
+    "vote_average.gte",
+    "vote_average.lte",
+    "vote_count.gte",
+    "with_runtime.gte",
+    "with_runtime.lte",
+    "primary_release_year",
+    "primary_release_date.gte",
+    "primary_release_date.lte",
+    "first_air_date_year",
+    "first_air_date.gte",
+    "first_air_date.lte",
+    "with_genres",
+    "without_genres",
+    "with_original_language",
+    "with_watch_providers",
+    "with_watch_monetization_types",
+];
 
-                    // Skip if we've already seen this movie
-                    if !tracker.is_new(movie_id) {
-                        continue;
-                    }
+// Load discover sources from a JSON file holding an array of
+// {"media_type": "movie", "params": {"vote_average.gte": 7, ...}} entries
+fn load_discover_sources(path: &str) -> Result<Vec<DiscoverSource>> {
+    let json = std::fs::read_to_string(path)
+        .map_err(|e| anyhow::anyhow!("Failed to read DISCOVER_CONFIG {}: {}", path, e))?;
+    let sources: Vec<DiscoverSource> = serde_json::from_str(&json)
+        .map_err(|e| anyhow::anyhow!("Failed to parse DISCOVER_CONFIG {}: {}", path, e))?;
 
-                    let genres = self.get_movie_genres(client, movie_id, auth_header).await
-                        .unwrap_or_default();
-                    let providers = self.get_watch_providers(client, "movie", movie_id, auth_header)
-                        .await.unwrap_or_default();
-
-                    let content = Content {
-                        title: movie["title"].as_str().unwrap_or_default().to_string(),
-                        year: movie["release_date"]
-                            .as_str()
-                            .and_then(|d| d.split('-').next())
-                            .map(String::from),
-                        rating: movie["vote_average"].as_f64().map(|r| r as f32),
-                        genre: genres,
-                        description: movie["overview"].as_str().unwrap_or_default().to_string(),
-                        where_to_watch: providers,
-                    };
-                    movies.push(content);
-                }
+    for source in &sources {
+        if source.media_type != "movie" && source.media_type != "tv" {
+            return Err(anyhow::anyhow!("Discover media_type must be movie or tv, got: {}", source.media_type));
+        }
+        for (name, value) in &source.params {
+            if !DISCOVER_PARAMS.contains(&name.as_str()) {
+                return Err(anyhow::anyhow!("Unsupported discover parameter: {}", name));
+            }
+            if !(value.is_string() || value.is_number() || value.is_boolean()) {
+                return Err(anyhow::anyhow!("Discover parameter {} must be a string, number or boolean", name));
             }
         }
-
-        Ok(movies)
     }
 
-    async fn fetch_tv_shows(&self, client: &reqwest::Client, auth_header: &str,
-                            tracker: &mut ContentTracker, url: String) -> Result<Vec<Content>> {
-        let mut shows = Vec::new();
-
-        println!("Fetching TV shows from: {}", url);
-        let response = client.get(&url)
-            .header("Authorization", auth_header)
-            .header("accept", "application/json")
-            .send()
-            .await?;
+    Ok(sources)
+}
 
-        if response.status().is_success() {
-            let data: Value = response.json().await?;
-            if let Some(results) = data["results"].as_array() {
-                for show in results {
-                    let show_id = show["id"].as_i64().unwrap_or_default();
+#[derive(Debug, Serialize, Clone)]
+struct ScoreBreakdown {
+    matched_genres: Vec<String>,
+    genre_score: f32,
+    rating_score: f32,
+    recency_score: f32,
+    provider_match: bool,
+    total: f32,
+    eligible: bool,
+}
 
-                    // Skip if we've already seen this show
-                    if !tracker.is_new(show_id) {
-                        continue;
-                    }
+// Prefix turning TMDB image paths into full URLs
+const TMDB_IMAGE_BASE: &str = "https://image.tmdb.org/t/p/original";
 
-                    let genres = self.get_tv_genres(client, show_id, auth_header).await
-                        .unwrap_or_default();
-                    let providers = self.get_watch_providers(client, "tv", show_id, auth_header)
-                        .await.unwrap_or_default();
-
-                    let content = Content {
-                        title: show["name"].as_str().unwrap_or_default().to_string(),
-                        year: show["first_air_date"]
-                            .as_str()
-                            .and_then(|d| d.split('-').next())
-                            .map(String::from),
-                        rating: show["vote_average"].as_f64().map(|r| r as f32),
-                        genre: genres,
-                        description: show["overview"].as_str().unwrap_or_default().to_string(),
-                        where_to_watch: providers,
-                    };
-                    shows.push(content);
-                }
-            }
-        }
+// Genres treated as close enough to each other for expand_genres, unless
+// GENRE_ADJACENCY_FILE supplies a replacement map
+const DEFAULT_GENRE_ADJACENCY: &[(&str, &[&str])] = &[
+    ("Action", &["Adventure", "Thriller"]),
+    ("Adventure", &["Action", "Fantasy"]),
+    ("Animation", &["Family"]),
+    ("Comedy", &["Family"]),
+    ("Crime", &["Thriller", "Mystery"]),
+    ("Drama", &["Romance"]),
+    ("Family", &["Animation", "Comedy"]),
+    ("Fantasy", &["Adventure", "Science Fiction"]),
+    ("Horror", &["Thriller"]),
+    ("Mystery", &["Thriller", "Crime"]),
+    ("Romance", &["Drama"]),
+    ("Science Fiction", &["Fantasy", "Adventure"]),
+    ("Thriller", &["Crime", "Mystery"]),
+    ("Action & Adventure", &["Sci-Fi & Fantasy"]),
+    ("Sci-Fi & Fantasy", &["Action & Adventure"]),
+];
 
-        Ok(shows)
-    }
+// Load a genre adjacency map from a JSON object of genre name to related genre names
+fn load_genre_adjacency(path: &str) -> Result<HashMap<String, Vec<String>>> {
+    let json = std::fs::read_to_string(path)
+        .map_err(|e| anyhow::anyhow!("Failed to read GENRE_ADJACENCY_FILE {}: {}", path, e))?;
+    serde_json::from_str(&json)
+        .map_err(|e| anyhow::anyhow!("Failed to parse GENRE_ADJACENCY_FILE {}: {}", path, e))
+}
 
-    // Helper function to generate a unique key for each user's preference combination
-    fn generate_user_key(&self, prefs: &UserPreferences) -> String {
-        use std::hash::{Hash, Hasher};
-        use std::collections::hash_map::DefaultHasher;
+// TMDB API version all requests are made against
+const TMDB_API_VERSION: u32 = 3;
 
-        let mut hasher = DefaultHasher::new();
+// Version of the CacheData layout written to blob storage. 2 keys watchlists,
+// watched items and pins by content id rather than bare tmdb_id.
+const CACHE_SCHEMA_VERSION: u32 = 2;
 
-        // Sort genres for consistent hashing
-        let mut genres = prefs.favorite_genres.clone();
-        genres.sort();
+// How long scraped content is considered fresh
+const CACHE_TTL_HOURS: i64 = 12;
 
-        // Hash the preferences
-        genres.hash(&mut hasher);
-        prefs.minimum_rating.to_bits().hash(&mut hasher);
+// Most pages fetched from any single TMDB list per scrape
+const MAX_LIST_PAGES: u32 = 5;
 
-        format!("user_{:x}", hasher.finish())
-    }
+// Seed used for all shuffling in DETERMINISTIC mode
+const DETERMINISTIC_SEED: u64 = 42;
 
-    // Update the scrape_content method to get even more content
-    async fn scrape_content(&self) -> Result<Vec<Content>> {
-        let client = reqwest::Client::new();
-        let mut all_content = Vec::new();
-        let mut tracker = ContentTracker::new();
-        let auth_header = format!("Bearer {}", self.tmdb_api_key);
+// Smallest max_age_hours a client may request, so forced refreshes can't be used
+// to hammer TMDB
+const MIN_MAX_AGE_HOURS: i64 = 1;
 
-        // Increase pages to get more content
-        for page in 1..=5 {  // Increased from 3 to 5 pages
-            // Trending Movies (Week)
-            all_content.extend(
-                self.fetch_movies(&client, &auth_header, &mut tracker,
-                                  format!("https://api.themoviedb.org/3/trending/movie/week?language=en-US&page={}", page)
-                ).await?
-            );
+// Items per row for /recommendations/rows, by default and at most
+const DEFAULT_ROW_SIZE: usize = 10;
+const MAX_ROW_SIZE: usize = 50;
 
-            // Trending Movies (Day)
-            all_content.extend(
-                self.fetch_movies(&client, &auth_header, &mut tracker,
-                                  format!("https://api.themoviedb.org/3/trending/movie/day?language=en-US&page={}", page)
-                ).await?
-            );
+// Items per page of /recommendations/page when page_size isn't given
+const DEFAULT_PAGE_SIZE: usize = 20;
 
-            // Popular Movies
-            all_content.extend(
-                self.fetch_movies(&client, &auth_header, &mut tracker,
-                                  format!("https://api.themoviedb.org/3/movie/popular?language=en-US&page={}", page)
-                ).await?
-            );
+// A user's used set is cleared when fewer unseen matching items than this are left
+const USED_RESET_THRESHOLD: usize = 10;
 
-            // Top Rated Movies
-            all_content.extend(
-                self.fetch_movies(&client, &auth_header, &mut tracker,
-                                  format!("https://api.themoviedb.org/3/movie/top_rated?language=en-US&page={}", page)
-                ).await?
-            );
+// Used titles listed by GET /admin/used/{user_key}
+const USED_SAMPLE_SIZE: usize = 20;
 
-            // Now Playing Movies
-            all_content.extend(
-                self.fetch_movies(&client, &auth_header, &mut tracker,
-                                  format!("https://api.themoviedb.org/3/movie/now_playing?language=en-US&page={}", page)
-                ).await?
-            );
+// Maximum number of profiles in one batch recommendation request
+const MAX_BATCH_PROFILES: usize = 10;
 
-            // Trending TV Shows (Week)
-            all_content.extend(
-                self.fetch_tv_shows(&client, &auth_header, &mut tracker,
-                                    format!("https://api.themoviedb.org/3/trending/tv/week?language=en-US&page={}", page)
-                ).await?
-            );
+// Longest date range TMDB's /changes endpoints accept in one request
+const TMDB_CHANGES_MAX_DAYS: i64 = 14;
 
-            // Trending TV Shows (Day)
-            all_content.extend(
-                self.fetch_tv_shows(&client, &auth_header, &mut tracker,
-                                    format!("https://api.themoviedb.org/3/trending/tv/day?language=en-US&page={}", page)
-                ).await?
-            );
+// How long TMDB's per-title recommendations are kept in memory
+const TMDB_RECOMMENDATIONS_TTL: std::time::Duration = std::time::Duration::from_secs(15 * 60);
 
-            // Popular TV Shows
-            all_content.extend(
-                self.fetch_tv_shows(&client, &auth_header, &mut tracker,
-                                    format!("https://api.themoviedb.org/3/tv/popular?language=en-US&page={}", page)
-                ).await?
-            );
+// Most watchlist items turned into "because you saved" rows per request
+const MAX_BECAUSE_ROWS: usize = 5;
 
-            // Top Rated TV Shows
-            all_content.extend(
-                self.fetch_tv_shows(&client, &auth_header, &mut tracker,
-                                    format!("https://api.themoviedb.org/3/tv/top_rated?language=en-US&page={}", page)
-                ).await?
-            );
+// Top billed cast members kept per item
+const MAX_CAST: usize = 5;
 
-            // Currently Airing TV Shows
-            all_content.extend(
-                self.fetch_tv_shows(&client, &auth_header, &mut tracker,
-                                    format!("https://api.themoviedb.org/3/tv/on_the_air?language=en-US&page={}", page)
-                ).await?
-            );
-        }
+// Maximum number of ids a single hydrate request may fetch live from TMDB
+const MAX_HYDRATE_FETCHES: usize = 20;
 
-        // Shuffle the content for variety
-        use rand::seq::SliceRandom;
-        let mut rng = rand::thread_rng();
-        all_content.shuffle(&mut rng);
+// Attempts made to upload a blob before giving up
+const BLOB_UPLOAD_ATTEMPTS: u32 = 3;
 
-        println!("Scraped {} unique items total", all_content.len());
-        Ok(all_content)
-    }
+// Largest block Azure accepts in a staged upload
+const MAX_BLOB_BLOCK_SIZE: usize = 4000 * 1024 * 1024;
 
-    async fn get_movie_genres(&self, client: &reqwest::Client, movie_id: i64, auth_header: &str) -> Result<Vec<String>> {
-        let url = format!(
-            "https://api.themoviedb.org/3/movie/{}?language=en-US",
-            movie_id
-        );
+// Written and removed again by the startup self-test
+const SELFTEST_BLOB: &str = "selftest.txt";
 
-        let response = client.get(&url)
-            .header("Authorization", auth_header)
-            .header("accept", "application/json")
-            .send()
-            .await?;
+// Blob holding saved preference profiles, kept apart from the catalog so a
+// rebuild never loses them
+const PROFILES_BLOB: &str = "profiles.json.gz";
 
-        let mut genres = Vec::new();
+// Profile names are used as map keys and in URLs, so keep them short and plain
+fn valid_profile_name(name: &str) -> bool {
+    !name.is_empty() && name.len() <= 64 &&
+        name.chars().all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_')
+}
 
-        if response.status().is_success() {
-            let data: Value = response.json().await?;
-            if let Some(genre_array) = data["genres"].as_array() {
-                for genre in genre_array {
-                    if let Some(name) = genre["name"].as_str() {
-                        genres.push(name.to_string());
-                    }
-                }
-            }
-        }
+impl UserPreferences {
+    // The favorite genres as a set, built once per request so matching a large
+    // catalog against many favorites doesn't scan the list for every item
+    fn favorite_genre_set(&self) -> HashSet<String> {
+        self.favorite_genres.iter().cloned().collect()
+    }
 
-        Ok(genres)
+    // Whether a piece of content satisfies the rating and theater filters and has
+    // one of the given genres
+    fn matches_genres(&self, content: &Content, genres: &HashSet<String>) -> bool {
+        content.rating.unwrap_or(0.0) >= self.minimum_rating &&
+            content.genre.iter().any(|g| genres.contains(g)) &&
+            (!self.in_theaters_only || content.in_theaters)
     }
 
-    async fn get_tv_genres(&self, client: &reqwest::Client, tv_id: i64, auth_header: &str) -> Result<Vec<String>> {
-        let url = format!(
-            "https://api.themoviedb.org/3/tv/{}?language=en-US",
-            tv_id
-        );
+    // Reject combinations that can never match anything, so the caller gets a 400
+    // naming the problem rather than an empty or fallback result
+    fn validate(&self) -> std::result::Result<(), String> {
+        let mut problems = Vec::new();
 
-        let response = client.get(&url)
-            .header("Authorization", auth_header)
-            .header("accept", "application/json")
-            .send()
-            .await?;
+        if !(0.0..=10.0).contains(&self.minimum_rating) {
+            problems.push(format!("minimum_rating must be between 0 and 10, got {}", self.minimum_rating));
+        }
+        if let (Some(min), Some(max)) = (self.min_year, self.max_year) {
+            if min > max {
+                problems.push(format!("min_year ({}) is after max_year ({})", min, max));
+            }
+        }
+        if self.expand_genres && self.favorite_genres.is_empty() {
+            problems.push("expand_genres needs at least one favorite genre to expand".to_string());
+        }
+        if let Some(min) = self.min_quality.filter(|min| !(0.0..=10.0).contains(min)) {
+            problems.push(format!("min_quality must be between 0 and 10, got {}", min));
+        }
+        if self.max_per_provider == Some(0) {
+            problems.push("max_per_provider must be at least 1".to_string());
+        }
 
-        let mut genres = Vec::new();
+        if problems.is_empty() {
+            Ok(())
+        } else {
+            Err(problems.join("; "))
+        }
+    }
 
-        if response.status().is_success() {
-            let data: Value = response.json().await?;
-            if let Some(genre_array) = data["genres"].as_array() {
-                for genre in genre_array {
-                    if let Some(name) = genre["name"].as_str() {
-                        genres.push(name.to_string());
-                    }
-                }
+    // Swap in original titles for a response when asked to. This runs on the copies
+    // being returned, after used items are recorded by title, so the cached catalog
+    // keeps its localized titles.
+    fn display_titles(&self, items: &mut [Content]) {
+        if !self.prefer_original_title {
+            return;
+        }
+        for content in items {
+            if let Some(original) = content.original_title.clone().filter(|t| *t != content.title) {
+                content.localized_title = Some(std::mem::replace(&mut content.title, original));
             }
         }
+    }
+}
 
-        Ok(genres)
+// Split a budget of list page requests evenly between genres, each capped at
+// max_depth pages. The remainder of an uneven split goes to the first genres.
+fn split_request_budget(budget: usize, genres: usize, max_depth: u32) -> Vec<u32> {
+    if genres == 0 {
+        return Vec::new();
     }
 
-    async fn get_watch_providers(&self, client: &reqwest::Client, media_type: &str, id: i64, auth_header: &str) -> Result<Vec<String>> {
-        let url = format!(
-            "https://api.themoviedb.org/3/{}/{}/watch/providers",
-            media_type, id
-        );
+    let share = budget / genres;
+    let remainder = budget % genres;
+    (0..genres)
+        .map(|i| {
+            let pages = share + usize::from(i < remainder);
+            pages.min(max_depth as usize) as u32
+        })
+        .collect()
+}
 
-        let response = client.get(&url)
-            .header("Authorization", auth_header)
-            .header("accept", "application/json")
-            .send()
-            .await?;
+// Add this new struct for tracking already seen content
+#[derive(Debug)]
+struct ContentTracker {
+    seen_ids: std::collections::HashSet<i64>,
+    // Every list each title appeared in, including sightings skipped as duplicates
+    sources: HashMap<(String, i64), Vec<String>>,
+}
 
-        let mut providers = Vec::new();
+impl ContentTracker {
+    fn new() -> Self {
+        Self {
+            seen_ids: std::collections::HashSet::new(),
+            sources: HashMap::new(),
+        }
+    }
 
-        if response.status().is_success() {
-            let data: Value = response.json().await?;
-            if let Some(us_data) = data.get("results").and_then(|r| r.get("US")) {
-                for provider_type in ["flatrate", "free"].iter() {
-                    if let Some(provider_list) = us_data.get(provider_type).and_then(|p| p.as_array()) {
-                        for provider in provider_list {
-                            if let Some(name) = provider.get("provider_name").and_then(|n| n.as_str()) {
-                                providers.push(name.to_string());
-                            }
-                        }
-                    }
-                }
-            }
+    fn is_new(&mut self, id: i64) -> bool {
+        self.seen_ids.insert(id)
+    }
+
+    // Note that a title appeared in a list, returning whether it's the first sighting
+    fn record(&mut self, media_type: &str, id: i64, source: &str) -> bool {
+        let sources = self.sources.entry((media_type.to_string(), id)).or_default();
+        if !sources.iter().any(|s| s == source) {
+            sources.push(source.to_string());
         }
+        self.is_new(id)
+    }
 
-        Ok(providers)
+    fn sources_for(&self, media_type: &str, id: i64) -> Vec<String> {
+        self.sources.get(&(media_type.to_string(), id)).cloned().unwrap_or_default()
     }
+}
 
-    // async fn update_content(&self) -> Result<()> {
-    //     {
+// The TMDB list a URL points at, e.g. "trending/movie/week", used to tag content with its sources
+fn source_tag(url: &str) -> String {
+    Url::parse(url)
+        .map(|u| u.path().trim_start_matches("/3/").to_string())
+        .unwrap_or_default()
+}
+
+// Find the best YouTube trailer in an appended videos response, preferring official
+// trailers over teasers and clips
+fn parse_trailer(data: &Value) -> Option<String> {
+    let rank = |video: &Value| {
+        let official = video["official"].as_bool().unwrap_or(false);
+        match (video["type"].as_str(), official) {
+            (Some("Trailer"), true) => 0,
+            (Some("Trailer"), false) => 1,
+            (Some("Teaser"), _) => 2,
+            _ => 3,
+        }
+    };
+
+    data["results"].as_array()
+        .and_then(|videos| videos.iter()
+            .filter(|v| v["site"].as_str() == Some("YouTube"))
+            .min_by_key(|v| rank(v)))
+        .and_then(|v| v["key"].as_str())
+        .map(|key| format!("https://www.youtube.com/watch?v={}", key))
+}
+
+// Each region's TMDB watch page from a watch/providers response, skipping regions
+// that list no providers
+fn parse_watch_links(data: &Value) -> std::collections::BTreeMap<String, String> {
+    data["results"].as_object()
+        .map(|regions| regions.iter()
+            .filter(|(_, region)| ["flatrate", "free", "ads", "rent", "buy"].iter()
+                .any(|t| region[*t].as_array().map_or(false, |p| !p.is_empty())))
+            .filter_map(|(code, region)| region["link"].as_str().map(|link| (code.clone(), link.to_string())))
+            .collect())
+        .unwrap_or_default()
+}
+
+// Score a piece of content against the user's preferences. Genre, rating and recency
+// each contribute 0.0-1.0, plus a small bonus when the item is streamable somewhere.
+// favorites is prefs.favorite_genre_set(), passed in so callers scoring many items
+// build it once
+fn score_content(content: &Content, prefs: &UserPreferences, favorites: &HashSet<String>) -> ScoreBreakdown {
+    use chrono::Datelike;
+
+    let matched_genres: Vec<String> = content.genre.iter()
+        .filter(|g| favorites.contains(*g))
+        .cloned()
+        .collect();
+    let genre_score = if prefs.favorite_genres.is_empty() {
+        0.0
+    } else {
+        matched_genres.len() as f32 / prefs.favorite_genres.len() as f32
+    };
+
+    let rating_score = content.rating.unwrap_or(0.0) / 10.0;
+
+    // Full score for this year's releases, fading out over 20 years
+    let current_year = chrono::Utc::now().year();
+    let recency_score = content_year(content)
+        .map(|y| 1.0 - ((current_year - y).clamp(0, 20) as f32 / 20.0))
+        .unwrap_or(0.0);
+
+    let provider_match = !content.where_to_watch.is_empty();
+    let provider_score = if provider_match { 0.5 } else { 0.0 };
+
+    ScoreBreakdown {
+        matched_genres,
+        genre_score,
+        rating_score,
+        recency_score,
+        provider_match,
+        total: genre_score + rating_score + recency_score + provider_score,
+        eligible: prefs.matches_genres(content, favorites),
+    }
+}
+
+// Orders the eligible, not yet shown candidates for a request; the first 20 (after
+// pins and provider caps) are returned. Eligibility filtering stays in the service
+// so every strategy honours the same genre, rating, watchlist and description rules.
+trait RecommendationStrategy {
+    fn recommend(&self, content: &[Content], prefs: &UserPreferences) -> Vec<Content>;
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+enum StrategyKind {
+    Default,
+    Weighted,
+    Similar,
+    Quality,
+}
+
+impl StrategyKind {
+    fn parse(value: &str) -> Option<Self> {
+        match value {
+            "default" => Some(StrategyKind::Default),
+            "weighted" => Some(StrategyKind::Weighted),
+            "similar" => Some(StrategyKind::Similar),
+            "quality" => Some(StrategyKind::Quality),
+            _ => None,
+        }
+    }
+}
+
+// Every matching item equally likely, in random order
+struct DefaultStrategy {
+    // Fixed shuffle seed, for DETERMINISTIC mode and seeded pages
+    seed: Option<u64>,
+}
+
+impl RecommendationStrategy for DefaultStrategy {
+    fn recommend(&self, content: &[Content], _prefs: &UserPreferences) -> Vec<Content> {
+        use rand::seq::SliceRandom;
+        use rand::SeedableRng;
+
+        // The catalog is stored sorted, so the order going into the shuffle is
+        // already stable for a fixed seed
+        let mut rng = match self.seed {
+            Some(seed) => rand::rngs::StdRng::seed_from_u64(seed),
+            None => rand::rngs::StdRng::from_entropy(),
+        };
+
+        let mut content = content.to_vec();
+        content.shuffle(&mut rng);
+        content
+    }
+}
+
+// Highest total score first, combining genre match, rating, recency and providers
+struct WeightedStrategy;
+
+impl RecommendationStrategy for WeightedStrategy {
+    fn recommend(&self, content: &[Content], prefs: &UserPreferences) -> Vec<Content> {
+        let favorites = prefs.favorite_genre_set();
+        let mut scored: Vec<(f32, &Content)> = content.iter()
+            .map(|c| (score_content(c, prefs, &favorites).total, c))
+            .collect();
+        scored.sort_by(|a, b| b.0.total_cmp(&a.0));
+        scored.into_iter().map(|(_, c)| c.clone()).collect()
+    }
+}
+
+// Closest to the favorites first: most favorite genres matched, then highest rated
+struct SimilarStrategy;
+
+impl RecommendationStrategy for SimilarStrategy {
+    fn recommend(&self, content: &[Content], prefs: &UserPreferences) -> Vec<Content> {
+        let favorites = prefs.favorite_genre_set();
+        let matched = |c: &Content| c.genre.iter().filter(|g| favorites.contains(*g)).count();
+        let mut content = content.to_vec();
+        content.sort_by(|a, b| matched(b).cmp(&matched(a))
+            .then_with(|| b.rating.unwrap_or(0.0).total_cmp(&a.rating.unwrap_or(0.0))));
+        content
+    }
+}
+
+// Highest quality_score first, so well rated items with many votes lead
+struct QualityStrategy;
+
+impl RecommendationStrategy for QualityStrategy {
+    fn recommend(&self, content: &[Content], _prefs: &UserPreferences) -> Vec<Content> {
+        let mut content = content.to_vec();
+        content.sort_by(|a, b| b.quality_score.total_cmp(&a.quality_score));
+        content
+    }
+}
+
+// Per-item data pulled from TMDB's details endpoint during enrichment
+#[derive(Debug, Default)]
+struct Enrichment {
+    genres: Vec<String>,
+    spoken_languages: Vec<String>,
+    imdb_id: Option<String>,
+    cast: Vec<String>,
+    keywords: Vec<String>,
+    status: Option<String>,
+    providers: Vec<Provider>,
+    where_to_watch: Vec<String>,
+    region_providers: std::collections::BTreeMap<String, Vec<String>>,
+    trailer_url: Option<String>,
+    watch_links: std::collections::BTreeMap<String, String>,
+}
+
+// One page of a TMDB list response
+#[derive(Debug, Default)]
+struct ListPage {
+    content: Vec<Content>,
+    total_pages: Option<u32>,
+    // Number of results on the page, including ones skipped as already seen
+    result_count: usize,
+    // Set when the request failed or the body wasn't a result list, as opposed
+    // to TMDB successfully returning no results
+    failed: bool,
+}
+
+// How fetching all pages of a list ended
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum ListOutcome {
+    Complete,
+    // TMDB returned no results at all
+    Empty,
+    // A page failed, so the list may be incomplete
+    Failed,
+}
+
+// First, modify the ContentCache struct to track used recommendations
+struct ContentCache {
+    data: HashMap<String, Vec<Content>>,
+    used_recommendations: HashMap<String, HashSet<String>>, // Track used content by user
+    unseen_remaining: HashMap<String, usize>, // Unseen matching items each user key had left after its last request, not persisted
+    watchlists: HashMap<String, HashSet<String>>, // Content ids saved by each user_id
+    watched: HashMap<String, HashSet<String>>, // Content ids each user_id has watched, never recommended to them again
+    pins: Vec<String>, // Content ids shown first to everyone they match, in pin order
+    exposure: HashMap<String, f64>, // Decayed count of times each content id was shown, across all users
+    by_id: HashMap<String, usize>, // Position in "latest" of each content id, rebuilt with it
+    generation: u64, // Bumped whenever the catalog, pins, watchlists or watched items change
+    snapshots: u64, // Sequence number of the last snapshot taken for saving
+    rating_mean: f32, // Mean rating of "latest", used for quality scores
+    last_updated: chrono::DateTime<chrono::Utc>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct CacheData {
+    #[serde(default)]
+    schema_version: u32,
+    content: Vec<Content>,
+    used_recommendations: HashMap<String, HashSet<String>>,
+    #[serde(default, deserialize_with = "deserialize_saved_id_sets")]
+    watchlists: HashMap<String, HashSet<String>>,
+    #[serde(default, deserialize_with = "deserialize_saved_id_sets")]
+    watched: HashMap<String, HashSet<String>>,
+    #[serde(default, deserialize_with = "deserialize_saved_ids")]
+    pins: Vec<String>,
+    last_updated: chrono::DateTime<chrono::Utc>,
+    #[serde(default)]
+    exposure: HashMap<String, f64>,
+    // Order in which snapshots were taken, so an older one is never saved over a newer one
+    #[serde(default)]
+    snapshot: u64,
+}
+
+// Saved per-user content id, or the bare tmdb_id blobs held before schema version 2.
+// Bare ids are kept as their digits until process_blob_data resolves them.
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum SavedId {
+    ContentId(String),
+    TmdbId(i64),
+}
+
+impl From<SavedId> for String {
+    fn from(id: SavedId) -> Self {
+        match id {
+            SavedId::ContentId(id) => id,
+            SavedId::TmdbId(id) => id.to_string(),
+        }
+    }
+}
+
+fn deserialize_saved_ids<'de, D: serde::Deserializer<'de>>(deserializer: D)
+    -> std::result::Result<Vec<String>, D::Error> {
+    let ids: Vec<SavedId> = Deserialize::deserialize(deserializer)?;
+    Ok(ids.into_iter().map(String::from).collect())
+}
+
+fn deserialize_saved_id_sets<'de, D: serde::Deserializer<'de>>(deserializer: D)
+    -> std::result::Result<HashMap<String, HashSet<String>>, D::Error> {
+    let sets: HashMap<String, Vec<SavedId>> = Deserialize::deserialize(deserializer)?;
+    Ok(sets.into_iter()
+        .map(|(user, ids)| (user, ids.into_iter().map(String::from).collect()))
+        .collect())
+}
+
+// Content id for a bare tmdb_id from an old blob: the movie with that id if the catalog
+// has one, else the show, else the movie, as lookups used to try movies first
+fn resolve_saved_id(id: String, catalog: &HashSet<String>) -> String {
+    match id.parse::<i64>() {
+        Ok(tmdb_id) if !catalog.contains(&content_id("movie", tmdb_id)) &&
+            catalog.contains(&content_id("tv", tmdb_id)) => content_id("tv", tmdb_id),
+        Ok(tmdb_id) => content_id("movie", tmdb_id),
+        Err(_) => id,
+    }
+}
+
+// Serialization used for the catalog blob, recorded in its "format" metadata so
+// either can be loaded whatever BLOB_FORMAT is currently set to
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum BlobFormat {
+    Json,
+    MessagePack,
+}
+
+impl BlobFormat {
+    fn parse(value: &str) -> Option<Self> {
+        match value {
+            "json" => Some(BlobFormat::Json),
+            "messagepack" => Some(BlobFormat::MessagePack),
+            _ => None,
+        }
+    }
+
+    fn as_str(&self) -> &'static str {
+        match self {
+            BlobFormat::Json => "json",
+            BlobFormat::MessagePack => "messagepack",
+        }
+    }
+}
+
+impl ContentCache {
+    fn new() -> Self {
+        Self {
+            data: HashMap::new(),
+            used_recommendations: HashMap::new(),
+            unseen_remaining: HashMap::new(),
+            watchlists: HashMap::new(),
+            watched: HashMap::new(),
+            pins: Vec::new(),
+            exposure: HashMap::new(),
+            by_id: HashMap::new(),
+            generation: 0,
+            snapshots: 0,
+            rating_mean: 0.0,
+            last_updated: chrono::Utc::now(),
+        }
+    }
+
+    // Scale every exposure count by factor, forgetting those that fall to almost nothing
+    fn decay_exposure(&mut self, factor: f64) {
+        for count in self.exposure.values_mut() {
+            *count *= factor;
+        }
+        self.exposure.retain(|_, count| *count >= 0.01);
+    }
+
+    // Replace the catalog, evicting down to max_items, then rebuilding its id index and
+    // scoring every item's quality against the new catalog's mean rating
+    fn set_latest(&mut self, mut content: Vec<Content>, quality_min_votes: f32, max_items: Option<usize>) {
+        if let Some(max_items) = max_items {
+            enforce_cache_limit(&mut content, max_items);
+        }
+        self.rating_mean = mean_rating(&content);
+        for item in &mut content {
+            item.quality_score = weighted_rating(item, self.rating_mean, quality_min_votes);
+        }
+        self.by_id = content.iter().enumerate()
+            .map(|(i, c)| (c.id.clone(), i))
+            .collect();
+        self.data.insert("latest".to_string(), content);
+        self.generation += 1;
+    }
+
+    // Catalog item by media type and TMDB id, without scanning the catalog
+    fn find(&self, media_type: &str, tmdb_id: i64) -> Option<&Content> {
+        let index = *self.by_id.get(&content_id(media_type, tmdb_id))?;
+        self.data.get("latest")?.get(index)
+    }
+
+    // Snapshot of everything that gets persisted to blob storage. Take it under the
+    // same write lock as the change being saved, so the snapshot includes it.
+    fn to_cache_data(&mut self) -> CacheData {
+        self.snapshots += 1;
+        CacheData {
+            snapshot: self.snapshots,
+            schema_version: CACHE_SCHEMA_VERSION,
+            content: self.data.get("latest").cloned().unwrap_or_default(),
+            used_recommendations: self.used_recommendations.clone(),
+            watchlists: self.watchlists.clone(),
+            watched: self.watched.clone(),
+            pins: self.pins.clone(),
+            exposure: self.exposure.clone(),
+            last_updated: self.last_updated,
+        }
+    }
+
+    fn needs_update(&self) -> bool {
+        if !self.data.contains_key("latest") {
+            return true;
+        }
+
+        let now = chrono::Utc::now();
+        now.signed_duration_since(self.last_updated).num_hours() > CACHE_TTL_HOURS
+    }
+
+    // Count how many catalog items fall under each name produced by `names`
+    fn tally<F>(&self, names: F) -> Vec<(String, usize)>
+    where
+        F: Fn(&Content) -> Vec<String>,
+    {
+        let mut counts: HashMap<String, usize> = HashMap::new();
+        for content in self.data.get("latest").into_iter().flatten() {
+            for name in names(content) {
+                *counts.entry(name).or_insert(0) += 1;
+            }
+        }
+
+        let mut counts: Vec<(String, usize)> = counts.into_iter().collect();
+        counts.sort_by(|a, b| b.1.cmp(&a.1).then_with(|| a.0.cmp(&b.0)));
+        counts
+    }
+}
+
+// Evict the lowest priority items (oldest fetched, then least popular) once the
+// catalog grows beyond max_items, keeping the rest in their original order
+fn enforce_cache_limit(content: &mut Vec<Content>, max_items: usize) {
+    if content.len() <= max_items {
+        return;
+    }
+
+    let mut ranked: Vec<usize> = (0..content.len()).collect();
+    ranked.sort_by(|&a, &b| {
+        let (a, b) = (&content[a], &content[b]);
+        b.fetched_at.cmp(&a.fetched_at)
+            .then_with(|| b.popularity.unwrap_or(0.0).total_cmp(&a.popularity.unwrap_or(0.0)))
+    });
+
+    let mut evicted = vec![false; content.len()];
+    println!("Cache limit of {} items exceeded, evicting {} items", max_items, content.len() - max_items);
+    for &index in &ranked[max_items..] {
+        let item = &content[index];
+        println!("  Evicted: {} ({} {})", item.title, item.media_type, item.tmdb_id);
+        evicted[index] = true;
+    }
+
+    let mut index = 0;
+    content.retain(|_| {
+        index += 1;
+        !evicted[index - 1]
+    });
+}
+
+// Lowercased title with punctuation and extra whitespace removed, for grouping near-duplicates
+fn normalize_title(title: &str) -> String {
+    title.to_lowercase()
+        .split(|c: char| !c.is_alphanumeric())
+        .filter(|w| !w.is_empty())
+        .collect::<Vec<_>>()
+        .join(" ")
+}
+
+fn token_set(text: &str) -> HashSet<String> {
+    text.to_lowercase()
+        .split(|c: char| !c.is_alphanumeric())
+        .filter(|w| !w.is_empty())
+        .map(String::from)
+        .collect()
+}
+
+// Token set overlap between 0.0 (disjoint) and 1.0 (identical)
+fn jaccard(a: &HashSet<String>, b: &HashSet<String>) -> f32 {
+    if a.is_empty() && b.is_empty() {
+        return 1.0;
+    }
+    a.intersection(b).count() as f32 / a.union(b).count() as f32
+}
+
+// Parts of an Azure Storage connection string we care about
+#[derive(Debug, Clone, PartialEq)]
+struct ConnInfo {
+    account_name: String,
+    account_key: String,
+    endpoint_suffix: Option<String>,
+    default_endpoints_protocol: Option<String>,
+    blob_endpoint: Option<String>,
+}
+
+impl ConnInfo {
+    // Explicit blob endpoint for non-public clouds (Azure Government, China, Azurite),
+    // or None to use the default public cloud endpoint for the account
+    fn custom_blob_endpoint(&self) -> Option<String> {
+        if let Some(endpoint) = &self.blob_endpoint {
+            return Some(endpoint.trim_end_matches('/').to_string());
+        }
+
+        match self.endpoint_suffix.as_deref() {
+            Some(suffix) if suffix != "core.windows.net" => Some(format!(
+                "{}://{}.blob.{}",
+                self.default_endpoints_protocol.as_deref().unwrap_or("https"),
+                self.account_name,
+                suffix
+            )),
+            _ => None,
+        }
+    }
+}
+
+// Well-known account used by the Azurite emulator for UseDevelopmentStorage=true
+const AZURITE_ACCOUNT_NAME: &str = "devstoreaccount1";
+const AZURITE_ACCOUNT_KEY: &str = "Eby8vdM02xNOcqFlqUwJPLlmEtlCDXJ1OUzFT50uSRZ6IFsuFq2UVErCz4I6tq/K1SZFPTOtr/KBHBeksoGMGw==";
+const AZURITE_BLOB_ENDPOINT: &str = "http://127.0.0.1:10000/devstoreaccount1";
+
+// Parse a "Key=Value;Key=Value" Azure Storage connection string. Values are split at
+// the first '=' only, since base64 account keys end in '=' padding.
+fn parse_connection_string(connection_string: &str) -> Result<ConnInfo> {
+    let mut parts = HashMap::new();
+    for part in connection_string.split(';') {
+        if let Some((key, value)) = part.split_once('=') {
+            parts.insert(key.trim(), value.trim());
+        }
+    }
+
+    // Shorthand for the local Azurite storage emulator and its well-known credentials
+    if parts.get("UseDevelopmentStorage").map_or(false, |v| v.eq_ignore_ascii_case("true")) {
+        return Ok(ConnInfo {
+            account_name: AZURITE_ACCOUNT_NAME.to_string(),
+            account_key: AZURITE_ACCOUNT_KEY.to_string(),
+            endpoint_suffix: None,
+            default_endpoints_protocol: Some("http".to_string()),
+            blob_endpoint: Some(AZURITE_BLOB_ENDPOINT.to_string()),
+        });
+    }
+
+    let account_name = parts.get("AccountName")
+        .filter(|v| !v.is_empty())
+        .ok_or_else(|| anyhow::anyhow!("AccountName not found"))?;
+    let account_key = parts.get("AccountKey")
+        .filter(|v| !v.is_empty())
+        .ok_or_else(|| anyhow::anyhow!("AccountKey not found"))?;
+
+    Ok(ConnInfo {
+        account_name: account_name.to_string(),
+        account_key: account_key.to_string(),
+        endpoint_suffix: parts.get("EndpointSuffix").map(|v| v.to_string()),
+        default_endpoints_protocol: parts.get("DefaultEndpointsProtocol").map(|v| v.to_string()),
+        blob_endpoint: parts.get("BlobEndpoint").map(|v| v.to_string()),
+    })
+}
+
+// Pick the highest quality language from an Accept-Language header, e.g.
+// "fr-CA,fr;q=0.9,en;q=0.8" gives "fr-CA". Ties keep the header's order.
+fn preferred_language(header: Option<&str>, default: &str) -> String {
+    let mut best: Option<(&str, f32)> = None;
+
+    for entry in header.unwrap_or_default().split(',') {
+        let mut parts = entry.split(';');
+        let tag = parts.next().unwrap_or_default().trim();
+        let quality = parts
+            .filter_map(|p| p.trim().strip_prefix("q="))
+            .next()
+            .map(|q| q.trim().parse::<f32>().unwrap_or(0.0))
+            .unwrap_or(1.0);
+
+        if tag.is_empty() || tag == "*" || quality <= 0.0 {
+            continue;
+        }
+        if best.map_or(true, |(_, best_quality)| quality > best_quality) {
+            best = Some((tag, quality));
+        }
+    }
+
+    best.map(|(tag, _)| tag.to_string())
+        .unwrap_or_else(|| default.to_string())
+}
+
+// Cache-Control and Expires headers letting clients cache a response until the
+// catalog it was built from is due for a refresh
+fn cache_headers(last_updated: chrono::DateTime<chrono::Utc>) -> [(&'static str, String); 2] {
+    let expires = last_updated + chrono::Duration::hours(CACHE_TTL_HOURS);
+    let max_age = expires.signed_duration_since(chrono::Utc::now()).num_seconds().max(0);
+
+    [
+        ("Cache-Control", format!("public, max-age={}", max_age)),
+        ("Expires", expires.format("%a, %d %b %Y %H:%M:%S GMT").to_string()),
+    ]
+}
+
+// TMDB response status worth retrying (5xx or 429)
+#[derive(Debug)]
+struct RetryableStatus(reqwest::StatusCode);
+
+impl std::fmt::Display for RetryableStatus {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "TMDB responded {}", self.0)
+    }
+}
+
+impl std::error::Error for RetryableStatus {}
+
+// 64-bit FNV-1a. Used for anything persisted, because unlike DefaultHasher its
+// output is fixed across builds and Rust versions.
+fn fnv1a(bytes: &[u8]) -> u64 {
+    bytes.iter().fold(0xcbf29ce484222325u64, |hash, byte| {
+        (hash ^ *byte as u64).wrapping_mul(0x100000001b3)
+    })
+}
+
+// Stable hash of everything a save persists apart from last_updated and the snapshot
+// number, for change detection. fetched_at is left out as it changes on every scrape.
+// Maps and sets are sorted so equal state always hashes the same; the catalog itself
+// relies on scrape_content's stable ordering.
+fn state_hash(cache_data: &CacheData) -> Result<String> {
+    use std::collections::{BTreeMap, BTreeSet};
+
+    fn sorted<T: Ord>(sets: &HashMap<String, HashSet<T>>) -> BTreeMap<&String, BTreeSet<&T>> {
+        sets.iter().map(|(key, set)| (key, set.iter().collect())).collect()
+    }
+
+    let content: Vec<Content> = cache_data.content.iter()
+        .map(|c| Content { fetched_at: None, ..c.clone() })
+        .collect();
+    let exposure: BTreeMap<&String, &f64> = cache_data.exposure.iter().collect();
+    let bytes = serde_json::to_vec(&(
+        cache_data.schema_version,
+        content,
+        sorted(&cache_data.used_recommendations),
+        sorted(&cache_data.watchlists),
+        sorted(&cache_data.watched),
+        &cache_data.pins,
+        exposure,
+    ))?;
+
+    Ok(format!("{:016x}", fnv1a(&bytes)))
+}
+
+// Keys for ordering items that score the same, applied in order. Every chain ends
+// with tmdb_id, so the order is always total.
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum TieBreaker {
+    VoteCount,  // most votes first
+    Popularity, // most popular first
+    Year,       // newest first
+    TmdbId,     // lowest id first
+}
+
+impl TieBreaker {
+    fn parse(value: &str) -> Option<Self> {
+        match value {
+            "vote_count" => Some(TieBreaker::VoteCount),
+            "popularity" => Some(TieBreaker::Popularity),
+            "year" => Some(TieBreaker::Year),
+            "tmdb_id" => Some(TieBreaker::TmdbId),
+            _ => None,
+        }
+    }
+}
+
+// How items without a parseable year are treated by year filters and year sorts.
+// Include and Exclude keep or drop them, sorting them after every dated item;
+// TreatAsOldest and TreatAsNewest keep them as if from the far past or future.
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum YearlessPolicy {
+    Include,
+    Exclude,
+    TreatAsOldest,
+    TreatAsNewest,
+}
+
+impl YearlessPolicy {
+    fn parse(value: &str) -> Option<Self> {
+        match value {
+            "include" => Some(YearlessPolicy::Include),
+            "exclude" => Some(YearlessPolicy::Exclude),
+            "oldest" => Some(YearlessPolicy::TreatAsOldest),
+            "newest" => Some(YearlessPolicy::TreatAsNewest),
+            _ => None,
+        }
+    }
+
+    // Year used for filtering and sorting, None when the item has none and is
+    // neither oldest nor newest
+    fn effective_year(&self, content: &Content) -> Option<i32> {
+        content_year(content).or(match self {
+            YearlessPolicy::TreatAsOldest => Some(i32::MIN),
+            YearlessPolicy::TreatAsNewest => Some(i32::MAX),
+            YearlessPolicy::Include | YearlessPolicy::Exclude => None,
+        })
+    }
+
+    // Whether an item passes the min_year/max_year bounds
+    fn within(&self, content: &Content, min_year: Option<i32>, max_year: Option<i32>) -> bool {
+        match self.effective_year(content) {
+            Some(year) => min_year.map_or(true, |min| year >= min) && max_year.map_or(true, |max| year <= max),
+            None => *self == YearlessPolicy::Include,
+        }
+    }
+}
+
+// How per-region streaming providers become the flat where_to_watch list. Union
+// lists WATCH_REGION's providers then any other region's not already listed;
+// Primary lists only WATCH_REGION's.
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum ProviderFlattening {
+    Union,
+    Primary,
+}
+
+impl ProviderFlattening {
+    fn parse(value: &str) -> Option<Self> {
+        match value {
+            "union" => Some(ProviderFlattening::Union),
+            "primary" => Some(ProviderFlattening::Primary),
+            _ => None,
+        }
+    }
+}
+
+// Flatten streaming providers by region into one list, primary region first and
+// then the others in the order given. A service offered in several regions is
+// listed once.
+fn flatten_providers(
+    region_providers: &std::collections::BTreeMap<String, Vec<String>>,
+    regions: &[String],
+    flattening: ProviderFlattening,
+) -> Vec<String> {
+    let regions = match flattening {
+        ProviderFlattening::Union => regions,
+        ProviderFlattening::Primary => &regions[..regions.len().min(1)],
+    };
+
+    let mut seen = HashSet::new();
+    regions.iter()
+        .flat_map(|region| region_providers.get(region).into_iter().flatten())
+        .filter(|name| seen.insert(name.as_str()))
+        .cloned()
+        .collect()
+}
+
+// IMDb-style weighted rating: (v / (v + m)) * R + (m / (v + m)) * C for an item
+// rated R from v votes, with m the minimum votes and C the mean rating. Items
+// with few votes score close to the mean rather than at their raw rating.
+fn weighted_rating(content: &Content, mean: f32, min_votes: f32) -> f32 {
+    let votes = content.vote_count.unwrap_or(0).max(0) as f32;
+    let rating = content.rating.unwrap_or(mean);
+    if votes + min_votes <= 0.0 {
+        return rating;
+    }
+    (votes / (votes + min_votes)) * rating + (min_votes / (votes + min_votes)) * mean
+}
+
+// Mean rating of the items that have votes, the C in weighted_rating
+fn mean_rating(content: &[Content]) -> f32 {
+    let rated: Vec<f32> = content.iter()
+        .filter(|c| c.vote_count.unwrap_or(0) > 0)
+        .filter_map(|c| c.rating)
+        .collect();
+    if rated.is_empty() {
+        0.0
+    } else {
+        rated.iter().sum::<f32>() / rated.len() as f32
+    }
+}
+
+// Release (or first air) year, if the item has a usable one
+fn content_year(content: &Content) -> Option<i32> {
+    content.year.as_deref()
+        .and_then(|y| y.trim().parse::<i32>().ok())
+}
+
+fn break_tie(a: &Content, b: &Content, tie_breakers: &[TieBreaker], yearless: YearlessPolicy) -> std::cmp::Ordering {
+    tie_breakers.iter()
+        .map(|key| match key {
+            TieBreaker::VoteCount => b.vote_count.unwrap_or(0).cmp(&a.vote_count.unwrap_or(0)),
+            TieBreaker::Popularity => b.popularity.unwrap_or(0.0).total_cmp(&a.popularity.unwrap_or(0.0)),
+            // Newest first, with items that have no effective year last
+            TieBreaker::Year => match (yearless.effective_year(a), yearless.effective_year(b)) {
+                (Some(a), Some(b)) => b.cmp(&a),
+                (Some(_), None) => std::cmp::Ordering::Less,
+                (None, Some(_)) => std::cmp::Ordering::Greater,
+                (None, None) => std::cmp::Ordering::Equal,
+            },
+            TieBreaker::TmdbId => (&a.media_type, a.tmdb_id).cmp(&(&b.media_type, b.tmdb_id)),
+        })
+        .find(|ordering| ordering.is_ne())
+        .unwrap_or(std::cmp::Ordering::Equal)
+}
+
+// Split a blob payload into staged blocks of at most block_size bytes, each with its
+// id. Ids are derived from the block index and all the same length, as Azure
+// requires, so a retry simply restages over the previous attempt's blocks.
+fn blob_blocks(payload: &[u8], block_size: usize) -> Vec<(String, &[u8])> {
+    payload.chunks(block_size)
+        .enumerate()
+        .map(|(index, chunk)| (format!("block-{:06}", index), chunk))
+        .collect()
+}
+
+// Exponential backoff for the given retry (1 for the first), capped at max and
+// jittered down to half its value so instances that failed together spread out
+fn backoff_delay(retry: u32, base: std::time::Duration, max: std::time::Duration) -> std::time::Duration {
+    use rand::Rng;
+
+    let delay = base.saturating_mul(2u32.saturating_pow(retry.saturating_sub(1))).min(max);
+    let jitter = rand::thread_rng().gen_range(0.5..=1.0);
+    delay.mul_f64(jitter)
+}
+
+// Run op up to attempts times, sleeping with jittered backoff between failures
+async fn with_retries<T, E, F, Fut>(label: &str, attempts: u32, base: std::time::Duration,
+                                    max: std::time::Duration, mut op: F) -> std::result::Result<T, E>
+where
+    E: std::fmt::Display,
+    F: FnMut() -> Fut,
+    Fut: std::future::Future<Output = std::result::Result<T, E>>,
+{
+    let mut retry = 0;
+    loop {
+        match op().await {
+            Ok(value) => return Ok(value),
+            Err(e) if retry + 1 >= attempts => return Err(e),
+            Err(e) => {
+                retry += 1;
+                let delay = backoff_delay(retry, base, max);
+                println!("{} failed (attempt {}): {}, retrying in {:?}", label, retry, e, delay);
+                tokio::time::sleep(delay).await;
+            }
+        }
+    }
+}
+
+struct ContentService {
+    blob_client: ContainerClient,
+    cache: Arc<RwLock<ContentCache>>,
+    tmdb_api_key: String,
+    language: String,
+    region: String,
+    // Further regions whose streaming providers are collected, and how they're
+    // merged into where_to_watch
+    provider_regions: Vec<String>,
+    provider_flattening: ProviderFlattening,
+    scrape_media_types: HashSet<String>,
+    scrape_concurrency: usize,
+    scrape_permits: tokio::sync::Semaphore,
+    tmdb_retries: u32,
+    circuit_breaker: CircuitBreaker,
+    fallback_size: usize,
+    tie_breakers: Vec<TieBreaker>,
+    yearless: YearlessPolicy,
+    default_strategy: StrategyKind,
+    default_min_rating: f32,
+    fetch_trailers: bool,
+    default_poster_url: Option<String>,
+    provider_priority_descending: bool,
+    deterministic: bool,
+    max_cache_items: Option<usize>,
+    max_item_age_days: Option<i64>,
+    keep_top_rated: bool,
+    dedup_similarity: Option<f32>,
+    admin_token: Option<String>,
+    blob_block_size: usize,
+    blob_format: BlobFormat,
+    blob_compression: flate2::Compression,
+    exposure_decay: f64,
+    discover_sources: Vec<DiscoverSource>,
+    scrape_request_budget: Option<usize>,
+    genre_discover_depth: u32,
+    genre_backfill_min_items: Option<usize>,
+    genre_backfill_budget: usize,
+    genre_adjacency: HashMap<String, Vec<String>>,
+    refresh_in_progress: std::sync::atomic::AtomicBool,
+    // Set once the catalog has first been populated, from blob or a scrape
+    ready: std::sync::atomic::AtomicBool,
+    last_refresh_success: RwLock<Option<chrono::DateTime<chrono::Utc>>>,
+    refresh_task_restarts: std::sync::atomic::AtomicUsize,
+    profiles: RwLock<HashMap<String, UserPreferences>>,
+    max_profiles: usize,
+    // TMDB provider id to logo URL, refreshed once per scrape
+    provider_logos: RwLock<HashMap<i64, String>>,
+    enrichment_sample: f32,
+    // TMDB genre id to name, for items that skip enrichment
+    genre_names: RwLock<HashMap<i64, String>>,
+    page_cache: Mutex<PageCache>,
+    max_page_size: usize,
+    reject_oversized_pages: bool,
+    // Snapshot number of the catalog blob last written, held for the whole save
+    last_saved_snapshot: tokio::sync::Mutex<u64>,
+    // Catalog change notifications; subscribe with events.subscribe()
+    events: tokio::sync::broadcast::Sender<CatalogEvent>,
+    // The m in weighted_rating: votes needed before an item's own rating dominates
+    quality_min_votes: f32,
+    // How often to apply TMDB's change lists between full refreshes, and the end of
+    // the range last applied
+    incremental_refresh_interval: Option<std::time::Duration>,
+    changes_checked_at: Mutex<Option<chrono::DateTime<chrono::Utc>>>,
+    // Longest an API handler may run before the client gets a 504
+    request_timeout: Option<std::time::Duration>,
+    // Oldest a stale catalog may be and still be recommended from
+    max_stale_age: Option<chrono::Duration>,
+    // When to refresh, instead of every CACHE_TTL_HOURS
+    scrape_cron: Option<cron::Schedule>,
+    // Open /ws sockets, and how many may be open at once
+    ws_connections: std::sync::atomic::AtomicUsize,
+    max_ws_connections: usize,
+    // TMDB recommendations by (media type, id, language), with when they were fetched
+    tmdb_recommendations_cache: Mutex<HashMap<(String, i64, String), (std::time::Instant, Vec<Content>)>>,
+}
+
+// Features that send TMDB_API_KEY as a v4 bearer token, and so fail with a v3 key
+const BEARER_FEATURES: &[&str] = &[
+    "catalog scraping (recommendations, /genres, /providers, /trending)",
+    "/search",
+    "/similar",
+    "/tmdb-recommendations",
+    "/hydrate live fetches",
+    "v4 list endpoints",
+];
+
+// Warn at startup when TMDB_API_KEY is a v3 key rather than a v4 read access token.
+// Every TMDB request here uses bearer auth, which rejects v3 keys, so otherwise the
+// only symptom is empty lists. Tries the key as a bearer token first, then as a v3
+// api_key parameter. Network errors are logged and ignored so startup isn't blocked.
+async fn check_tmdb_key(api_key: &str) {
+    let client = reqwest::Client::new();
+    let url = "https://api.themoviedb.org/3/configuration";
+
+    let bearer = client.get(url)
+        .header("Authorization", format!("Bearer {}", api_key))
+        .timeout(std::time::Duration::from_secs(10))
+        .send()
+        .await;
+    let bearer_status = match bearer {
+        Ok(response) => response.status(),
+        Err(e) => {
+            println!("Could not verify TMDB_API_KEY: {}", e);
+            return;
+        }
+    };
+    if bearer_status.is_success() {
+        println!("TMDB_API_KEY accepted as a v4 read access token");
+        return;
+    }
+
+    let v3 = client.get(url)
+        .query(&[("api_key", api_key)])
+        .timeout(std::time::Duration::from_secs(10))
+        .send()
+        .await;
+    match v3 {
+        Ok(response) if response.status().is_success() => {
+            eprintln!("WARNING: TMDB_API_KEY is a v3 API key, but bearer auth needs a v4 read access token.");
+            eprintln!("WARNING: These features will return empty results until it's replaced:");
+            for feature in BEARER_FEATURES {
+                eprintln!("WARNING:   - {}", feature);
+            }
+        },
+        Ok(_) => eprintln!("WARNING: TMDB rejected TMDB_API_KEY ({}), TMDB requests will fail", bearer_status),
+        Err(e) => println!("Could not verify TMDB_API_KEY: {}", e),
+    }
+}
+
+impl ContentService {
+    async fn new() -> Result<Self> {
+        // Get TMDB API key
+        let tmdb_api_key = env::var("TMDB_API_KEY")?;
+        check_tmdb_key(&tmdb_api_key).await;
+
+        let blob_client = Self::connect_blob_container().await?;
+        Self::from_env(tmdb_api_key, blob_client)
+    }
+
+    // Connect to the catalog's blob container, creating it if it doesn't exist
+    async fn connect_blob_container() -> Result<ContainerClient> {
+        // Get Azure Storage connection string
+        let connection_string = env::var("AZURE_STORAGE_CONNECTION_STRING")
+            .expect("AZURE_STORAGE_CONNECTION_STRING must be set");
+
+        println!("Parsing connection string...");
+        let conn_info = parse_connection_string(&connection_string)?;
+        let account = &conn_info.account_name;
+        let key = &conn_info.account_key;
+
+        println!("Account: {}", account);
+        println!("Key length: {}", key.len());
+
+        // Create credentials first
+        let credentials = StorageCredentials::access_key(account.to_string(), key.to_string());
+        println!("Created credentials");
+
+        // AZURE_BLOB_ENDPOINT overrides whatever endpoint the connection string implies
+        let blob_endpoint = env::var("AZURE_BLOB_ENDPOINT").ok()
+            .filter(|e| !e.is_empty())
+            .map(|e| e.trim_end_matches('/').to_string())
+            .or_else(|| conn_info.custom_blob_endpoint());
+
+        let blob_service_client = match blob_endpoint {
+            Some(uri) => {
+                println!("Using custom blob endpoint: {}", uri);
+                let location = CloudLocation::Custom { account: account.to_string(), uri };
+                ClientBuilder::with_location(location, credentials).blob_service_client()
+            },
+            // Create the service client with just the account name
+            None => BlobServiceClient::new(account.to_string(), credentials),
+        };
+        println!("Created blob service client");
+
+        let container_name = "content-data";
+        let container_client = blob_service_client.container_client(container_name);
+        println!("Created container client for: {}", container_name);
+
+        // Try to list containers first
+        println!("\nListing all containers to test connectivity...");
+        let mut containers = blob_service_client.list_containers()
+            .into_stream();
+
+        let mut found = false;
+        while let Some(container_result) = containers.next().await {
+            match container_result {
+                Ok(response) => {
+                    for container in response.containers {
+                        println!("Found container: {}", container.name);
+                        println!("  Last modified: {}", container.last_modified);
+                        println!("  Public access: {:?}", container.public_access);
+                        println!("  Lease status: {:?}", container.lease_status);
+
+                        if container.name == container_name {
+                            found = true;
+                            println!("Target container already exists");
+                        }
+                    }
+
+                    if let Some(marker) = response.next_marker {
+                        println!("More containers available, next marker: {}", marker);
+                    }
+                },
+                Err(e) => {
+                    println!("Error listing containers: {}", e);
+                    println!("Full error details: {:?}", e);
+                }
+            }
+        }
+
+        if !found {
+            println!("\nTarget container not found, attempting to create it...");
+            match container_client.create()
+                .public_access(PublicAccess::None)
+                .await
+            {
+                Ok(_) => println!("Container created successfully"),
+                Err(e) => {
+                    println!("Error creating container: {}", e);
+                    println!("Full error details: {:?}", e);
+                    return Err(anyhow::anyhow!("Failed to create container: {}", e));
+                }
+            }
+        }
+
+        Ok(container_client)
+    }
+
+    // The rest of the configuration, read from the environment without any network
+    // access
+    fn from_env(tmdb_api_key: String, blob_client: ContainerClient) -> Result<Self> {
+        // Language the cached catalog is scraped in. Live endpoints (/search, /similar)
+        // use the client's Accept-Language instead, falling back to this.
+        let language = env::var("TMDB_LANGUAGE").unwrap_or_else(|_| "en-US".to_string());
+        println!("Using language: {}", language);
+
+        // Region used for theatrical (now playing) listings and watch providers, e.g. "US" or "GB"
+        let region = match env::var("WATCH_REGION") {
+            Ok(value) => normalize_region(&value).ok_or_else(|| anyhow::anyhow!(
+                "WATCH_REGION must be an ISO 3166-1 alpha-2 code like US or GB, got: {}", value
+            ))?,
+            Err(_) => "US".to_string(),
+        };
+        println!("Using region: {}", region);
+
+        // Extra regions to collect streaming providers for, e.g. "GB,CA". Each
+        // item keeps its providers by region; where_to_watch merges them
+        // (PROVIDER_FLATTENING=union, the default) or shows WATCH_REGION's only
+        // (PROVIDER_FLATTENING=primary).
+        let provider_regions = match env::var("PROVIDER_REGIONS") {
+            Ok(value) => {
+                let mut regions = Vec::new();
+                for code in value.split(',').map(str::trim).filter(|code| !code.is_empty()) {
+                    let code = normalize_region(code).ok_or_else(|| anyhow::anyhow!(
+                        "PROVIDER_REGIONS entries must be ISO 3166-1 alpha-2 codes, got: {}", code
+                    ))?;
+                    if code != region && !regions.contains(&code) {
+                        regions.push(code);
+                    }
+                }
+                regions
+            },
+            Err(_) => Vec::new(),
+        };
+        let provider_flattening = match env::var("PROVIDER_FLATTENING") {
+            Ok(value) => ProviderFlattening::parse(&value)
+                .ok_or_else(|| anyhow::anyhow!("PROVIDER_FLATTENING must be union or primary, got: {}", value))?,
+            Err(_) => ProviderFlattening::Union,
+        };
+        if !provider_regions.is_empty() {
+            println!("Also collecting providers for: {}", provider_regions.join(", "));
+        }
+
+        // Media types scraped into the catalog, "movie", "tv" or "movie,tv" (the default).
+        // Recommendations never include a disabled type, even from an older saved catalog.
+        // The live endpoints (/trending, /search, /similar, /hydrate) query TMDB directly
+        // and are unaffected.
+        let scrape_media_types: HashSet<String> = match env::var("SCRAPE_MEDIA_TYPES") {
+            Ok(value) => {
+                let types: HashSet<String> = value.split(',')
+                    .map(|t| t.trim().to_string())
+                    .filter(|t| !t.is_empty())
+                    .collect();
+                if types.is_empty() || types.iter().any(|t| t != "movie" && t != "tv") {
+                    return Err(anyhow::anyhow!("SCRAPE_MEDIA_TYPES must be movie, tv or movie,tv, got: {}", value));
+                }
+                types
+            },
+            Err(_) => ["movie", "tv"].iter().map(|t| t.to_string()).collect(),
+        };
+        println!("Scraping media types: {:?}", scrape_media_types);
+
+        // Maximum number of TMDB requests in flight during a scrape, across both list
+        // sources and per-item enrichment. Each in-flight request buffers a JSON body,
+        // so 1-4 is safe on hosts with under 512MB of memory; 8 (the default) to 16
+        // suits larger hosts. Going much higher mostly trips TMDB's rate limit.
+        let scrape_concurrency = match env::var("SCRAPE_CONCURRENCY") {
+            Ok(value) => match value.parse::<usize>() {
+                Ok(n) if n > 0 => n,
+                _ => return Err(anyhow::anyhow!("SCRAPE_CONCURRENCY must be a positive integer, got: {}", value)),
+            },
+            Err(_) => 8,
+        };
+        println!("Using scrape concurrency: {}", scrape_concurrency);
+
+        // Retries for TMDB requests failing with a network error, 5xx or 429
+        let tmdb_retries = match env::var("TMDB_RETRIES") {
+            Ok(value) => value.parse::<u32>()
+                .map_err(|_| anyhow::anyhow!("TMDB_RETRIES must be a non-negative integer, got: {}", value))?,
+            Err(_) => 2,
+        };
+
+        // Number of top rated items returned when a user's filters match nothing
+        let fallback_size = env::var("FALLBACK_SIZE")
+            .ok()
+            .and_then(|v| v.parse::<usize>().ok())
+            .unwrap_or(20);
+
+        // Comma-separated tie-break chain for equally rated items, from vote_count,
+        // popularity, year and tmdb_id. tmdb_id is always appended as the last resort.
+        let mut tie_breakers = match env::var("TIE_BREAKERS") {
+            Ok(value) => value.split(',')
+                .map(|key| TieBreaker::parse(key.trim())
+                    .ok_or_else(|| anyhow::anyhow!("Unknown TIE_BREAKERS key: {}", key.trim())))
+                .collect::<Result<Vec<_>>>()?,
+            Err(_) => vec![TieBreaker::VoteCount, TieBreaker::Year],
+        };
+        if !tie_breakers.contains(&TieBreaker::TmdbId) {
+            tie_breakers.push(TieBreaker::TmdbId);
+        }
+
+        // Items with no usable year: include (default), exclude, oldest or newest.
+        // See YearlessPolicy.
+        let yearless = match env::var("YEARLESS_ITEMS") {
+            Ok(value) => YearlessPolicy::parse(&value)
+                .ok_or_else(|| anyhow::anyhow!("YEARLESS_ITEMS must be include, exclude, oldest or newest, got: {}", value))?,
+            Err(_) => YearlessPolicy::Include,
+        };
+
+        // Computed pages kept for GET /recommendations/page, and for how long
+        let page_cache_size = match env::var("PAGE_CACHE_SIZE") {
+            Ok(value) => value.parse::<usize>()
+                .map_err(|_| anyhow::anyhow!("PAGE_CACHE_SIZE must be a non-negative integer, got: {}", value))?,
+            Err(_) => 256,
+        };
+        let page_cache_ttl_secs = match env::var("PAGE_CACHE_TTL_SECS") {
+            Ok(value) => value.parse::<u64>()
+                .map_err(|_| anyhow::anyhow!("PAGE_CACHE_TTL_SECS must be a number of seconds, got: {}", value))?,
+            Err(_) => 300,
+        };
+
+        // Largest page_size /recommendations/page serves, 100 by default. Larger requests
+        // are clamped to it, or rejected with a 400 when PAGE_SIZE_OVERFLOW=reject.
+        let max_page_size = match env::var("MAX_PAGE_SIZE") {
+            Ok(value) => match value.parse::<usize>() {
+                Ok(n) if n > 0 => n,
+                _ => return Err(anyhow::anyhow!("MAX_PAGE_SIZE must be a positive integer, got: {}", value)),
+            },
+            Err(_) => 100,
+        };
+        let reject_oversized_pages = match env::var("PAGE_SIZE_OVERFLOW").as_deref() {
+            Ok("reject") => true,
+            Ok("clamp") | Err(_) => false,
+            Ok(other) => return Err(anyhow::anyhow!("PAGE_SIZE_OVERFLOW must be clamp or reject, got: {}", other)),
+        };
+
+        // Strategy used when a request doesn't name one: default (random among matches),
+        // weighted (best scoring first), similar (most favorite genres matched first)
+        // or quality (highest quality_score first)
+        let default_strategy = match env::var("RECOMMENDATION_STRATEGY") {
+            Ok(value) => StrategyKind::parse(&value)
+                .ok_or_else(|| anyhow::anyhow!("RECOMMENDATION_STRATEGY must be default, weighted, similar or quality, got: {}", value))?,
+            Err(_) => StrategyKind::Default,
+        };
+
+        // Floor applied to every request's minimum_rating, so clients that omit it or
+        // send 0.0 don't get flooded with poorly rated items. Defaults to no floor.
+        let default_min_rating = match env::var("DEFAULT_MIN_RATING") {
+            Ok(value) => match value.parse::<f32>() {
+                Ok(r) if (0.0..=10.0).contains(&r) => r,
+                _ => return Err(anyhow::anyhow!("DEFAULT_MIN_RATING must be between 0.0 and 10.0, got: {}", value)),
+            },
+            Err(_) => 0.0,
+        };
+
+        // Fraction (0.0-1.0) of each list page that gets a details request. The rest
+        // keep only list fields: genres from genre_ids, but no providers or trailer,
+        // so they won't show where_to_watch. Defaults to enriching everything.
+        let enrichment_sample = match env::var("ENRICHMENT_SAMPLE") {
+            Ok(value) => match value.parse::<f32>() {
+                Ok(f) if (0.0..=1.0).contains(&f) => f,
+                _ => return Err(anyhow::anyhow!("ENRICHMENT_SAMPLE must be between 0.0 and 1.0, got: {}", value)),
+            },
+            Err(_) => 1.0,
+        };
+
+        // Trailers come with the details request rather than a request of their own,
+        // but TMDB's videos list can run to dozens of entries and enlarges every details
+        // response, so they stay opt-in
+        let fetch_trailers = env_flag("FETCH_TRAILERS", false);
+
+        // Placeholder image used as poster_url for items TMDB has no poster for. Unset
+        // leaves poster_url null for those items, for clients that handle it themselves.
+        let default_poster_url = match env::var("DEFAULT_POSTER_URL") {
+            Ok(value) => match Url::parse(&value) {
+                Ok(url) if matches!(url.scheme(), "http" | "https") => Some(value),
+                _ => return Err(anyhow::anyhow!("DEFAULT_POSTER_URL must be an http or https URL, got: {}", value)),
+            },
+            Err(_) => None,
+        };
+
+        // Order providers by TMDB display priority, "asc" (default) or "desc"
+        let provider_priority_descending = match env::var("PROVIDER_PRIORITY_ORDER").as_deref() {
+            Ok("desc") => true,
+            Ok("asc") | Err(_) => false,
+            Ok(other) => return Err(anyhow::anyhow!("PROVIDER_PRIORITY_ORDER must be asc or desc, got: {}", other)),
+        };
+
+        // DETERMINISTIC disables all randomness so responses can be asserted exactly in
+        // end-to-end tests. It is for testing only: every user sees the same order.
+        let deterministic = env_flag("DETERMINISTIC", false);
+        if deterministic {
+            println!("WARNING: DETERMINISTIC mode enabled, all shuffling uses a fixed seed");
+        }
+
+        // Upper bound on catalog size, unset for no limit
+        let max_cache_items = match env::var("MAX_CACHE_ITEMS") {
+            Ok(value) => match value.parse::<usize>() {
+                Ok(n) if n > 0 => Some(n),
+                _ => return Err(anyhow::anyhow!("MAX_CACHE_ITEMS must be a positive integer, got: {}", value)),
+            },
+            Err(_) => None,
+        };
+
+        // Drop items released more than this many days ago, unset to keep everything.
+        // Items from the top_rated lists are exempt unless MAX_ITEM_AGE_KEEP_TOP_RATED=false.
+        let max_item_age_days = match env::var("MAX_ITEM_AGE_DAYS") {
+            Ok(value) => match value.parse::<i64>() {
+                Ok(days) if days > 0 => Some(days),
+                _ => return Err(anyhow::anyhow!("MAX_ITEM_AGE_DAYS must be a positive integer, got: {}", value)),
+            },
+            Err(_) => None,
+        };
+        let keep_top_rated = env_flag("MAX_ITEM_AGE_KEEP_TOP_RATED", true);
+
+        // Collapse items sharing a title whose descriptions overlap at least this much
+        // (0.0-1.0 token overlap). Unset to keep every distinct TMDB id.
+        let dedup_similarity = match env::var("DEDUP_SIMILARITY") {
+            Ok(value) => match value.parse::<f32>() {
+                Ok(t) if (0.0..=1.0).contains(&t) => Some(t),
+                _ => return Err(anyhow::anyhow!("DEDUP_SIMILARITY must be between 0.0 and 1.0, got: {}", value)),
+            },
+            Err(_) => None,
+        };
+
+        // Token required by the /admin endpoints, which are disabled when unset
+        let admin_token = env::var("ADMIN_TOKEN").ok().filter(|t| !t.is_empty());
+        if admin_token.is_none() {
+            println!("ADMIN_TOKEN not set, admin endpoints are disabled");
+        }
+
+        // Blobs larger than this many bytes (default 4 MiB) are uploaded as staged blocks
+        // of this size rather than in a single request
+        let blob_block_size = match env::var("BLOB_BLOCK_SIZE") {
+            Ok(value) => match value.parse::<usize>() {
+                Ok(n) if n > 0 && n <= MAX_BLOB_BLOCK_SIZE => n,
+                _ => return Err(anyhow::anyhow!("BLOB_BLOCK_SIZE must be between 1 and {} bytes, got: {}", MAX_BLOB_BLOCK_SIZE, value)),
+            },
+            Err(_) => 4 * 1024 * 1024,
+        };
+
+        // Serialization for the catalog blob, "json" (default) or "messagepack"
+        let blob_format = match env::var("BLOB_FORMAT") {
+            Ok(value) => BlobFormat::parse(&value)
+                .ok_or_else(|| anyhow::anyhow!("BLOB_FORMAT must be json or messagepack, got: {}", value))?,
+            Err(_) => BlobFormat::Json,
+        };
+
+        // Gzip level (0-9) for the catalog and profiles blobs. Higher levels shrink large
+        // catalogs at the cost of CPU on each upload. Defaults to flate2's default (6).
+        let blob_compression = match env::var("BLOB_COMPRESSION_LEVEL") {
+            Ok(value) => match value.parse::<u32>() {
+                Ok(level) if level <= 9 => flate2::Compression::new(level),
+                _ => return Err(anyhow::anyhow!("BLOB_COMPRESSION_LEVEL must be between 0 and 9, got: {}", value)),
+            },
+            Err(_) => flate2::Compression::default(),
+        };
+
+        // Factor (0.0-1.0) applied to every item's global show count on each refresh, so
+        // fair_exposure reflects recent showings. 0.5 (the default) halves them.
+        let exposure_decay = match env::var("EXPOSURE_DECAY") {
+            Ok(value) => match value.parse::<f64>() {
+                Ok(f) if (0.0..=1.0).contains(&f) => f,
+                _ => return Err(anyhow::anyhow!("EXPOSURE_DECAY must be between 0.0 and 1.0, got: {}", value)),
+            },
+            Err(_) => 0.5,
+        };
+
+        // Most saved profiles kept, bounding the size of the profiles blob
+        let max_profiles = match env::var("MAX_PROFILES") {
+            Ok(value) => value.parse::<usize>()
+                .map_err(|_| anyhow::anyhow!("MAX_PROFILES must be a non-negative integer, got: {}", value))?,
+            Err(_) => 1000,
+        };
+
+        // Votes an item needs before its quality_score leans more on its own rating
+        // than on the catalog mean
+        let quality_min_votes = match env::var("QUALITY_MIN_VOTES") {
+            Ok(value) => match value.parse::<f32>() {
+                Ok(votes) if votes >= 0.0 && votes.is_finite() => votes,
+                _ => return Err(anyhow::anyhow!("QUALITY_MIN_VOTES must be a non-negative number, got: {}", value)),
+            },
+            Err(_) => 100.0,
+        };
+
+        // Minutes between incremental refreshes, which re-fetch only the catalog items
+        // TMDB lists as changed (new providers, rating updates) since the last refresh
+        // or check. Full refreshes still run on their own schedule to pick up new
+        // titles. Off when unset.
+        let incremental_refresh_interval = match env::var("INCREMENTAL_REFRESH_MINS") {
+            Ok(value) => match value.parse::<u64>() {
+                Ok(mins) if mins > 0 => Some(std::time::Duration::from_secs(mins * 60)),
+                _ => return Err(anyhow::anyhow!("INCREMENTAL_REFRESH_MINS must be a positive number of minutes, got: {}", value)),
+            },
+            Err(_) => None,
+        };
+
+        // Seconds an API request may take before the client gets a 504, with the
+        // handler left to finish in the background. Unset or 0 means no limit.
+        let request_timeout = match env::var("REQUEST_TIMEOUT_SECS") {
+            Ok(value) => match value.parse::<u64>() {
+                Ok(0) => None,
+                Ok(secs) => Some(std::time::Duration::from_secs(secs)),
+                Err(_) => return Err(anyhow::anyhow!("REQUEST_TIMEOUT_SECS must be a number of seconds, got: {}", value)),
+            },
+            Err(_) => None,
+        };
+
+        // Hours old the catalog may get before recommendations are refused with a 503
+        // rather than served stale, e.g. when refreshes keep failing during a long
+        // TMDB outage. Unset means stale content is always served.
+        let max_stale_age = match env::var("MAX_STALE_HOURS") {
+            Ok(value) => match value.parse::<i64>() {
+                Ok(hours) if hours > 0 => Some(chrono::Duration::hours(hours)),
+                _ => return Err(anyhow::anyhow!("MAX_STALE_HOURS must be a positive number of hours, got: {}", value)),
+            },
+            Err(_) => None,
+        };
+
+        // Cron schedule for refreshes in UTC, with a leading seconds field, e.g.
+        // "0 0 3 * * *" for 3am daily. Without it the catalog refreshes every
+        // CACHE_TTL_HOURS.
+        let scrape_cron = match env::var("SCRAPE_CRON") {
+            Ok(value) => {
+                let schedule = value.parse::<cron::Schedule>()
+                    .map_err(|e| anyhow::anyhow!("SCRAPE_CRON must be a cron expression, got: {} ({})", value, e))?;
+                println!("Refreshing content on the schedule {}", value);
+                Some(schedule)
+            },
+            Err(_) => None,
+        };
+
+        // Most /ws sockets open at once; further upgrade requests get a 503
+        let max_ws_connections = match env::var("MAX_WS_CONNECTIONS") {
+            Ok(value) => value.parse::<usize>()
+                .map_err(|_| anyhow::anyhow!("MAX_WS_CONNECTIONS must be a non-negative integer, got: {}", value))?,
+            Err(_) => 100,
+        };
+
+        // Optional JSON file of extra discover queries to scrape alongside the fixed lists
+        let discover_sources = match env::var("DISCOVER_CONFIG") {
+            Ok(path) => load_discover_sources(&path)?,
+            Err(_) => Vec::new(),
+        };
+        println!("Using {} extra discover sources", discover_sources.len());
+
+        // List pages per refresh spent discovering the genres favored in saved profiles,
+        // shared evenly between them. Unset disables genre discovery.
+        let scrape_request_budget = match env::var("SCRAPE_REQUEST_BUDGET") {
+            Ok(value) => Some(value.parse::<usize>()
+                .map_err(|_| anyhow::anyhow!("SCRAPE_REQUEST_BUDGET must be a non-negative integer, got: {}", value))?),
+            Err(_) => None,
+        };
+
+        // Most pages discovered for any one genre, however large its share of the budget
+        let genre_discover_depth = match env::var("GENRE_DISCOVER_DEPTH") {
+            Ok(value) => match value.parse::<u32>() {
+                Ok(n) if n > 0 => n,
+                _ => return Err(anyhow::anyhow!("GENRE_DISCOVER_DEPTH must be a positive integer, got: {}", value)),
+            },
+            Err(_) => MAX_LIST_PAGES,
+        };
+
+        // With GENRE_BACKFILL on, favored genres left with fewer than
+        // GENRE_BACKFILL_MIN_ITEMS items after a scrape get extra discover pages, at most
+        // GENRE_BACKFILL_BUDGET of them per refresh
+        let genre_backfill_min_items = if env_flag("GENRE_BACKFILL", false) {
+            Some(match env::var("GENRE_BACKFILL_MIN_ITEMS") {
+                Ok(value) => value.parse::<usize>()
+                    .map_err(|_| anyhow::anyhow!("GENRE_BACKFILL_MIN_ITEMS must be a non-negative integer, got: {}", value))?,
+                Err(_) => 20,
+            })
+        } else {
+            None
+        };
+        let genre_backfill_budget = match env::var("GENRE_BACKFILL_BUDGET") {
+            Ok(value) => value.parse::<usize>()
+                .map_err(|_| anyhow::anyhow!("GENRE_BACKFILL_BUDGET must be a non-negative integer, got: {}", value))?,
+            Err(_) => 10,
+        };
+
+        let genre_adjacency = match env::var("GENRE_ADJACENCY_FILE") {
+            Ok(path) => load_genre_adjacency(&path)?,
+            Err(_) => DEFAULT_GENRE_ADJACENCY.iter()
+                .map(|(genre, related)| (genre.to_string(), related.iter().map(|r| r.to_string()).collect()))
+                .collect(),
+        };
+
+        Ok(Self {
+            blob_client,
+            cache: Arc::new(RwLock::new(ContentCache::new())),
+            tmdb_api_key,
+            language,
+            region,
+            provider_regions,
+            provider_flattening,
+            scrape_media_types,
+            scrape_concurrency,
+            scrape_permits: tokio::sync::Semaphore::new(scrape_concurrency),
+            tmdb_retries,
+            circuit_breaker: CircuitBreaker::from_env()?,
+            fallback_size,
+            tie_breakers,
+            yearless,
+            default_strategy,
+            default_min_rating,
+            fetch_trailers,
+            default_poster_url,
+            provider_priority_descending,
+            deterministic,
+            max_cache_items,
+            max_item_age_days,
+            keep_top_rated,
+            dedup_similarity,
+            admin_token,
+            blob_block_size,
+            blob_format,
+            blob_compression,
+            exposure_decay,
+            discover_sources,
+            scrape_request_budget,
+            genre_discover_depth,
+            genre_backfill_min_items,
+            genre_backfill_budget,
+            genre_adjacency,
+            refresh_in_progress: std::sync::atomic::AtomicBool::new(false),
+            ready: std::sync::atomic::AtomicBool::new(false),
+            last_refresh_success: RwLock::new(None),
+            refresh_task_restarts: std::sync::atomic::AtomicUsize::new(0),
+            profiles: RwLock::new(HashMap::new()),
+            max_profiles,
+            provider_logos: RwLock::new(HashMap::new()),
+            enrichment_sample,
+            genre_names: RwLock::new(HashMap::new()),
+            page_cache: Mutex::new(PageCache::new(page_cache_size, std::time::Duration::from_secs(page_cache_ttl_secs))),
+            tmdb_recommendations_cache: Mutex::new(HashMap::new()),
+            max_page_size,
+            reject_oversized_pages,
+            last_saved_snapshot: tokio::sync::Mutex::new(0),
+            events: tokio::sync::broadcast::channel(EVENT_CHANNEL_CAPACITY).0,
+            quality_min_votes,
+            incremental_refresh_interval,
+            changes_checked_at: Mutex::new(None),
+            request_timeout,
+            max_stale_age,
+            scrape_cron,
+            ws_connections: std::sync::atomic::AtomicUsize::new(0),
+            max_ws_connections,
+        })
+    }
+
+    // Perform a TMDB GET request. A scrape permit is held until the body has been read,
+    // so at most SCRAPE_CONCURRENCY requests and their JSON bodies are in flight at once
+    async fn tmdb_get(&self, client: &reqwest::Client, auth_header: &str, url: &str) -> Result<Option<Value>> {
+        // Transient failures (network errors, 5xx, 429) are retried with backoff; the
+        // breaker check inside each attempt stops retrying once it opens
+        let result = with_retries(&format!("Request to {}", url), self.tmdb_retries + 1,
+                                  std::time::Duration::from_millis(500), std::time::Duration::from_secs(10),
+                                  || self.tmdb_get_once(client, auth_header, url)).await;
+
+        // A status that is still failing after retries is an unsuccessful response like
+        // any other, not an error
+        match result {
+            Err(e) if e.downcast_ref::<RetryableStatus>().is_some() => Ok(None),
+            other => other,
+        }
+    }
+
+    async fn tmdb_get_once(&self, client: &reqwest::Client, auth_header: &str, url: &str) -> Result<Option<Value>> {
+        if self.circuit_breaker.is_open() {
+            return Err(anyhow::anyhow!("TMDB circuit breaker is open, skipping {}", url));
+        }
+
+        let _permit = self.scrape_permits.acquire().await?;
+
+        let response = match client.get(url)
+            .header("Authorization", auth_header)
+            .header("accept", "application/json")
+            .send()
+            .await
+        {
+            Ok(response) => response,
+            Err(e) => {
+                self.circuit_breaker.record_failure(&e.to_string());
+                return Err(e.into());
+            }
+        };
+
+        let status = response.status();
+        if !status.is_success() {
+            println!("Request to {} failed: {}", url, status);
+            if status.is_server_error() || status == reqwest::StatusCode::TOO_MANY_REQUESTS {
+                self.circuit_breaker.record_failure(&format!("{} from {}", status, url));
+                return Err(RetryableStatus(status).into());
+            }
+            return Ok(None);
+        }
+
+        match response.json().await {
+            Ok(data) => Ok(Some(data)),
+            Err(e) => {
+                println!("Invalid JSON from {}: {}", url, e);
+                self.circuit_breaker.record_failure(&format!("invalid JSON from {}", url));
+                Err(e.into())
+            }
+        }
+    }
+
+    // Log and count a list response that parsed but doesn't look like a TMDB list, so
+    // a changed API shows up as an anomaly rather than a silently empty scrape
+    fn check_list_shape(&self, url: &str, data: &Value) -> bool {
+        let problem = match data.get("results") {
+            None => "missing results",
+            Some(results) if !results.is_array() => "results is not an array",
+            Some(_) => return true,
+        };
+
+        println!("Unexpected TMDB response shape from {}: {} (keys: {:?})", url, problem,
+                 data.as_object().map(|o| o.keys().collect::<Vec<_>>()).unwrap_or_default());
+        self.circuit_breaker.record_failure(&format!("{} from {}", problem, url));
+        false
+    }
+
+    async fn fetch_movies(&self, client: &reqwest::Client, auth_header: &str,
+                          tracker: &Mutex<ContentTracker>, url: String, in_theaters: bool) -> Result<ListPage> {
+        println!("Fetching movies from: {}", url);
+        let source = source_tag(&url);
+        let data = match self.tmdb_get(client, auth_header, &url).await? {
+            Some(data) => data,
+            None => return Ok(ListPage { failed: true, ..ListPage::default() }),
+        };
+        if !self.check_list_shape(&url, &data) {
+            return Ok(ListPage { failed: true, ..ListPage::default() });
+        }
+        let total_pages = data["total_pages"].as_u64().map(|p| p as u32);
+        let result_count = data["results"].as_array().map_or(0, |r| r.len());
+
+        // Skip movies we've already seen before spending requests enriching them
+        let new_movies: Vec<Value> = data["results"].as_array()
+            .map(|results| results.iter()
+                .filter(|movie| tracker.lock().record("movie", movie["id"].as_i64().unwrap_or_default(), &source))
+                .cloned()
+                .collect())
+            .unwrap_or_default();
+
+        let enriched = self.sample_for_enrichment(&new_movies);
+        let (source, enriched) = (source.as_str(), &enriched);
+        let movies = futures_util::stream::iter(new_movies)
+            .map(|movie| async move {
+                let movie_id = movie["id"].as_i64().unwrap_or_default();
+
+                let Enrichment { genres, spoken_languages, imdb_id, cast, keywords, status, providers, where_to_watch, region_providers, trailer_url, watch_links } = if enriched.contains(&movie_id) {
+                    self.get_enrichment(client, "movie", movie_id, auth_header).await
+                } else {
+                    self.list_enrichment(&movie)
+                };
+
+                Content {
+                    id: content_id("movie", movie_id),
+                    title: movie["title"].as_str().unwrap_or_default().to_string(),
+                    original_title: movie["original_title"].as_str().filter(|t| !t.is_empty()).map(String::from),
+                    localized_title: None,
+                    year: movie["release_date"]
+                        .as_str()
+                        .and_then(|d| d.split('-').next())
+                        .map(String::from),
+                    release_date: movie["release_date"].as_str().filter(|d| !d.is_empty()).map(String::from),
+                    rating: json_number(&movie["vote_average"]),
+                    quality_score: 0.0,
+                    popularity: json_number(&movie["popularity"]),
+                    vote_count: movie["vote_count"].as_i64(),
+                    fetched_at: Some(chrono::Utc::now()),
+                    sources: vec![source.to_string()],
+                    genre: genres,
+                    spoken_languages,
+                    description: movie["overview"].as_str().unwrap_or_default().to_string(),
+                    where_to_watch,
+                    in_theaters,
+                    tmdb_id: movie_id,
+                    imdb_id,
+                    cast,
+                    keywords,
+                    status,
+                    media_type: "movie".to_string(),
+                    providers,
+                    trailer_url,
+                    poster_url: self.poster_url(&movie["poster_path"]),
+                    watch_links,
+                    region_providers,
+                    debug: None,
+                }
+            })
+            .buffered(self.scrape_concurrency)
+            .collect()
+            .await;
+
+        Ok(ListPage {
+            content: movies,
+            total_pages,
+            result_count,
+            failed: false,
+        })
+    }
+
+    async fn fetch_tv_shows(&self, client: &reqwest::Client, auth_header: &str,
+                            tracker: &Mutex<ContentTracker>, url: String) -> Result<ListPage> {
+        println!("Fetching TV shows from: {}", url);
+        let source = source_tag(&url);
+        let data = match self.tmdb_get(client, auth_header, &url).await? {
+            Some(data) => data,
+            None => return Ok(ListPage { failed: true, ..ListPage::default() }),
+        };
+        if !self.check_list_shape(&url, &data) {
+            return Ok(ListPage { failed: true, ..ListPage::default() });
+        }
+        let total_pages = data["total_pages"].as_u64().map(|p| p as u32);
+        let result_count = data["results"].as_array().map_or(0, |r| r.len());
+
+        // Skip shows we've already seen before spending requests enriching them
+        let new_shows: Vec<Value> = data["results"].as_array()
+            .map(|results| results.iter()
+                .filter(|show| tracker.lock().record("tv", show["id"].as_i64().unwrap_or_default(), &source))
+                .cloned()
+                .collect())
+            .unwrap_or_default();
+
+        let enriched = self.sample_for_enrichment(&new_shows);
+        let (source, enriched) = (source.as_str(), &enriched);
+        let shows = futures_util::stream::iter(new_shows)
+            .map(|show| async move {
+                let show_id = show["id"].as_i64().unwrap_or_default();
+
+                let Enrichment { genres, spoken_languages, imdb_id, cast, keywords, status, providers, where_to_watch, region_providers, trailer_url, watch_links } = if enriched.contains(&show_id) {
+                    self.get_enrichment(client, "tv", show_id, auth_header).await
+                } else {
+                    self.list_enrichment(&show)
+                };
+
+                Content {
+                    id: content_id("tv", show_id),
+                    title: show["name"].as_str().unwrap_or_default().to_string(),
+                    original_title: show["original_name"].as_str().filter(|t| !t.is_empty()).map(String::from),
+                    localized_title: None,
+                    year: show["first_air_date"]
+                        .as_str()
+                        .and_then(|d| d.split('-').next())
+                        .map(String::from),
+                    release_date: show["first_air_date"].as_str().filter(|d| !d.is_empty()).map(String::from),
+                    rating: json_number(&show["vote_average"]),
+                    quality_score: 0.0,
+                    popularity: json_number(&show["popularity"]),
+                    vote_count: show["vote_count"].as_i64(),
+                    fetched_at: Some(chrono::Utc::now()),
+                    sources: vec![source.to_string()],
+                    genre: genres,
+                    spoken_languages,
+                    description: show["overview"].as_str().unwrap_or_default().to_string(),
+                    where_to_watch,
+                    in_theaters: false,
+                    tmdb_id: show_id,
+                    imdb_id,
+                    cast,
+                    keywords,
+                    status,
+                    media_type: "tv".to_string(),
+                    providers,
+                    trailer_url,
+                    poster_url: self.poster_url(&show["poster_path"]),
+                    watch_links,
+                    region_providers,
+                    debug: None,
+                }
+            })
+            .buffered(self.scrape_concurrency)
+            .collect()
+            .await;
+
+        Ok(ListPage {
+            content: shows,
+            total_pages,
+            result_count,
+            failed: false,
+        })
+    }
+
+    // Fetch up to max_pages pages of a TMDB list, stopping at the list's
+    // total_pages or at the first page with no results
+    async fn fetch_list(&self, client: &reqwest::Client, auth_header: &str, tracker: &Mutex<ContentTracker>,
+                        media_type: &str, url: String, in_theaters: bool, max_pages: u32) -> Result<(Vec<Content>, ListOutcome)> {
+        let mut content = Vec::new();
+        let mut last_page = max_pages;
+        let mut outcome = ListOutcome::Complete;
+
+        let mut page = 1;
+        while page <= last_page {
+            let page_url = format!("{}&page={}", url, page);
+            let fetched = if media_type == "movie" {
+                self.fetch_movies(client, auth_header, tracker, page_url, in_theaters).await?
+            } else {
+                self.fetch_tv_shows(client, auth_header, tracker, page_url).await?
+            };
+
+            if fetched.failed {
+                println!("Failed to fetch page {} of {}, stopping", page, url);
+                outcome = ListOutcome::Failed;
+                break;
+            }
+            if fetched.result_count == 0 {
+                println!("No results on page {} of {}, stopping", page, url);
+                if page == 1 {
+                    outcome = ListOutcome::Empty;
+                }
+                break;
+            }
+            if let Some(total_pages) = fetched.total_pages {
+                last_page = last_page.min(total_pages);
+            }
+
+            content.extend(fetched.content);
+            page += 1;
+        }
+
+        Ok((content, outcome))
+    }
+
+    // Whether the request carries the configured admin token as a bearer token
+    fn is_admin(&self, req: &HttpRequest) -> bool {
+        let provided = req.headers()
+            .get("Authorization")
+            .and_then(|h| h.to_str().ok())
+            .and_then(|h| h.strip_prefix("Bearer "));
+
+        matches!((&self.admin_token, provided), (Some(expected), Some(provided)) if expected == provided)
+    }
+
+    // Random source for shuffling, seeded with a fixed value in DETERMINISTIC mode
+    fn rng(&self) -> rand::rngs::StdRng {
+        use rand::SeedableRng;
+        if self.deterministic {
+            rand::rngs::StdRng::seed_from_u64(DETERMINISTIC_SEED)
+        } else {
+            rand::rngs::StdRng::from_entropy()
+        }
+    }
+
+    // Helper function to generate a unique key for each user's preference combination
+    // Keys are persisted with used_recommendations, so they're a stable hash of a
+    // canonical string: sorted genres separated by a unit separator, then the rating bits
+    fn generate_user_key(&self, prefs: &UserPreferences) -> String {
+        // Sort genres for consistent hashing
+        let mut genres = prefs.favorite_genres.clone();
+        genres.sort();
+
+        let canonical = format!("{}\u{1e}{:08x}", genres.join("\u{1f}"), prefs.minimum_rating.to_bits());
+        format!("user_{:x}", fnv1a(canonical.as_bytes()))
+    }
+
+    // Update the scrape_content method to get even more content
+    async fn scrape_content(&self) -> Result<Vec<Content>> {
+        let client = reqwest::Client::new();
+        let tracker = Mutex::new(ContentTracker::new());
+        let auth_header = format!("Bearer {}", self.tmdb_api_key);
+
+        if let Err(e) = self.refresh_provider_logos(&client, &auth_header).await {
+            println!("Error refreshing provider logos: {}", e);
+        }
+        if self.enrichment_sample < 1.0 {
+            if let Err(e) = self.refresh_genre_names(&client, &auth_header).await {
+                println!("Error refreshing genre names: {}", e);
+            }
+        }
+
+        // Fetch now playing first so those movies are tagged as in theaters
+        // before any other list marks them as already seen
+        let mut lists = Vec::new();
+        if self.scrapes("movie") {
+            lists.push(self.fetch_list(&client, &auth_header, &tracker, "movie",
+                                       format!("https://api.themoviedb.org/3/movie/now_playing?language={}&region={}", self.language, self.region),
+                                       true, MAX_LIST_PAGES).await?);
+        }
+
+        let sources = [
+            ("movie", "trending/movie/week"), // Trending Movies (Week)
+            ("movie", "trending/movie/day"),  // Trending Movies (Day)
+            ("movie", "movie/popular"),       // Popular Movies
+            ("movie", "movie/top_rated"),     // Top Rated Movies
+            ("tv", "trending/tv/week"),       // Trending TV Shows (Week)
+            ("tv", "trending/tv/day"),        // Trending TV Shows (Day)
+            ("tv", "tv/popular"),             // Popular TV Shows
+            ("tv", "tv/top_rated"),           // Top Rated TV Shows
+            ("tv", "tv/on_the_air"),          // Currently Airing TV Shows
+        ];
+
+        let mut requests: Vec<(String, String, u32)> = sources.iter()
+            .filter(|(media_type, _)| self.scrapes(media_type))
+            .map(|(media_type, path)| {
+                (media_type.to_string(), format!("https://api.themoviedb.org/3/{}?language={}", path, self.language), MAX_LIST_PAGES)
+            })
+            .collect();
+        for source in self.discover_sources.iter().filter(|s| self.scrapes(&s.media_type)) {
+            requests.push((source.media_type.clone(), self.discover_url(source)?, MAX_LIST_PAGES));
+        }
+        if let Some(budget) = self.scrape_request_budget {
+            match self.genre_discover_requests(&client, &auth_header, budget).await {
+                Ok(genre_requests) => requests.extend(genre_requests),
+                Err(e) => println!("Error planning genre discovery: {}", e),
+            }
+        }
+
+        let other_lists: Vec<(Vec<Content>, ListOutcome)> = futures_util::stream::iter(requests)
+            .map(|(media_type, url, max_pages)| {
+                let (client, auth_header, tracker) = (&client, &auth_header, &tracker);
+                async move {
+                    self.fetch_list(client, auth_header, tracker, &media_type, url, false, max_pages).await
+                }
+            })
+            .buffer_unordered(self.scrape_concurrency)
+            .try_collect()
+            .await?;
+        lists.extend(other_lists);
+
+        let count = |outcome| lists.iter().filter(|(_, o)| *o == outcome).count();
+        println!("Fetched {} lists: {} complete, {} empty, {} failed",
+                 lists.len(), count(ListOutcome::Complete), count(ListOutcome::Empty), count(ListOutcome::Failed));
+        let mut all_content: Vec<Content> = lists.into_iter().flat_map(|(content, _)| content).collect();
+
+        if let Some(min_items) = self.genre_backfill_min_items {
+            match self.backfill_genres(&client, &auth_header, &tracker, &all_content, min_items).await {
+                Ok(backfilled) => all_content.extend(backfilled),
+                Err(e) => println!("Error backfilling genres: {}", e),
+            }
+        }
+
+        // Tag each item with every list it appeared in, not just the first. Items from
+        // one scrape share a fetched_at, so the cache limit evicts them by popularity.
+        let tracker = tracker.into_inner();
+        let fetched_at = Some(chrono::Utc::now());
+        for content in &mut all_content {
+            content.sources = tracker.sources_for(&content.media_type, content.tmdb_id);
+            content.fetched_at = fetched_at;
+        }
+
+        let pruned = self.prune_expired(&mut all_content);
+        if pruned > 0 {
+            println!("Pruned {} items older than the maximum item age", pruned);
+        }
+
+        self.collapse_near_duplicates(&mut all_content);
+
+        // Keep the catalog in a stable order so the saved blob only changes when the
+        // content does. Variety comes from the shuffle in filter_recommendations.
+        all_content.sort_by(|a, b| (&a.media_type, a.tmdb_id).cmp(&(&b.media_type, b.tmdb_id)));
+
+        println!("Scraped {} unique items total", all_content.len());
+        Ok(all_content)
+    }
+
+    // Discover-by-genre lists for the genres favored in saved profiles, with
+    // SCRAPE_REQUEST_BUDGET list pages split between them by split_request_budget.
+    // A genre whose list has fewer pages than its share simply uses fewer requests.
+    async fn genre_discover_requests(&self, client: &reqwest::Client, auth_header: &str, budget: usize)
+                                     -> Result<Vec<(String, String, u32)>> {
+        let genres = self.genre_ids(client, auth_header, &self.favored_genres()).await?;
+        println!("Discovering {} active genres with a budget of {} pages", genres.len(), budget);
+        Ok(self.genre_discover_urls(genres, budget))
+    }
+
+    // Every genre named in a saved profile's favorites
+    fn favored_genres(&self) -> HashSet<String> {
+        self.profiles.read().values()
+            .flat_map(|prefs| prefs.favorite_genres.iter().cloned())
+            .collect()
+    }
+
+    // (media type, TMDB genre id) for each named genre, for every scraped media type
+    // that has it. Genre ids differ between movies and tv, so they're looked up per type.
+    async fn genre_ids(&self, client: &reqwest::Client, auth_header: &str, names: &HashSet<String>)
+                       -> Result<Vec<(String, i64)>> {
+        let mut genres = Vec::new();
+        for media_type in ["movie", "tv"].iter().filter(|t| self.scrapes(t)) {
+            let url = format!(
+                "https://api.themoviedb.org/3/genre/{}/list?language={}",
+                media_type, self.language
+            );
+            let data = match self.tmdb_get(client, auth_header, &url).await? {
+                Some(data) => data,
+                None => return Err(anyhow::anyhow!("Genre list request was unsuccessful")),
+            };
+
+            for genre in data["genres"].as_array().into_iter().flatten() {
+                if let (Some(id), Some(name)) = (genre["id"].as_i64(), genre["name"].as_str()) {
+                    if names.contains(name) {
+                        genres.push((media_type.to_string(), id));
+                    }
+                }
+            }
+        }
+        genres.sort();
+        Ok(genres)
+    }
+
+    // Discover list requests for the genres, sharing a budget of pages between them
+    fn genre_discover_urls(&self, genres: Vec<(String, i64)>, budget: usize) -> Vec<(String, String, u32)> {
+        let pages = split_request_budget(budget, genres.len(), self.genre_discover_depth);
+
+        genres.into_iter().zip(pages)
+            .filter(|(_, pages)| *pages > 0)
+            .map(|((media_type, id), pages)| {
+                let url = format!(
+                    "https://api.themoviedb.org/3/discover/{}?language={}&sort_by=popularity.desc&with_genres={}",
+                    media_type, self.language, id
+                );
+                (media_type, url, pages)
+            })
+            .collect()
+    }
+
+    // Favored genres with fewer than min_items items in the scraped content
+    fn under_covered_genres(&self, content: &[Content], min_items: usize) -> HashSet<String> {
+        let mut counts: HashMap<&str, usize> = HashMap::new();
+        for item in content {
+            for genre in &item.genre {
+                *counts.entry(genre.as_str()).or_insert(0) += 1;
+            }
+        }
+
+        self.favored_genres().into_iter()
+            .filter(|genre| counts.get(genre.as_str()).copied().unwrap_or(0) < min_items)
+            .collect()
+    }
+
+    // Top up favored genres left with too few items by the main scrape, spending at
+    // most GENRE_BACKFILL_BUDGET list pages. Items already scraped are skipped by the tracker.
+    async fn backfill_genres(&self, client: &reqwest::Client, auth_header: &str, tracker: &Mutex<ContentTracker>,
+                             content: &[Content], min_items: usize) -> Result<Vec<Content>> {
+        let under_covered = self.under_covered_genres(content, min_items);
+        if under_covered.is_empty() {
+            return Ok(Vec::new());
+        }
+
+        let mut names: Vec<&String> = under_covered.iter().collect();
+        names.sort();
+        println!("Genres with fewer than {} items: {:?}, backfilling", min_items, names);
+
+        let genres = self.genre_ids(client, auth_header, &under_covered).await?;
+        let requests = self.genre_discover_urls(genres, self.genre_backfill_budget);
+
+        let lists: Vec<(Vec<Content>, ListOutcome)> = futures_util::stream::iter(requests)
+            .map(|(media_type, url, max_pages)| async move {
+                self.fetch_list(client, auth_header, tracker, &media_type, url, false, max_pages).await
+            })
+            .buffer_unordered(self.scrape_concurrency)
+            .try_collect()
+            .await?;
+
+        let backfilled: Vec<Content> = lists.into_iter().flat_map(|(content, _)| content).collect();
+        println!("Backfilled {} items for under-covered genres", backfilled.len());
+        Ok(backfilled)
+    }
+
+    fn scrapes(&self, media_type: &str) -> bool {
+        self.scrape_media_types.contains(media_type)
+    }
+
+    fn discover_url(&self, source: &DiscoverSource) -> Result<String> {
+        let mut params = vec![("language".to_string(), self.language.clone())];
+        params.extend(source.params.iter().map(|(name, value)| {
+            let value = match value {
+                Value::String(s) => s.clone(),
+                other => other.to_string(),
+            };
+            (name.clone(), value)
+        }));
+        // Provider filters only apply within a region
+        if source.params.keys().any(|name| name.starts_with("with_watch_")) {
+            params.push(("watch_region".to_string(), self.region.clone()));
+        }
+
+        let url = Url::parse_with_params(
+            &format!("https://api.themoviedb.org/3/discover/{}", source.media_type),
+            &params,
+        )?;
+        Ok(url.to_string())
+    }
+
+    // Collapse distinct TMDB ids that are effectively the same title (e.g. regional cuts):
+    // same media type and normalized title with near-identical descriptions. The
+    // higher rated item is kept.
+    fn collapse_near_duplicates(&self, content: &mut Vec<Content>) {
+        let threshold = match self.dedup_similarity {
+            Some(threshold) => threshold,
+            None => return,
+        };
+
+        // Highest rated first, so the item kept from each collapsed pair comes earlier
+        content.sort_by(|a, b| b.rating.unwrap_or(0.0).total_cmp(&a.rating.unwrap_or(0.0)));
+
+        let mut kept: Vec<Content> = Vec::with_capacity(content.len());
+        let mut by_title: HashMap<(String, String), Vec<usize>> = HashMap::new();
+
+        for item in content.drain(..) {
+            let key = (item.media_type.clone(), normalize_title(&item.title));
+            let tokens = token_set(&item.description);
+            let candidates = by_title.entry(key).or_default();
+
+            let duplicate_of = candidates.iter()
+                .find(|&&i| jaccard(&tokens, &token_set(&kept[i].description)) >= threshold);
+
+            match duplicate_of {
+                Some(&i) => println!("Collapsed near-duplicate '{}' ({}) into {}",
+                                     item.title, item.tmdb_id, kept[i].tmdb_id),
+                None => {
+                    candidates.push(kept.len());
+                    kept.push(item);
+                },
+            }
+        }
+
+        *content = kept;
+    }
+
+    // Drop items whose release date is more than MAX_ITEM_AGE_DAYS ago, returning how
+    // many were removed. Undated items are kept, as are top rated ones if configured.
+    fn prune_expired(&self, content: &mut Vec<Content>) -> usize {
+        let max_age_days = match self.max_item_age_days {
+            Some(days) => days,
+            None => return 0,
+        };
+        let cutoff = chrono::Utc::now().date_naive() - chrono::Duration::days(max_age_days);
+
+        let before = content.len();
+        content.retain(|c| {
+            if self.keep_top_rated && c.sources.iter().any(|s| s.ends_with("/top_rated")) {
+                return true;
+            }
+            c.release_date.as_deref()
+                .and_then(|d| chrono::NaiveDate::parse_from_str(d, "%Y-%m-%d").ok())
+                .map_or(true, |released| released >= cutoff)
+        });
+        before - content.len()
+    }
+
+    // Details for a title with its watch providers, external ids, credits, keywords and
+    // (when trailers are on) videos appended, so enrichment costs one TMDB request per item
+    async fn get_details(&self, client: &reqwest::Client, media_type: &str, id: i64, auth_header: &str) -> Result<Option<Value>> {
+        let append = if self.fetch_trailers {
+            "watch/providers,external_ids,credits,keywords,videos"
+        } else {
+            "watch/providers,external_ids,credits,keywords"
+        };
+        let url = format!(
+            "https://api.themoviedb.org/3/{}/{}?language={}&append_to_response={}",
+            media_type, id, self.language, append
+        );
+
+        self.tmdb_get(client, auth_header, &url).await
+    }
+
+    // Full URL for a TMDB poster_path, falling back to DEFAULT_POSTER_URL when it's missing
+    fn poster_url(&self, poster_path: &Value) -> Option<String> {
+        poster_path.as_str()
+            .filter(|path| !path.is_empty())
+            .map(|path| format!("{}{}", TMDB_IMAGE_BASE, path))
+            .or_else(|| self.default_poster_url.clone())
+    }
+
+    // Genres, providers and trailer for a list item, empty if the details request fails
+    async fn get_enrichment(&self, client: &reqwest::Client, media_type: &str, id: i64, auth_header: &str) -> Enrichment {
+        match self.get_details(client, media_type, id, auth_header).await {
+            Ok(Some(data)) => self.parse_enrichment(&data),
+            Ok(None) => Enrichment::default(),
+            Err(e) => {
+                println!("Error fetching details for {} {}: {}", media_type, id, e);
+                Enrichment::default()
+            }
+        }
+    }
+
+    // Ids of the list items to fully enrich: all of them, or with ENRICHMENT_SAMPLE set
+    // that fraction of the page, most popular (then highest rated) first
+    fn sample_for_enrichment(&self, items: &[Value]) -> HashSet<i64> {
+        let mut items: Vec<&Value> = items.iter().collect();
+        if self.enrichment_sample < 1.0 {
+            let keep = (items.len() as f32 * self.enrichment_sample).ceil() as usize;
+            items.sort_by(|a, b| {
+                let key = |v: &Value| (v["popularity"].as_f64().unwrap_or(0.0), v["vote_average"].as_f64().unwrap_or(0.0));
+                key(b).partial_cmp(&key(a)).unwrap_or(std::cmp::Ordering::Equal)
+            });
+            items.truncate(keep);
+        }
+
+        items.iter().filter_map(|v| v["id"].as_i64()).collect()
+    }
+
+    // Enrichment for an item left out of sampling, using only the list response. Genres
+    // come from its genre_ids; providers and trailer stay empty.
+    fn list_enrichment(&self, item: &Value) -> Enrichment {
+        let genre_names = self.genre_names.read();
+        let mut seen = HashSet::new();
+        let genres = item["genre_ids"].as_array()
+            .map(|ids| ids.iter()
+                .filter_map(|id| id.as_i64().and_then(|id| genre_names.get(&id).cloned()))
+                .filter(|name| seen.insert(name.clone()))
+                .collect())
+            .unwrap_or_default();
+
+        Enrichment {
+            genres,
+            ..Enrichment::default()
+        }
+    }
+
+    // Refresh the genre id to name mapping used for unenriched items
+    async fn refresh_genre_names(&self, client: &reqwest::Client, auth_header: &str) -> Result<()> {
+        let mut names = HashMap::new();
+        for media_type in ["movie", "tv"] {
+            let url = format!(
+                "https://api.themoviedb.org/3/genre/{}/list?language={}",
+                media_type, self.language
+            );
+            let data = match self.tmdb_get(client, auth_header, &url).await? {
+                Some(data) => data,
+                None => return Err(anyhow::anyhow!("Genre list request was unsuccessful")),
+            };
+
+            for genre in data["genres"].as_array().into_iter().flatten() {
+                if let (Some(id), Some(name)) = (genre["id"].as_i64(), genre["name"].as_str()) {
+                    names.insert(id, name.to_string());
+                }
+            }
+        }
+
+        println!("Loaded {} genre names", names.len());
+        *self.genre_names.write() = names;
+        Ok(())
+    }
+
+    fn parse_enrichment(&self, data: &Value) -> Enrichment {
+        // A genre can be listed more than once, which would count it twice in
+        // scoring and the /genres tally
+        let mut seen = HashSet::new();
+        let genres = data["genres"].as_array()
+            .map(|genres| genres.iter()
+                .filter_map(|g| g["name"].as_str().map(String::from))
+                .filter(|name| seen.insert(name.clone()))
+                .collect())
+            .unwrap_or_default();
+
+        let watch_providers = &data["watch/providers"];
+        let providers = self.parse_watch_providers(watch_providers, &self.region);
+
+        // Streaming names for every configured region, the primary from the
+        // providers already parsed
+        let mut regions = vec![self.region.clone()];
+        regions.extend(self.provider_regions.iter().cloned());
+        let region_providers: std::collections::BTreeMap<String, Vec<String>> = regions.iter()
+            .map(|region| {
+                let names = if *region == self.region {
+                    streaming_provider_names(&providers)
+                } else {
+                    streaming_provider_names(&self.parse_watch_providers(watch_providers, region))
+                };
+                (region.clone(), names)
+            })
+            .filter(|(_, names)| !names.is_empty())
+            .collect();
+
+        let spoken_languages = data["spoken_languages"].as_array()
+            .map(|languages| languages.iter()
+                .filter_map(|l| l["iso_639_1"].as_str())
+                .filter(|code| !code.is_empty())
+                .map(String::from)
+                .collect())
+            .unwrap_or_default();
+
+        // Cast comes in billing order
+        let cast = data["credits"]["cast"].as_array()
+            .map(|cast| cast.iter()
+                .filter_map(|c| c["name"].as_str().map(String::from))
+                .take(MAX_CAST)
+                .collect())
+            .unwrap_or_default();
+
+        // Movies list keywords under "keywords", TV under "results"
+        let keywords = data["keywords"]["keywords"].as_array()
+            .or_else(|| data["keywords"]["results"].as_array())
+            .map(|keywords| keywords.iter()
+                .filter_map(|k| k["name"].as_str().map(String::from))
+                .collect())
+            .unwrap_or_default();
+
+        // Movie details carry imdb_id directly; TV only has it under external_ids
+        let imdb_id = data["imdb_id"].as_str()
+            .or_else(|| data["external_ids"]["imdb_id"].as_str())
+            .filter(|id| !id.is_empty())
+            .map(String::from);
+
+        Enrichment {
+            genres,
+            spoken_languages,
+            imdb_id,
+            cast,
+            keywords,
+            status: data["status"].as_str().filter(|s| !s.is_empty()).map(String::from),
+            where_to_watch: flatten_providers(&region_providers, &regions, self.provider_flattening),
+            region_providers,
+            providers,
+            trailer_url: if self.fetch_trailers { parse_trailer(&data["videos"]) } else { None },
+            watch_links: parse_watch_links(&data["watch/providers"]),
+        }
+    }
+
+    // Refresh the provider id to logo mapping from TMDB's provider list for our region.
+    // On failure the previous mapping is kept.
+    async fn refresh_provider_logos(&self, client: &reqwest::Client, auth_header: &str) -> Result<()> {
+        let url = format!(
+            "https://api.themoviedb.org/3/watch/providers/movie?language={}&watch_region={}",
+            self.language, self.region
+        );
+
+        let data = match self.tmdb_get(client, auth_header, &url).await? {
+            Some(data) => data,
+            None => return Err(anyhow::anyhow!("Provider list request was unsuccessful")),
+        };
+
+        let logos: HashMap<i64, String> = data["results"].as_array()
+            .map(|results| results.iter()
+                .filter_map(|p| {
+                    let id = p["provider_id"].as_i64()?;
+                    let path = p["logo_path"].as_str()?;
+                    Some((id, format!("{}{}", TMDB_IMAGE_BASE, path)))
+                })
+                .collect())
+            .unwrap_or_default();
+
+        println!("Loaded logos for {} providers", logos.len());
+        *self.provider_logos.write() = logos;
+        Ok(())
+    }
+
+    // Providers for a region from an appended watch/providers response
+    fn parse_watch_providers(&self, data: &Value, region: &str) -> Vec<Provider> {
+        let mut providers = Vec::new();
+
+        if let Some(region_data) = data.get("results").and_then(|r| r.get(region)) {
+            // TMDB only gives one watch page link per region, shared by all its providers
+            let link = region_data.get("link").and_then(|l| l.as_str()).map(String::from);
+
+            for provider_type in ["flatrate", "free", "rent", "buy"].iter() {
+                if let Some(provider_list) = region_data.get(provider_type).and_then(|p| p.as_array()) {
+                    for provider in provider_list {
+                        if let Some(name) = provider.get("provider_name").and_then(|n| n.as_str()) {
+                            // Fall back to the provider list's logo when the item omits it
+                            let logo_path = provider.get("logo_path").and_then(|l| l.as_str())
+                                .map(|path| format!("{}{}", TMDB_IMAGE_BASE, path))
+                                .or_else(|| provider.get("provider_id").and_then(|id| id.as_i64())
+                                    .and_then(|id| self.provider_logos.read().get(&id).cloned()));
+
+                            providers.push(Provider {
+                                name: name.to_string(),
+                                provider_type: provider_type.to_string(),
+                                provider_link: link.clone(),
+                                display_priority: provider.get("display_priority").and_then(|p| p.as_i64()),
+                                logo_path,
+                            });
+                        }
+                    }
+                }
+            }
+        }
+
+        // Most relevant services first (or last), with unranked providers at the end
+        providers.sort_by_key(|p| match p.display_priority {
+            Some(priority) if self.provider_priority_descending => (0, -priority),
+            Some(priority) => (0, priority),
+            None => (1, 0),
+        });
+
+        providers
+    }
+
+    // Fetch a single title directly from TMDB, used when it isn't in the cache
+    async fn fetch_details(&self, client: &reqwest::Client, auth_header: &str,
+                           media_type: &str, id: i64) -> Result<Option<Content>> {
+        let data = match self.get_details(client, media_type, id, auth_header).await? {
+            Some(data) => data,
+            None => {
+                println!("No details found for {} {}", media_type, id);
+                return Ok(None);
+            }
+        };
+
+        let (title_field, original_title_field, date_field) = if media_type == "movie" {
+            ("title", "original_title", "release_date")
+        } else {
+            ("name", "original_name", "first_air_date")
+        };
+
+        let Enrichment { genres, spoken_languages, imdb_id, cast, keywords, status, providers, where_to_watch, region_providers, trailer_url, watch_links } = self.parse_enrichment(&data);
+
+        Ok(Some(Content {
+            id: content_id(media_type, id),
+            title: data[title_field].as_str().unwrap_or_default().to_string(),
+            original_title: data[original_title_field].as_str().filter(|t| !t.is_empty()).map(String::from),
+            localized_title: None,
+            year: data[date_field]
+                .as_str()
+                .and_then(|d| d.split('-').next())
+                .map(String::from),
+            release_date: data[date_field].as_str().filter(|d| !d.is_empty()).map(String::from),
+            rating: json_number(&data["vote_average"]),
+            quality_score: 0.0,
+            popularity: json_number(&data["popularity"]),
+            vote_count: data["vote_count"].as_i64(),
+            fetched_at: Some(chrono::Utc::now()),
+            sources: Vec::new(),
+            genre: genres,
+            spoken_languages,
+            description: data["overview"].as_str().unwrap_or_default().to_string(),
+            where_to_watch,
+            in_theaters: false,
+            tmdb_id: id,
+            imdb_id,
+            cast,
+            keywords,
+            status,
+            media_type: media_type.to_string(),
+            providers,
+            trailer_url,
+            poster_url: self.poster_url(&data["poster_path"]),
+            watch_links,
+            region_providers,
+            debug: None,
+        }))
+    }
+
+    // Run one page of a live TMDB list (search, similar titles) through the usual enrichment.
+    // Titles and descriptions follow the list URL's language; genres stay in the server language
+    // so they line up with the catalog's genre names.
+    async fn fetch_live_list(&self, media_type: &str, url: &str) -> Result<Vec<Content>> {
+        let client = reqwest::Client::new();
+        let auth_header = format!("Bearer {}", self.tmdb_api_key);
+        let tracker = Mutex::new(ContentTracker::new());
+
+        let page = if media_type == "movie" {
+            self.fetch_movies(&client, &auth_header, &tracker, url.to_string(), false).await?
+        } else {
+            self.fetch_tv_shows(&client, &auth_header, &tracker, url.to_string()).await?
+        };
+
+        let mut content = page.content;
+        self.score_quality(&mut content);
+        Ok(content)
+    }
+
+    // Quality scores for items fetched outside a refresh, against the catalog's mean
+    fn score_quality(&self, content: &mut [Content]) {
+        let mean = self.cache.read().rating_mean;
+        for item in content {
+            item.quality_score = weighted_rating(item, mean, self.quality_min_votes);
+        }
+    }
+
+    // Trending titles from the cache, most popular first, without any preference
+    // filtering. Falls back to TMDB directly if the cache predates source tags.
+    async fn trending(&self, media_type: &str, window: &str) -> Result<Vec<Content>> {
+        let source = format!("trending/{}/{}", media_type, window);
+
+        let (mut trending, tagged) = {
+            let cache = self.cache.read();
+            let content = cache.data.get("latest");
+            let trending: Vec<Content> = content.into_iter().flatten()
+                .filter(|c| c.sources.contains(&source))
+                .cloned()
+                .collect();
+            let tagged = content.map_or(false, |c| c.iter().any(|c| !c.sources.is_empty()));
+            (trending, tagged)
+        };
+
+        if !tagged {
+            println!("Cache has no source tags, fetching {} live", source);
+            let url = format!("https://api.themoviedb.org/3/{}?language={}", source, self.language);
+            return self.fetch_live_list(media_type, &url).await;
+        }
+
+        trending.sort_by(|a, b| b.popularity.unwrap_or(0.0).total_cmp(&a.popularity.unwrap_or(0.0)));
+        Ok(trending)
+    }
+
+    async fn search(&self, media_type: &str, query: &str, language: &str, region: Option<&str>) -> Result<Vec<Content>> {
+        let mut params = vec![("query", query), ("language", language), ("page", "1")];
+        if let Some(region) = region {
+            params.push(("region", region));
+        }
+        let url = Url::parse_with_params(
+            &format!("https://api.themoviedb.org/3/search/{}", media_type),
+            &params,
+        )?;
+
+        self.fetch_live_list(media_type, url.as_str()).await
+    }
+
+    async fn similar(&self, media_type: &str, id: i64, language: &str) -> Result<Vec<Content>> {
+        let url = Url::parse_with_params(
+            &format!("https://api.themoviedb.org/3/{}/{}/similar", media_type, id),
+            &[("language", language), ("page", "1")],
+        )?;
+
+        self.fetch_live_list(media_type, url.as_str()).await
+    }
+
+    // TMDB's own recommendations for a title, which come from viewing patterns rather
+    // than the shared keywords and genres behind /similar. Kept in memory briefly.
+    async fn tmdb_recommendations(&self, media_type: &str, id: i64, language: &str) -> Result<Vec<Content>> {
+        let key = (media_type.to_string(), id, language.to_string());
+        if let Some((fetched, content)) = self.tmdb_recommendations_cache.lock().get(&key) {
+            if fetched.elapsed() < TMDB_RECOMMENDATIONS_TTL {
+                return Ok(content.clone());
+            }
+        }
+
+        let url = Url::parse_with_params(
+            &format!("https://api.themoviedb.org/3/{}/{}/recommendations", media_type, id),
+            &[("language", language), ("page", "1")],
+        )?;
+        let content = self.fetch_live_list(media_type, url.as_str()).await?;
+
+        let mut cache = self.tmdb_recommendations_cache.lock();
+        cache.retain(|_, (fetched, _)| fetched.elapsed() < TMDB_RECOMMENDATIONS_TTL);
+        cache.insert(key, (std::time::Instant::now(), content.clone()));
+        Ok(content)
+    }
+
+    // "Because you saved X" rows: TMDB recommendations for the user's watchlist items
+    // that are in the catalog, leaving out anything already saved. Watchlists don't
+    // record when items were added, so rows go by media type then tmdb_id.
+    async fn watchlist_recommendations(&self, user_id: &str, language: &str) -> Result<Vec<(Content, Vec<Content>)>> {
+        let (saved, saved_ids) = {
+            let cache = self.cache.read();
+            let ids = cache.watchlists.get(user_id).cloned().unwrap_or_default();
+            let mut saved: Vec<Content> = ids.iter()
+                .filter_map(|id| cache.by_id.get(id).and_then(|&i| cache.data.get("latest")?.get(i)).cloned())
+                .collect();
+            saved.sort_by(|a, b| (&a.media_type, a.tmdb_id).cmp(&(&b.media_type, b.tmdb_id)));
+            saved.truncate(MAX_BECAUSE_ROWS);
+            (saved, ids)
+        };
+
+        let mut rows = Vec::new();
+        for item in saved {
+            let mut recommended = self.tmdb_recommendations(&item.media_type, item.tmdb_id, language).await?;
+            recommended.retain(|c| !saved_ids.contains(&c.id));
+            rows.push((item, recommended));
+        }
+        Ok(rows)
+    }
+
+    // Turn a list of (media_type, tmdb_id) pairs into full content, preserving input order
+    async fn hydrate(&self, ids: &[HydrateId]) -> Result<Vec<Content>> {
+        let cached: HashMap<(String, i64), Content> = {
+            let cache = self.cache.read();
+            ids.iter()
+                .filter_map(|id| cache.find(&id.media_type, id.tmdb_id)
+                    .map(|c| ((id.media_type.clone(), id.tmdb_id), c.clone())))
+                .collect()
+        };
+
+        let client = reqwest::Client::new();
+        let auth_header = format!("Bearer {}", self.tmdb_api_key);
+        let mut live_fetches = 0;
+        let mut hydrated = Vec::new();
+
+        for id in ids {
+            if id.media_type != "movie" && id.media_type != "tv" {
+                println!("Skipping unknown media type: {}", id.media_type);
+                continue;
+            }
+
+            if let Some(content) = cached.get(&(id.media_type.clone(), id.tmdb_id)) {
+                hydrated.push(content.clone());
+                continue;
+            }
+
+            if live_fetches >= MAX_HYDRATE_FETCHES {
+                println!("Live fetch limit reached, skipping {} {}", id.media_type, id.tmdb_id);
+                continue;
+            }
+            live_fetches += 1;
+
+            if let Some(mut content) = self.fetch_details(&client, &auth_header, &id.media_type, id.tmdb_id).await? {
+                self.score_quality(std::slice::from_mut(&mut content));
+                hydrated.push(content);
+            }
+        }
+
+        println!("Hydrated {} of {} ids ({} fetched live)", hydrated.len(), ids.len(), live_fetches);
+        Ok(hydrated)
+    }
+
+    // async fn update_content(&self) -> Result<()> {
+    //     {
     //         let cache = self.cache.read();
     //         if !cache.needs_update() {
     //             return Ok(());
@@ -560,395 +3376,3905 @@ impl ContentService {
     //     Ok(())
     // }
 
-    // async fn get_recommendations(&self, prefs: &UserPreferences) -> Result<Vec<Content>> {
-    //     // First try cache
-    //     {
-    //         let cache = self.cache.read();
-    //         if let Some(content) = cache.data.get("latest") {
-    //             println!("Returning recommendations from cache");
-    //             return Ok(content.iter()
-    //                 .filter(|c| {
-    //                     c.rating.unwrap_or(0.0) >= prefs.minimum_rating &&
-    //                         c.genre.iter().any(|g| prefs.favorite_genres.contains(g))
-    //                 })
-    //                 .cloned()
-    //                 .collect());
-    //         }
-    //     }
-    //
-    //     // If not in cache, try to load from blob
-    //     let blob_name = "latest.json.gz";
-    //     let blob_client = self.blob_client.blob_client(blob_name);
-    //
-    //     println!("Checking if blob exists...");
-    //     match blob_client.get_properties().await {
-    //         Ok(props) => {
-    //             println!("Found existing blob, downloading content...");
-    //             let mut stream = blob_client.get().into_stream();
-    //             let mut data = Vec::new();
-    //
-    //             use futures_util::StreamExt;
-    //             while let Some(chunk) = stream.next().await {
-    //                 match chunk {
-    //                     Ok(chunk) => {
-    //                         let bytes = chunk.data.collect().await?;
-    //                         data.extend(bytes);
-    //                     },
-    //                     Err(e) => {
-    //                         println!("Error reading chunk: {}", e);
-    //                         break;
-    //                     }
-    //                 }
-    //             }
-    //
-    //             if !data.is_empty() {
-    //                 println!("Downloaded {} bytes", data.len());
-    //                 match self.process_blob_data(&data) {
-    //                     Ok(content) => {
-    //                         println!("Successfully processed blob data with {} items", content.len());
-    //                         return Ok(content.into_iter()
-    //                             .filter(|c| {
-    //                                 c.rating.unwrap_or(0.0) >= prefs.minimum_rating &&
-    //                                     c.genre.iter().any(|g| prefs.favorite_genres.contains(g))
-    //                             })
-    //                             .collect());
-    //                     },
-    //                     Err(e) => {
-    //                         println!("Error processing blob data: {}, falling back to fresh fetch", e);
-    //                     }
-    //                 }
-    //             } else {
-    //                 println!("Downloaded data was empty, falling back to fresh fetch");
-    //             }
-    //         },
-    //         Err(e) => {
-    //             if e.to_string().contains("404") {
-    //                 println!("No existing blob found (404), will fetch fresh content");
-    //             } else {
-    //                 println!("Error checking blob properties: {}, falling back to fresh fetch", e);
-    //             }
-    //         }
-    //     }
-    //
-    //     // If we couldn't load from blob storage, fetch fresh content
-    //     println!("Starting fresh content fetch...");
-    //     match self.scrape_content().await {
-    //         Ok(content) => {
-    //             println!("Successfully fetched {} items, saving to blob storage...", content.len());
-    //
-    //             // Save the content to blob storage
-    //             if let Err(e) = self.save_to_blob(&content).await {
-    //                 println!("Warning: Failed to save to blob storage: {}", e);
-    //             } else {
-    //                 println!("Successfully saved to blob storage");
-    //             }
-    //
-    //             // Update cache with fresh content
-    //             let mut cache = self.cache.write();
-    //             cache.data.insert("latest".to_string(), content.clone());
-    //             cache.last_updated = chrono::Utc::now();
-    //
-    //             Ok(content.into_iter()
-    //                 .filter(|c| {
-    //                     c.rating.unwrap_or(0.0) >= prefs.minimum_rating &&
-    //                         c.genre.iter().any(|g| prefs.favorite_genres.contains(g))
-    //                 })
-    //                 .collect())
-    //         },
-    //         Err(e) => {
-    //             eprintln!("Failed to fetch fresh content: {}", e);
-    //             Ok(vec![])
-    //         }
-    //     }
-    // }
+    // async fn get_recommendations(&self, prefs: &UserPreferences) -> Result<Vec<Content>> {
+    //     // First try cache
+    //     {
+    //         let cache = self.cache.read();
+    //         if let Some(content) = cache.data.get("latest") {
+    //             println!("Returning recommendations from cache");
+    //             return Ok(content.iter()
+    //                 .filter(|c| {
+    //                     c.rating.unwrap_or(0.0) >= prefs.minimum_rating &&
+    //                         c.genre.iter().any(|g| prefs.favorite_genres.contains(g))
+    //                 })
+    //                 .cloned()
+    //                 .collect());
+    //         }
+    //     }
+    //
+    //     // If not in cache, try to load from blob
+    //     let blob_name = "latest.json.gz";
+    //     let blob_client = self.blob_client.blob_client(blob_name);
+    //
+    //     println!("Checking if blob exists...");
+    //     match blob_client.get_properties().await {
+    //         Ok(props) => {
+    //             println!("Found existing blob, downloading content...");
+    //             let mut stream = blob_client.get().into_stream();
+    //             let mut data = Vec::new();
+    //
+    //             use futures_util::StreamExt;
+    //             while let Some(chunk) = stream.next().await {
+    //                 match chunk {
+    //                     Ok(chunk) => {
+    //                         let bytes = chunk.data.collect().await?;
+    //                         data.extend(bytes);
+    //                     },
+    //                     Err(e) => {
+    //                         println!("Error reading chunk: {}", e);
+    //                         break;
+    //                     }
+    //                 }
+    //             }
+    //
+    //             if !data.is_empty() {
+    //                 println!("Downloaded {} bytes", data.len());
+    //                 match self.process_blob_data(&data) {
+    //                     Ok(content) => {
+    //                         println!("Successfully processed blob data with {} items", content.len());
+    //                         return Ok(content.into_iter()
+    //                             .filter(|c| {
+    //                                 c.rating.unwrap_or(0.0) >= prefs.minimum_rating &&
+    //                                     c.genre.iter().any(|g| prefs.favorite_genres.contains(g))
+    //                             })
+    //                             .collect());
+    //                     },
+    //                     Err(e) => {
+    //                         println!("Error processing blob data: {}, falling back to fresh fetch", e);
+    //                     }
+    //                 }
+    //             } else {
+    //                 println!("Downloaded data was empty, falling back to fresh fetch");
+    //             }
+    //         },
+    //         Err(e) => {
+    //             if e.to_string().contains("404") {
+    //                 println!("No existing blob found (404), will fetch fresh content");
+    //             } else {
+    //                 println!("Error checking blob properties: {}, falling back to fresh fetch", e);
+    //             }
+    //         }
+    //     }
+    //
+    //     // If we couldn't load from blob storage, fetch fresh content
+    //     println!("Starting fresh content fetch...");
+    //     match self.scrape_content().await {
+    //         Ok(content) => {
+    //             println!("Successfully fetched {} items, saving to blob storage...", content.len());
+    //
+    //             // Save the content to blob storage
+    //             if let Err(e) = self.save_to_blob(&content).await {
+    //                 println!("Warning: Failed to save to blob storage: {}", e);
+    //             } else {
+    //                 println!("Successfully saved to blob storage");
+    //             }
+    //
+    //             // Update cache with fresh content
+    //             let mut cache = self.cache.write();
+    //             cache.data.insert("latest".to_string(), content.clone());
+    //             cache.last_updated = chrono::Utc::now();
+    //
+    //             Ok(content.into_iter()
+    //                 .filter(|c| {
+    //                     c.rating.unwrap_or(0.0) >= prefs.minimum_rating &&
+    //                         c.genre.iter().any(|g| prefs.favorite_genres.contains(g))
+    //                 })
+    //                 .collect())
+    //         },
+    //         Err(e) => {
+    //             eprintln!("Failed to fetch fresh content: {}", e);
+    //             Ok(vec![])
+    //         }
+    //     }
+    // }
+
+    async fn update_content(&self) -> Result<()> {
+        // Check if update is needed
+        {
+            let cache = self.cache.read();
+            if !cache.needs_update() {
+                return Ok(());
+            }
+        }
+
+        self.refresh_content().await?;
+        Ok(())
+    }
+
+    // Ids TMDB reports as changed for a media type between two times. The range is
+    // split into windows of at most TMDB_CHANGES_MAX_DAYS, each paged through.
+    async fn changed_ids(&self, client: &reqwest::Client, auth_header: &str, media_type: &str,
+                         since: chrono::DateTime<chrono::Utc>, until: chrono::DateTime<chrono::Utc>) -> Result<HashSet<i64>> {
+        let mut ids = HashSet::new();
+        let mut start = since;
+
+        while start < until {
+            let end = (start + chrono::Duration::days(TMDB_CHANGES_MAX_DAYS)).min(until);
+            let mut page = 1;
+            loop {
+                let url = format!(
+                    "https://api.themoviedb.org/3/{}/changes?start_date={}&end_date={}&page={}",
+                    media_type, start.format("%Y-%m-%d"), end.format("%Y-%m-%d"), page
+                );
+                let data = match self.tmdb_get(client, auth_header, &url).await? {
+                    Some(data) => data,
+                    None => return Err(anyhow::anyhow!("Changes request for {} was unsuccessful", media_type)),
+                };
+
+                ids.extend(data["results"].as_array().into_iter().flatten().filter_map(|r| r["id"].as_i64()));
+                if page >= data["total_pages"].as_u64().unwrap_or(1) {
+                    break;
+                }
+                page += 1;
+            }
+            start = end;
+        }
+
+        Ok(ids)
+    }
+
+    // Re-fetch the catalog items TMDB reports as changed since the last refresh or
+    // check, returning how many were updated. Titles new to TMDB's lists still only
+    // arrive with a full refresh.
+    async fn incremental_refresh(&self) -> Result<usize> {
+        let client = reqwest::Client::new();
+        let auth_header = format!("Bearer {}", self.tmdb_api_key);
+
+        let until = chrono::Utc::now();
+        let last_updated = self.cache.read().last_updated;
+        let since = self.changes_checked_at.lock().map_or(last_updated, |checked| checked.max(last_updated));
+
+        let mut changed = Vec::new();
+        for media_type in ["movie", "tv"] {
+            if !self.scrapes(media_type) {
+                continue;
+            }
+            let ids = self.changed_ids(&client, &auth_header, media_type, since, until).await?;
+            let cache = self.cache.read();
+            changed.extend(ids.into_iter()
+                .filter(|id| cache.find(media_type, *id).is_some())
+                .map(|id| (media_type.to_string(), id)));
+        }
+        println!("{} catalog items changed on TMDB since {}", changed.len(), since);
+
+        let (client, auth_header) = (&client, auth_header.as_str());
+        let updated: Vec<Content> = futures_util::stream::iter(changed)
+            .map(|(media_type, id)| async move {
+                match self.fetch_details(client, auth_header, &media_type, id).await {
+                    Ok(content) => content,
+                    Err(e) => {
+                        println!("Error re-fetching {} {}: {}", media_type, id, e);
+                        None
+                    }
+                }
+            })
+            .buffer_unordered(self.scrape_concurrency)
+            .filter_map(|content| async move { content })
+            .collect()
+            .await;
+
+        // Applied to the catalog as it is now, so items a full refresh dropped in the
+        // meantime are skipped rather than brought back
+        let count = updated.len();
+        if count > 0 {
+            let cache_data = {
+                let mut cache = self.cache.write();
+                let mut content = cache.data.get("latest").cloned().unwrap_or_default();
+                for mut item in updated {
+                    if let Some(&index) = cache.by_id.get(&item.id) {
+                        // Details don't say which lists an item came from or whether
+                        // it's in theaters, so those carry over
+                        item.sources = std::mem::take(&mut content[index].sources);
+                        item.in_theaters = content[index].in_theaters;
+                        item.fetched_at = Some(until);
+                        content[index] = item;
+                    }
+                }
+                cache.set_latest(content, self.quality_min_votes, self.max_cache_items);
+                cache.to_cache_data()
+            }; // Lock is dropped here
+            let _ = self.events.send(CatalogEvent::Updated { changed: count });
+            self.save_to_blob(&cache_data).await?;
+        }
+
+        *self.changes_checked_at.lock() = Some(until);
+        Ok(count)
+    }
+
+    // Tell subscribers the catalog was replaced. Having none is fine.
+    fn publish_refresh(&self, last_updated: chrono::DateTime<chrono::Utc>, items: usize) {
+        let _ = self.events.send(CatalogEvent::Refreshed { last_updated, items });
+    }
+
+    // Scrape unconditionally, replace the cached catalog and save it, returning the item count
+    async fn refresh_content(&self) -> Result<usize> {
+        println!("Starting content scraping...");
+        let content = self.scrape_content().await?;
+        println!("Scraped {} items", content.len());
+
+        // Create cache data outside the lock
+        let cache_data = {
+            let mut cache = self.cache.write();
+            cache.set_latest(content, self.quality_min_votes, self.max_cache_items);
+            cache.used_recommendations.clear();
+            cache.unseen_remaining.clear();
+            cache.decay_exposure(self.exposure_decay);
+            cache.last_updated = chrono::Utc::now();
+
+            cache.to_cache_data()
+        }; // Lock is dropped here
+        let item_count = cache_data.content.len();
+        self.ready.store(true, std::sync::atomic::Ordering::SeqCst);
+        self.publish_refresh(cache_data.last_updated, item_count);
+
+        // Save to blob after releasing the lock. When the saved blob already holds
+        // exactly this state, in the current format, only its last-updated time needs
+        // writing, which spares a full upload in quiet periods.
+        let stored = self.stored_metadata().await.unwrap_or_default();
+        if stored.get("state-hash").map(String::as_str) == Some(state_hash(&cache_data)?.as_str()) &&
+            stored.get("format").map(String::as_str) == Some(self.blob_format.as_str()) {
+            println!("Saved state unchanged, updating its last-updated time only");
+            self.touch_blob(&cache_data).await?;
+        } else {
+            self.save_to_blob(&cache_data).await?;
+        }
+        *self.last_refresh_success.write() = Some(chrono::Utc::now());
+
+        Ok(item_count)
+    }
+
+    // Refresh now if the catalog is older than max_age_hours and no other refresh is
+    // running. Returns whether this call did the refresh.
+    async fn refresh_if_older_than(&self, max_age_hours: i64) -> Result<bool> {
+        use std::sync::atomic::Ordering;
+
+        let max_age = chrono::Duration::hours(max_age_hours.max(MIN_MAX_AGE_HOURS));
+        if chrono::Utc::now().signed_duration_since(self.cache.read().last_updated) <= max_age {
+            return Ok(false);
+        }
+
+        if self.refresh_in_progress.swap(true, Ordering::SeqCst) {
+            println!("Refresh already in progress, serving current catalog");
+            return Ok(false);
+        }
+
+        println!("Catalog older than {} hours, refreshing for request", max_age.num_hours());
+        let result = self.refresh_content().await;
+        self.refresh_in_progress.store(false, Ordering::SeqCst);
+        result.map(|_| true)
+    }
+
+    // Recover from a corrupted blob by deleting it and uploading a freshly scraped catalog
+    async fn rebuild_blob(&self) -> Result<usize> {
+        let blob_client = self.blob_client.blob_client("latest.json.gz");
+
+        println!("Deleting existing blob for rebuild...");
+        match blob_client.delete().await {
+            Ok(_) => println!("Deleted existing blob"),
+            Err(e) if e.to_string().contains("404") => println!("No existing blob to delete"),
+            Err(e) => return Err(anyhow::anyhow!("Failed to delete blob: {}", e)),
+        }
+
+        self.refresh_content().await
+    }
+
+    async fn save_to_blob(&self, cache_data: &CacheData) -> Result<()> {
+        // Saves run one at a time, and a snapshot older than one already saved is
+        // dropped: it was taken before a change that the newer snapshot includes
+        let mut last_saved = self.last_saved_snapshot.lock().await;
+        if cache_data.snapshot <= *last_saved {
+            println!("Skipping save of snapshot {}, snapshot {} is already saved", cache_data.snapshot, *last_saved);
+            return Ok(());
+        }
+
+        // set_latest already caps the catalog; checking again here means no snapshot can
+        // put more than MAX_CACHE_ITEMS in the blob
+        let capped;
+        let cache_data = match self.max_cache_items {
+            Some(max_items) if cache_data.content.len() > max_items => {
+                let mut content = cache_data.content.clone();
+                enforce_cache_limit(&mut content, max_items);
+                capped = CacheData { content, ..cache_data.clone() };
+                &capped
+            },
+            _ => cache_data,
+        };
+
+        let serialized = match self.blob_format {
+            BlobFormat::Json => serde_json::to_vec(cache_data)?,
+            BlobFormat::MessagePack => rmp_serde::to_vec_named(cache_data)?,
+        };
+        println!("{} serialized, size: {} bytes", self.blob_format.as_str(), serialized.len());
+
+        let mut encoder = flate2::write::GzEncoder::new(Vec::new(), self.blob_compression);
+        encoder.write_all(&serialized)?;
+        let compressed = encoder.finish()?;
+        println!("Compressed size: {} bytes", compressed.len());
+
+        let metadata = self.blob_metadata(cache_data)?;
+        self.upload_blob("latest.json.gz", compressed, &metadata).await?;
+        *last_saved = cache_data.snapshot;
+        Ok(())
+    }
+
+    // Metadata saved with the catalog blob
+    fn blob_metadata(&self, cache_data: &CacheData) -> Result<azure_core::headers::Headers> {
+        // Create metadata using standard Headers
+        use azure_core::headers::Headers;
+        let mut metadata = Headers::new();
+        metadata.insert("encoding", "gzip");
+        metadata.insert("items", &cache_data.content.len().to_string());
+        metadata.insert("last-updated", &cache_data.last_updated.to_rfc3339());
+        metadata.insert("schema-version", &cache_data.schema_version.to_string());
+        metadata.insert("format", self.blob_format.as_str());
+        metadata.insert("state-hash", &state_hash(cache_data)?);
+        Ok(metadata)
+    }
+
+    // Record a snapshot whose state the saved blob already holds by rewriting only the
+    // blob's metadata. Its newer last-updated time is taken over the one in the blob
+    // body when loading.
+    async fn touch_blob(&self, cache_data: &CacheData) -> Result<()> {
+        let mut last_saved = self.last_saved_snapshot.lock().await;
+        if cache_data.snapshot <= *last_saved {
+            println!("Skipping save of snapshot {}, snapshot {} is already saved", cache_data.snapshot, *last_saved);
+            return Ok(());
+        }
+
+        let metadata = self.blob_metadata(cache_data)?;
+        self.blob_client.blob_client("latest.json.gz")
+            .set_metadata()
+            .metadata(&metadata)
+            .await
+            .map_err(|e| anyhow::anyhow!("Failed to update blob metadata: {}", e))?;
+        *last_saved = cache_data.snapshot;
+        Ok(())
+    }
+
+    // Metadata of the saved catalog blob, if there is one
+    async fn stored_metadata(&self) -> Option<HashMap<String, String>> {
+        let properties = self.blob_client.blob_client("latest.json.gz")
+            .get_properties()
+            .await
+            .ok()?;
+        properties.blob.metadata
+    }
+
+    // Upload a gzipped blob, retrying with exponential backoff
+    async fn upload_blob(&self, blob_name: &str, compressed: Vec<u8>, metadata: &azure_core::headers::Headers) -> Result<()> {
+        // Create a blob client for our file
+        let blob_client = self.blob_client.blob_client(blob_name);
+
+        println!("Attempting to upload blob: {}", blob_name);
+
+        // Try to upload with retries
+        let result = with_retries(&format!("Upload of {}", blob_name), BLOB_UPLOAD_ATTEMPTS,
+                                  std::time::Duration::from_secs(2), std::time::Duration::from_secs(30),
+                                  || self.put_blob(&blob_client, &compressed, metadata)).await;
+
+        match result {
+            Ok(()) => {
+                println!("Successfully uploaded blob: {}", blob_name);
+                Ok(())
+            },
+            Err(e) => {
+                println!("Failed to upload blob after {} attempts", BLOB_UPLOAD_ATTEMPTS);
+                Err(anyhow::anyhow!("Failed to upload blob after {} attempts: {}", BLOB_UPLOAD_ATTEMPTS, e))
+            }
+        }
+    }
+
+    // Upload in one request when the payload fits in a single block, otherwise stage it
+    // as BLOB_BLOCK_SIZE blocks and commit the block list
+    async fn put_blob(&self, blob_client: &BlobClient, compressed: &[u8],
+                      metadata: &azure_core::headers::Headers) -> azure_core::Result<()> {
+        let blocks = blob_blocks(compressed, self.blob_block_size);
+        if blocks.len() <= 1 {
+            blob_client.put_block_blob(compressed.to_vec())
+                .content_type("application/gzip")
+                .metadata(metadata)
+                .await?;
+            return Ok(());
+        }
+
+        let mut block_list = BlockList::default();
+        for (block_id, chunk) in blocks {
+            let block_id = BlockId::new(block_id);
+            blob_client.put_block(block_id.clone(), chunk.to_vec()).await?;
+            block_list.blocks.push(BlobBlockType::new_uncommitted(block_id));
+        }
+        println!("Staged {} blocks, committing block list", block_list.blocks.len());
+
+        blob_client.put_block_list(block_list)
+            .content_type("application/gzip")
+            .metadata(metadata)
+            .await?;
+        Ok(())
+    }
+
+    // Download a whole blob and its metadata, returning None when it doesn't exist or is empty
+    async fn download_blob(&self, blob_name: &str) -> Result<Option<(Vec<u8>, HashMap<String, String>)>> {
+        let blob_client = self.blob_client.blob_client(blob_name);
+
+        let mut stream = blob_client.get().into_stream();
+        let mut data = Vec::new();
+        let mut metadata = HashMap::new();
+
+        while let Some(chunk) = stream.next().await {
+            match chunk {
+                Ok(chunk) => {
+                    if let Some(blob_metadata) = chunk.blob.metadata {
+                        metadata = blob_metadata;
+                    }
+                    let bytes = chunk.data.collect().await?;
+                    data.extend(bytes);
+                },
+                Err(e) if e.to_string().contains("404") => {
+                    println!("No existing blob found: {}", blob_name);
+                    return Ok(None);
+                },
+                Err(e) => return Err(anyhow::anyhow!("Failed to download blob {}: {}", blob_name, e)),
+            }
+        }
+
+        if data.is_empty() {
+            println!("Downloaded blob was empty: {}", blob_name);
+            return Ok(None);
+        }
+
+        println!("Downloaded {} bytes from {}", data.len(), blob_name);
+        Ok(Some((data, metadata)))
+    }
+
+    fn process_blob_data(&self, data: &[u8], format: BlobFormat) -> Result<CacheData> {
+        let mut decoder = flate2::read::GzDecoder::new(data);
+        let mut decompressed = Vec::new();
+        std::io::Read::read_to_end(&mut decoder, &mut decompressed)?;
+
+        let mut cache_data: CacheData = match format {
+            BlobFormat::Json => serde_json::from_slice(&decompressed)?,
+            BlobFormat::MessagePack => rmp_serde::from_slice(&decompressed)?,
+        };
+
+        // Catalogs saved before ids were added
+        for content in cache_data.content.iter_mut().filter(|c| c.id.is_empty()) {
+            content.id = content_id(&content.media_type, content.tmdb_id);
+        }
+
+        // Watchlists, watched items and pins saved as bare tmdb_ids
+        let catalog: HashSet<String> = cache_data.content.iter().map(|c| c.id.clone()).collect();
+        for ids in cache_data.watchlists.values_mut().chain(cache_data.watched.values_mut()) {
+            *ids = ids.drain().map(|id| resolve_saved_id(id, &catalog)).collect();
+        }
+        let mut pinned = HashSet::new();
+        cache_data.pins = std::mem::take(&mut cache_data.pins).into_iter()
+            .map(|id| resolve_saved_id(id, &catalog))
+            .filter(|id| pinned.insert(id.clone()))
+            .collect();
+        Ok(cache_data)
+    }
+
+    // Populate the cache from the saved blob so we can serve before the first scrape.
+    // Returns false when there is no blob yet.
+    async fn load_from_blob(&self) -> Result<bool> {
+        println!("Loading content from blob storage...");
+        let (data, metadata) = match self.download_blob("latest.json.gz").await? {
+            Some(blob) => blob,
+            None => return Ok(false),
+        };
+
+        // Blobs written before the format tag existed are JSON
+        let format = match metadata.get("format") {
+            Some(format) => BlobFormat::parse(format)
+                .ok_or_else(|| anyhow::anyhow!("Unknown blob format: {}", format))?,
+            None => BlobFormat::Json,
+        };
+
+        let mut cache_data = self.process_blob_data(&data, format)?;
+
+        // A refresh that changed nothing else only rewrites the metadata's last-updated
+        if let Some(touched) = metadata.get("last-updated")
+            .and_then(|t| chrono::DateTime::parse_from_rfc3339(t).ok())
+            .map(|t| t.with_timezone(&chrono::Utc)) {
+            cache_data.last_updated = cache_data.last_updated.max(touched);
+        }
+        println!("Loaded {} items last updated {}", cache_data.content.len(), cache_data.last_updated);
+
+        // Items saved before the age limit was set (or lowered) expire here too
+        let pruned = self.prune_expired(&mut cache_data.content);
+        if pruned > 0 {
+            println!("Pruned {} saved items older than the maximum item age", pruned);
+        }
+
+        let mut cache = self.cache.write();
+        cache.set_latest(cache_data.content, self.quality_min_votes, self.max_cache_items);
+        cache.used_recommendations = cache_data.used_recommendations;
+        cache.watchlists = cache_data.watchlists;
+        cache.watched = cache_data.watched;
+        cache.pins = cache_data.pins;
+        cache.exposure = cache_data.exposure;
+        cache.last_updated = cache_data.last_updated;
+        self.ready.store(true, std::sync::atomic::Ordering::SeqCst);
+
+        Ok(true)
+    }
+
+    // Scrape into an empty catalog and save it, returning the scored catalog
+    async fn populate_empty_catalog(&self) -> Result<Vec<Content>> {
+        let content = self.scrape_content().await?;
+
+        let cache_data = {
+            let mut cache = self.cache.write();
+            cache.set_latest(content, self.quality_min_votes, self.max_cache_items);
+            cache.used_recommendations.clear();
+            cache.unseen_remaining.clear();
+            cache.last_updated = chrono::Utc::now();
+            cache.to_cache_data()
+        }; // Lock is dropped here
+        self.ready.store(true, std::sync::atomic::Ordering::SeqCst);
+        self.publish_refresh(cache_data.last_updated, cache_data.content.len());
+
+        // Save to blob outside the lock
+        self.save_to_blob(&cache_data).await?;
+        Ok(cache_data.content)
+    }
+
+    async fn get_recommendations(&self, prefs: &UserPreferences, debug: bool) -> Result<RecommendationResponse> {
+        println!("ContentService: Processing recommendation request");
+        let user_key = self.generate_user_key(prefs);
+
+        // Try to load from cache first. Stale content is still served, up to
+        // MAX_STALE_HOURS old; the caller kicks off a background refresh when the
+        // response is marked stale.
+        let (content, stale, age) = {
+            let cache = self.cache.read();
+            (cache.data.get("latest").filter(|c| !c.is_empty()).cloned(), cache.needs_update(),
+             chrono::Utc::now().signed_duration_since(cache.last_updated))
+        };
+
+        let recommendations = if let Some(content) = content {
+            // Use cached content
+            if stale {
+                if self.max_stale_age.is_some_and(|max| age > max) {
+                    return Err(CatalogTooStale { age_hours: age.num_hours() }.into());
+                }
+                println!("Serving recommendations from stale cache");
+            }
+            self.filter_recommendations(content, prefs, &user_key, debug, &HashSet::new())?
+        } else {
+            // Nothing to recommend from yet. Only one request scrapes; the rest are
+            // told to retry rather than given an empty result.
+            if self.refresh_in_progress.swap(true, std::sync::atomic::Ordering::SeqCst) {
+                return Err(CatalogNotReady.into());
+            }
+            println!("Starting fresh content fetch");
+            let populated = self.populate_empty_catalog().await;
+            self.refresh_in_progress.store(false, std::sync::atomic::Ordering::SeqCst);
+
+            // Filter recommendations
+            self.filter_recommendations(populated?, prefs, &user_key, debug, &HashSet::new())?
+        };
+
+        if recommendations.items.is_empty() && prefs.allow_fallback {
+            println!("No content matched preferences, returning popular fallback");
+            let items = self.fallback_recommendations(prefs);
+            return Ok(RecommendationResponse {
+                diversity: debug.then(|| genre_entropy(&items)),
+                items,
+                fallback: true,
+                stale,
+                total_matching: recommendations.total_matching,
+                total_unseen: recommendations.total_unseen,
+            });
+        }
+
+        Ok(RecommendationResponse {
+            diversity: debug.then(|| genre_entropy(&recommendations.items)),
+            items: recommendations.items,
+            fallback: false,
+            stale,
+            total_matching: recommendations.total_matching,
+            total_unseen: recommendations.total_unseen,
+        })
+    }
+
+    // A page of the eligible items in a fixed order for the seed. Unlike the other
+    // recommendation endpoints nothing is marked as used, so the same request always
+    // gives the same page until the catalog, pins or watchlists change, and computed
+    // pages are cached.
+    fn recommendation_page(&self, prefs: &UserPreferences, seed: u64, page: usize, page_size: usize) -> Result<RecommendationPage> {
+        // Every preference field can change the result, so the whole set is the key
+        let key = format!("{}|{}|{}|{}", serde_json::to_string(prefs)?, seed, page, page_size);
+        let generation = self.cache.read().generation;
+        if let Some(cached) = self.page_cache.lock().get(&key, generation) {
+            return Ok(cached);
+        }
+
+        let (content, generation) = {
+            let cache = self.cache.read();
+            (cache.data.get("latest").cloned().unwrap_or_default(), cache.generation)
+        };
+
+        let is_eligible = self.eligibility(prefs);
+        let eligible: Vec<Content> = content.into_iter().filter(|c| is_eligible(c)).collect();
+        let ordered = self.seeded_strategy(prefs, Some(seed)).recommend(&eligible, prefs);
+
+        let computed = RecommendationPage {
+            total_matching: ordered.len(),
+            items: ordered.into_iter().skip((page - 1) * page_size).take(page_size).collect(),
+            page,
+            page_size,
+        };
+        self.page_cache.lock().insert(key, generation, computed.clone());
+        Ok(computed)
+    }
+
+    // Recommendations for several saved profiles in one call, in request order. With
+    // shared_dedup an item given to one profile is excluded for the rest, so earlier
+    // profiles get first pick. There's no popular fallback for an empty result here.
+    fn batch_recommendations(&self, profiles: &[(String, UserPreferences)], shared_dedup: bool)
+                             -> Result<Vec<(String, FilteredRecommendations)>> {
+        let content = self.cache.read().data.get("latest").cloned().unwrap_or_default();
+        let mut seen = HashSet::new();
+        let mut results = Vec::new();
+
+        for (name, prefs) in profiles {
+            let user_key = self.generate_user_key(prefs);
+            let recommendations = self.filter_recommendations(content.clone(), prefs, &user_key, false, &seen)?;
+            if shared_dedup {
+                seen.extend(recommendations.items.iter().map(|c| c.id.clone()));
+            }
+            results.push((name.clone(), recommendations));
+        }
+
+        Ok(results)
+    }
+
+    // One row per favorite genre, each filtered as if that genre were the only favorite
+    // and shuffled. Rows are filled in favorite order, so with duplicates disallowed an
+    // item lands in the first row it matches. Rows don't mark items as used.
+    fn recommendation_rows(&self, prefs: &UserPreferences, per_row: usize, allow_duplicates: bool)
+                           -> std::collections::BTreeMap<String, Vec<Content>> {
+        use rand::seq::SliceRandom;
+
+        let content = self.cache.read().data.get("latest").cloned().unwrap_or_default();
+        let mut rng = self.rng();
+        let mut placed = HashSet::new();
+        let mut rows = std::collections::BTreeMap::new();
+
+        for genre in &prefs.favorite_genres {
+            let row_prefs = UserPreferences {
+                favorite_genres: vec![genre.clone()],
+                ..prefs.clone()
+            };
+            let is_eligible = self.eligibility(&row_prefs);
+
+            let mut row: Vec<Content> = content.iter()
+                .filter(|c| is_eligible(c))
+                .filter(|c| allow_duplicates || !placed.contains(&c.id))
+                .cloned()
+                .collect();
+            row.shuffle(&mut rng);
+            row.truncate(per_row);
+
+            placed.extend(row.iter().map(|c| c.id.clone()));
+            rows.insert(genre.clone(), row);
+        }
+
+        rows
+    }
+
+    // Highest rated items in the catalog regardless of genre, leaving out anything the
+    // user has watched
+    fn fallback_recommendations(&self, prefs: &UserPreferences) -> Vec<Content> {
+        let watched = self.watched_ids(prefs);
+        let cache = self.cache.read();
+        let mut content: Vec<Content> = cache.data.get("latest").cloned().unwrap_or_default();
+        content.retain(|c| (c.media_type.is_empty() || self.scrapes(&c.media_type)) && !watched.contains(&c.id));
+        content.sort_by(|a, b| b.rating.unwrap_or(0.0).total_cmp(&a.rating.unwrap_or(0.0))
+            .then_with(|| break_tie(a, b, &self.tie_breakers, self.yearless)));
+        content.truncate(self.fallback_size);
+        content
+    }
+
+    // Pin an item, returning the updated pin list
+    async fn add_pin(&self, media_type: &str, tmdb_id: i64) -> Result<Vec<String>> {
+        let id = content_id(media_type, tmdb_id);
+        let (pins, cache_data) = {
+            let mut cache = self.cache.write();
+            if !cache.pins.contains(&id) {
+                cache.pins.push(id);
+                cache.generation += 1;
+            }
+            (cache.pins.clone(), cache.to_cache_data())
+        };
+
+        self.save_to_blob(&cache_data).await?;
+        Ok(pins)
+    }
+
+    // Unpin an item, returning None if it wasn't pinned
+    async fn remove_pin(&self, media_type: &str, tmdb_id: i64) -> Result<Option<Vec<String>>> {
+        let id = content_id(media_type, tmdb_id);
+        let (pins, cache_data) = {
+            let mut cache = self.cache.write();
+            let before = cache.pins.len();
+            cache.pins.retain(|pinned| *pinned != id);
+            if cache.pins.len() == before {
+                return Ok(None);
+            }
+            cache.generation += 1;
+            (cache.pins.clone(), cache.to_cache_data())
+        };
+
+        self.save_to_blob(&cache_data).await?;
+        Ok(Some(pins))
+    }
+
+    fn get_watchlist(&self, user_id: &str) -> Vec<String> {
+        let cache = self.cache.read();
+        let mut ids: Vec<String> = cache.watchlists.get(user_id)
+            .map(|ids| ids.iter().cloned().collect())
+            .unwrap_or_default();
+        ids.sort();
+        ids
+    }
+
+    async fn add_to_watchlist(&self, user_id: &str, media_type: &str, tmdb_id: i64) -> Result<()> {
+        let cache_data = {
+            let mut cache = self.cache.write();
+            cache.watchlists
+                .entry(user_id.to_string())
+                .or_insert_with(HashSet::new)
+                .insert(content_id(media_type, tmdb_id));
+            cache.generation += 1;
+            cache.to_cache_data()
+        };
+
+        self.save_to_blob(&cache_data).await
+    }
+
+    fn get_watched(&self, user_id: &str) -> Vec<String> {
+        let cache = self.cache.read();
+        let mut ids: Vec<String> = cache.watched.get(user_id)
+            .map(|ids| ids.iter().cloned().collect())
+            .unwrap_or_default();
+        ids.sort();
+        ids
+    }
+
+    // Mark an item watched or unwatched for a user, returning false without saving
+    // when that changes nothing
+    async fn set_watched(&self, user_id: &str, media_type: &str, tmdb_id: i64, watched: bool) -> Result<bool> {
+        let id = content_id(media_type, tmdb_id);
+        let cache_data = {
+            let mut cache = self.cache.write();
+            let changed = if watched {
+                cache.watched.entry(user_id.to_string()).or_insert_with(HashSet::new).insert(id)
+            } else {
+                let removed = cache.watched.get_mut(user_id).map_or(false, |ids| ids.remove(&id));
+                if cache.watched.get(user_id).map_or(false, |ids| ids.is_empty()) {
+                    cache.watched.remove(user_id);
+                }
+                removed
+            };
+            if !changed {
+                return Ok(false);
+            }
+            cache.generation += 1;
+            cache.to_cache_data()
+        };
+
+        self.save_to_blob(&cache_data).await?;
+        Ok(true)
+    }
+
+    fn get_profile(&self, name: &str) -> Option<UserPreferences> {
+        self.profiles.read().get(name).cloned()
+    }
+
+    fn profile_names(&self) -> Vec<String> {
+        let mut names: Vec<String> = self.profiles.read().keys().cloned().collect();
+        names.sort();
+        names
+    }
+
+    // Store a profile, returning false without saving when it's new and MAX_PROFILES
+    // are already stored
+    async fn save_profile(&self, name: &str, prefs: UserPreferences) -> Result<bool> {
+        let json = {
+            let mut profiles = self.profiles.write();
+            if !profiles.contains_key(name) && profiles.len() >= self.max_profiles {
+                return Ok(false);
+            }
+            profiles.insert(name.to_string(), prefs);
+            serde_json::to_string(&*profiles)?
+        };
+
+        self.save_profiles_blob(json).await?;
+        Ok(true)
+    }
+
+    // Remove a profile, returning false if there was none by that name
+    async fn delete_profile(&self, name: &str) -> Result<bool> {
+        let json = {
+            let mut profiles = self.profiles.write();
+            if profiles.remove(name).is_none() {
+                return Ok(false);
+            }
+            serde_json::to_string(&*profiles)?
+        };
+
+        self.save_profiles_blob(json).await?;
+        Ok(true)
+    }
+
+    async fn save_profiles_blob(&self, json: String) -> Result<()> {
+        let mut encoder = flate2::write::GzEncoder::new(Vec::new(), self.blob_compression);
+        encoder.write_all(json.as_bytes())?;
+        let compressed = encoder.finish()?;
+
+        use azure_core::headers::Headers;
+        let mut metadata = Headers::new();
+        metadata.insert("encoding", "gzip");
+
+        self.upload_blob(PROFILES_BLOB, compressed, &metadata).await
+    }
+
+    // One lightweight TMDB request and a write, read and delete of a tiny blob, so
+    // bad credentials or networking fail at boot instead of on the first request
+    async fn self_test(&self) -> Result<()> {
+        let response = reqwest::Client::new()
+            .get("https://api.themoviedb.org/3/configuration")
+            .header("Authorization", format!("Bearer {}", self.tmdb_api_key))
+            .timeout(std::time::Duration::from_secs(10))
+            .send()
+            .await
+            .map_err(|e| anyhow::anyhow!("Self-test could not reach TMDB: {}", e))?;
+        if !response.status().is_success() {
+            return Err(anyhow::anyhow!(
+                "Self-test TMDB request failed with {}, check TMDB_API_KEY", response.status()
+            ));
+        }
+        println!("Self-test: TMDB reachable");
+
+        let blob_client = self.blob_client.blob_client(SELFTEST_BLOB);
+        let payload = format!("selftest {}", chrono::Utc::now().to_rfc3339()).into_bytes();
+        blob_client.put_block_blob(payload.clone())
+            .content_type("text/plain")
+            .await
+            .map_err(|e| anyhow::anyhow!("Self-test could not write blob {}: {}, check the storage account settings", SELFTEST_BLOB, e))?;
+
+        let read_back = self.download_blob(SELFTEST_BLOB).await
+            .map_err(|e| anyhow::anyhow!("Self-test could not read blob {}: {}", SELFTEST_BLOB, e))?;
+        if read_back.map(|(data, _)| data) != Some(payload) {
+            return Err(anyhow::anyhow!("Self-test blob {} read back different content than was written", SELFTEST_BLOB));
+        }
+
+        if let Err(e) = blob_client.delete().await {
+            println!("Self-test could not delete blob {}: {}", SELFTEST_BLOB, e);
+        }
+        println!("Self-test: blob storage round-trip succeeded");
+        Ok(())
+    }
+
+    // Load saved profiles, returning how many were found
+    async fn load_profiles(&self) -> Result<usize> {
+        let data = match self.download_blob(PROFILES_BLOB).await? {
+            Some((data, _)) => data,
+            None => return Ok(0),
+        };
+
+        let mut decoder = flate2::read::GzDecoder::new(&data[..]);
+        let mut decompressed = String::new();
+        std::io::Read::read_to_string(&mut decoder, &mut decompressed)?;
+
+        let profiles: HashMap<String, UserPreferences> = serde_json::from_str(&decompressed)?;
+        let count = profiles.len();
+        *self.profiles.write() = profiles;
+        Ok(count)
+    }
+
+    // The favorite genres plus, when expand_genres is set, their adjacent genres
+    fn expanded_genres(&self, prefs: &UserPreferences) -> HashSet<String> {
+        let mut genres = prefs.favorite_genre_set();
+        if prefs.expand_genres {
+            for genre in &prefs.favorite_genres {
+                genres.extend(self.genre_adjacency.get(genre).into_iter().flatten().cloned());
+            }
+        }
+        genres
+    }
+
+    // Content ids the requesting user has marked watched
+    fn watched_ids(&self, prefs: &UserPreferences) -> HashSet<String> {
+        prefs.user_id.as_ref()
+            .and_then(|user_id| self.cache.read().watched.get(user_id).cloned())
+            .unwrap_or_default()
+    }
+
+    // Predicate for content the preferences allow, before any used-item filtering
+    fn eligibility<'a>(&'a self, prefs: &UserPreferences) -> impl Fn(&Content) -> bool + 'a {
+        // Items on the user's watchlist, when they've asked to exclude them
+        let watchlisted: HashSet<String> = match (&prefs.user_id, prefs.exclude_watchlisted) {
+            (Some(user_id), true) => self.cache.read().watchlists
+                .get(user_id)
+                .cloned()
+                .unwrap_or_default(),
+            _ => HashSet::new(),
+        };
+
+        // Items the user has marked watched are excluded for good, unlike used
+        // recommendations which are forgotten on each refresh
+        let watched = self.watched_ids(prefs);
+
+        let genres = self.expanded_genres(prefs);
+
+        // DEFAULT_MIN_RATING is a floor: a client value above it wins, anything at or
+        // below it (including an omitted 0.0) is raised to it
+        let mut prefs = prefs.clone();
+        prefs.minimum_rating = prefs.minimum_rating.max(self.default_min_rating);
+
+        move |c: &Content| prefs.matches_genres(c, &genres) &&
+            (c.media_type.is_empty() || self.scrapes(&c.media_type)) &&
+            !watchlisted.contains(&c.id) &&
+            !watched.contains(&c.id) &&
+            (!prefs.require_description || !c.description.trim().is_empty()) &&
+            prefs.min_description_len.map_or(true, |min| c.description.trim().chars().count() >= min) &&
+            self.yearless.within(c, prefs.min_year, prefs.max_year) &&
+            prefs.min_quality.map_or(true, |min| c.quality_score >= min) &&
+            (prefs.spoken_languages.is_empty() ||
+                c.spoken_languages.iter().any(|l| prefs.spoken_languages.iter().any(|wanted| wanted.eq_ignore_ascii_case(l)))) &&
+            c.status.as_ref().map_or(true, |status| !prefs.exclude_statuses.iter().any(|excluded| excluded.eq_ignore_ascii_case(status)))
+    }
+
+    // Choose a single item at random, marking only it as used. Prefers items the user
+    // hasn't been shown, but will repeat one rather than return nothing.
+    fn pick(&self, prefs: &UserPreferences, seed: Option<u64>) -> Option<Content> {
+        use rand::seq::SliceRandom;
+        use rand::SeedableRng;
+
+        let user_key = self.generate_user_key(prefs);
+        let is_eligible = self.eligibility(prefs);
+
+        let mut cache = self.cache.write();
+        let eligible: Vec<Content> = cache.data.get("latest")
+            .map(|content| content.iter().filter(|c| is_eligible(c)).cloned().collect())
+            .unwrap_or_default();
+
+        let used_recs = cache.used_recommendations
+            .entry(user_key)
+            .or_insert_with(HashSet::new);
+        let unused: Vec<&Content> = eligible.iter()
+            .filter(|c| !used_recs.contains(&c.title))
+            .collect();
+        let candidates = if unused.is_empty() { eligible.iter().collect() } else { unused };
+
+        let mut rng = match seed {
+            Some(seed) => rand::rngs::StdRng::seed_from_u64(seed),
+            None => self.rng(),
+        };
+        let pick = candidates.choose(&mut rng).map(|c| (*c).clone())?;
+
+        used_recs.insert(pick.title.clone());
+        Some(pick)
+    }
+
+    fn strategy(&self, prefs: &UserPreferences) -> Box<dyn RecommendationStrategy> {
+        let seed = if self.deterministic { Some(DETERMINISTIC_SEED) } else { None };
+        self.seeded_strategy(prefs, seed)
+    }
+
+    fn seeded_strategy(&self, prefs: &UserPreferences, seed: Option<u64>) -> Box<dyn RecommendationStrategy> {
+        match prefs.strategy.unwrap_or(self.default_strategy) {
+            StrategyKind::Default => Box::new(DefaultStrategy { seed }),
+            StrategyKind::Weighted => Box::new(WeightedStrategy),
+            StrategyKind::Similar => Box::new(SimilarStrategy),
+            StrategyKind::Quality => Box::new(QualityStrategy),
+        }
+    }
+
+    // Items whose id is in exclude are never selected, even after a used-set reset. In
+    // debug mode each item is annotated with its provenance, score and whether it had
+    // been shown before.
+    fn filter_recommendations(&self, content: Vec<Content>, prefs: &UserPreferences, user_key: &str, debug: bool,
+                              exclude: &HashSet<String>) -> Result<FilteredRecommendations> {
+        println!("Starting content filtering with {} items", content.len());
+
+        let eligible = self.eligibility(prefs);
+        let is_eligible = |c: &Content| eligible(c) && !exclude.contains(&c.id);
+
+        // Filter content before taking the lock
+        let mut available: Vec<_> = content.into_iter()
+            .filter(|c| is_eligible(c))
+            .collect();
+
+        println!("Found {} items matching rating and genre criteria", available.len());
+        let total_matching = available.len();
+
+        // Pinned items that match go first, in pin order. They skip the used filter so
+        // they appear on every request while pinned, but are still marked used below,
+        // so once unpinned they're treated like anything else the user has seen.
+        let pins = self.cache.read().pins.clone();
+        let pinned: Vec<Content> = pins.iter()
+            .flat_map(|id| available.iter().filter(move |c| c.id == *id))
+            .take(20)
+            .cloned()
+            .collect();
+
+        let mut shown_before = HashSet::new();
+        let total_unseen;
+
+        // Take a write lock only when needed
+        {
+            let mut cache = self.cache.write();
+            let used_recs = cache.used_recommendations
+                .entry(user_key.to_string())
+                .or_insert_with(HashSet::new);
+
+            if debug {
+                shown_before = used_recs.clone();
+            }
+
+            // Filter out used recommendations
+            available.retain(|c| !used_recs.contains(&c.title));
+            println!("After filtering used recommendations: {} items remain", available.len());
+            total_unseen = available.len();
+
+            // Reset if running low
+            if available.len() < USED_RESET_THRESHOLD {
+                println!("Running low on recommendations, resetting for user");
+                used_recs.clear();
+                drop(cache);
+
+                let cache_read = self.cache.read();
+                if let Some(latest_content) = cache_read.data.get("latest") {
+                    available = latest_content.iter()
+                        .filter(|c| is_eligible(c))
+                        .cloned()
+                        .collect();
+                }
+            }
+        }
+
+        // Unseen items this request can draw from, pins included
+        let unseen = available.len();
+        available.retain(|c| !pins.contains(&c.id));
+
+        available = self.strategy(prefs).recommend(&available, prefs);
+
+        // Push items back from their strategy position in proportion to how often
+        // they've been shown, so rarely shown items get their turn
+        if prefs.fair_exposure {
+            let exposure = self.cache.read().exposure.clone();
+            let mut keyed: Vec<(f64, Content)> = available.into_iter().enumerate()
+                .map(|(position, c)| ((position + 1) as f64 * (1.0 + exposure.get(&c.id).copied().unwrap_or(0.0)), c))
+                .collect();
+            keyed.sort_by(|a, b| a.0.total_cmp(&b.0));
+            available = keyed.into_iter().map(|(_, c)| c).collect();
+        }
+
+        // Cap items per primary provider. Only the first streaming provider counts, so a
+        // title on several services doesn't use up every one of their caps. Pins count
+        // toward the caps but are never dropped.
+        if let Some(max) = prefs.max_per_provider {
+            let mut per_provider: HashMap<String, usize> = HashMap::new();
+            for content in &pinned {
+                if let Some(provider) = content.where_to_watch.first() {
+                    *per_provider.entry(provider.clone()).or_insert(0) += 1;
+                }
+            }
+            available.retain(|c| match c.where_to_watch.first() {
+                Some(provider) => {
+                    let count = per_provider.entry(provider.clone()).or_insert(0);
+                    *count += 1;
+                    *count <= max
+                },
+                None => true,
+            });
+        }
+
+        let remaining = 20 - pinned.len();
+        let mut recommendations: Vec<_> = pinned.into_iter()
+            .chain(available.into_iter().take(remaining))
+            .collect();
+        if debug {
+            let favorites = prefs.favorite_genre_set();
+            for content in &mut recommendations {
+                content.debug = Some(ContentDebug {
+                    tmdb_id: content.tmdb_id,
+                    sources: content.sources.clone(),
+                    fetched_at: content.fetched_at,
+                    score: score_content(content, prefs, &favorites),
+                    previously_shown: shown_before.contains(&content.title),
+                });
+            }
+        }
+        println!("Selected {} recommendations", recommendations.len());
+
+        // Mark selected items as used
+        {
+            let mut cache = self.cache.write();
+            let used_recs = cache.used_recommendations
+                .entry(user_key.to_string())
+                .or_insert_with(HashSet::new);
+
+            let newly_used = recommendations.iter()
+                .filter(|content| used_recs.insert(content.title.clone()))
+                .count();
+            cache.unseen_remaining.insert(user_key.to_string(), unseen.saturating_sub(newly_used));
+            for content in &recommendations {
+                *cache.exposure.entry(content.id.clone()).or_insert(0.0) += 1.0;
+            }
+        }
+
+        Ok(FilteredRecommendations {
+            items: recommendations,
+            total_matching,
+            total_unseen,
+        })
+    }
+}
+
+// Returned when there's no catalog to recommend from yet because a scrape to fill
+// it is still running, so handlers can answer 503 instead of an empty result
+#[derive(Debug)]
+struct CatalogNotReady;
+
+impl std::fmt::Display for CatalogNotReady {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "content is still loading")
+    }
+}
+
+impl std::error::Error for CatalogNotReady {}
+
+// Returned when the catalog is older than MAX_STALE_HOURS, so handlers answer 503
+// instead of serving it
+#[derive(Debug)]
+struct CatalogTooStale {
+    age_hours: i64,
+}
+
+impl std::fmt::Display for CatalogTooStale {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "content is {} hours old", self.age_hours)
+    }
+}
+
+impl std::error::Error for CatalogTooStale {}
+
+// Refresh the catalog in the background unless a refresh is already running
+fn trigger_background_refresh(service: web::Data<ContentService>) {
+    use std::sync::atomic::Ordering;
+
+    if service.refresh_in_progress.swap(true, Ordering::SeqCst) {
+        return;
+    }
+
+    tokio::spawn(async move {
+        println!("Starting background content refresh...");
+        match service.refresh_content().await {
+            Ok(items) => println!("Background refresh completed with {} items", items),
+            Err(e) => eprintln!("Error during background refresh: {}", e),
+        }
+        service.refresh_in_progress.store(false, Ordering::SeqCst);
+    });
+}
+
+// Periodically refresh the catalog. When restarted after a panic the first refresh
+// runs straight away rather than waiting out another full TTL.
+async fn refresh_loop(service: web::Data<ContentService>, run_now: bool) {
+    if run_now {
+        println!("Starting content update after refresh task restart...");
+        if let Err(e) = service.update_content().await {
+            eprintln!("Error updating content: {}", e);
+        }
+    }
+
+    if let Some(schedule) = &service.scrape_cron {
+        // Scheduled refreshes always scrape, however recently the catalog was updated
+        for next in schedule.upcoming(chrono::Utc) {
+            let wait = (next - chrono::Utc::now()).to_std().unwrap_or_default();
+            tokio::time::sleep(wait).await;
+            println!("Starting scheduled content refresh for {}", next);
+            if let Err(e) = service.refresh_content().await {
+                eprintln!("Error refreshing content: {}", e);
+            }
+        }
+        println!("SCRAPE_CRON has no more upcoming times, stopping scheduled refreshes");
+        return;
+    }
+
+    loop {
+        tokio::time::sleep(tokio::time::Duration::from_secs(CACHE_TTL_HOURS as u64 * 3600)).await;
+        println!("Starting periodic content update...");
+        if let Err(e) = service.update_content().await {
+            eprintln!("Error updating content: {}", e);
+        }
+    }
+}
+
+// Apply TMDB's change lists to the catalog every interval, between full refreshes
+async fn incremental_refresh_loop(service: web::Data<ContentService>, interval: std::time::Duration) {
+    loop {
+        tokio::time::sleep(interval).await;
+        println!("Starting incremental content refresh...");
+        match service.incremental_refresh().await {
+            Ok(changed) => println!("Incremental refresh updated {} items", changed),
+            Err(e) => eprintln!("Error during incremental refresh: {}", e),
+        }
+    }
+}
+
+// Keep the refresh loop alive, restarting it with exponential backoff (capped at
+// five minutes) whenever it panics
+async fn supervise_refresh(service: web::Data<ContentService>) {
+    let mut backoff = std::time::Duration::from_secs(1);
+    let mut run_now = false;
+
+    loop {
+        let handle = tokio::spawn(refresh_loop(service.clone(), run_now));
+        match handle.await {
+            Ok(()) => return,
+            Err(e) if e.is_panic() => {
+                let panic = e.into_panic();
+                let message = panic.downcast_ref::<&str>().map(|m| m.to_string())
+                    .or_else(|| panic.downcast_ref::<String>().cloned())
+                    .unwrap_or_else(|| "unknown panic".to_string());
+                eprintln!("Refresh task panicked: {}, restarting in {:?}", message, backoff);
+
+                service.refresh_task_restarts.fetch_add(1, std::sync::atomic::Ordering::SeqCst);
+
+                tokio::time::sleep(backoff).await;
+                backoff = (backoff * 2).min(std::time::Duration::from_secs(300));
+                run_now = true;
+            },
+            Err(e) => {
+                eprintln!("Refresh task was cancelled: {}", e);
+                return;
+            },
+        }
+    }
+}
+
+// Stops calling TMDB for a cooldown period once too many failures (errors, 5xx/429
+// responses, or malformed bodies) pile up within a short window
+struct CircuitBreaker {
+    failures: Mutex<std::collections::VecDeque<std::time::Instant>>,
+    open_until: Mutex<Option<std::time::Instant>>,
+    threshold: usize,
+    window: std::time::Duration,
+    cooldown: std::time::Duration,
+}
+
+impl CircuitBreaker {
+    fn from_env() -> Result<Self> {
+        let threshold: usize = env::var("CIRCUIT_BREAKER_THRESHOLD")
+            .unwrap_or_else(|_| "10".to_string())
+            .parse()?;
+        let cooldown_secs: u64 = env::var("CIRCUIT_BREAKER_COOLDOWN_SECS")
+            .unwrap_or_else(|_| "60".to_string())
+            .parse()?;
+
+        if threshold == 0 {
+            return Err(anyhow::anyhow!("CIRCUIT_BREAKER_THRESHOLD must be at least 1"));
+        }
+
+        Ok(Self {
+            failures: Mutex::new(std::collections::VecDeque::new()),
+            open_until: Mutex::new(None),
+            threshold,
+            window: std::time::Duration::from_secs(60),
+            cooldown: std::time::Duration::from_secs(cooldown_secs),
+        })
+    }
+
+    fn is_open(&self) -> bool {
+        let mut open_until = self.open_until.lock();
+        match *open_until {
+            Some(until) if std::time::Instant::now() < until => true,
+            Some(_) => {
+                println!("TMDB circuit breaker closed, resuming requests");
+                *open_until = None;
+                false
+            },
+            None => false,
+        }
+    }
+
+    fn record_failure(&self, reason: &str) {
+        let now = std::time::Instant::now();
+        let mut failures = self.failures.lock();
+        failures.push_back(now);
+        while failures.front().map_or(false, |t| now.duration_since(*t) > self.window) {
+            failures.pop_front();
+        }
+
+        if failures.len() >= self.threshold {
+            println!("TMDB circuit breaker opened for {:?} after {} failures, last: {}",
+                     self.cooldown, failures.len(), reason);
+            failures.clear();
+            *self.open_until.lock() = Some(now + self.cooldown);
+        }
+    }
+}
+
+struct TokenBucket {
+    tokens: f64,
+    last_refill: std::time::Instant,
+}
+
+// Published on ContentService::events for anything that reacts to catalog changes
+#[derive(Debug, Clone, Serialize)]
+#[serde(tag = "event", rename_all = "snake_case")]
+enum CatalogEvent {
+    // The catalog was replaced by a scrape
+    Refreshed {
+        last_updated: chrono::DateTime<chrono::Utc>,
+        items: usize,
+    },
+    // An incremental refresh re-fetched items TMDB reported as changed
+    Updated {
+        changed: usize,
+    },
+}
+
+// Events a slow subscriber can fall behind by before it starts missing them
+const EVENT_CHANNEL_CAPACITY: usize = 16;
+
+// One computed page of stable recommendations
+#[derive(Debug, Clone, Serialize)]
+struct RecommendationPage {
+    items: Vec<Content>,
+    page: usize,
+    page_size: usize,
+    total_matching: usize,
+}
+
+struct PageCacheEntry {
+    page: RecommendationPage,
+    created: std::time::Instant,
+    last_used: u64,
+}
+
+// Small TTL cache of computed pages for GET /recommendations/page, bounded by least
+// recently used eviction. Emptied whenever the catalog generation changes.
+struct PageCache {
+    entries: HashMap<String, PageCacheEntry>,
+    generation: u64,
+    tick: u64,
+    capacity: usize,
+    ttl: std::time::Duration,
+}
+
+impl PageCache {
+    fn new(capacity: usize, ttl: std::time::Duration) -> Self {
+        Self {
+            entries: HashMap::new(),
+            generation: 0,
+            tick: 0,
+            capacity,
+            ttl,
+        }
+    }
+
+    fn sync_generation(&mut self, generation: u64) {
+        if generation != self.generation {
+            self.entries.clear();
+            self.generation = generation;
+        }
+    }
+
+    fn get(&mut self, key: &str, generation: u64) -> Option<RecommendationPage> {
+        self.sync_generation(generation);
+        self.tick += 1;
+
+        let ttl = self.ttl;
+        match self.entries.get_mut(key) {
+            Some(entry) if entry.created.elapsed() < ttl => {
+                entry.last_used = self.tick;
+                Some(entry.page.clone())
+            },
+            Some(_) => {
+                self.entries.remove(key);
+                None
+            },
+            None => None,
+        }
+    }
+
+    fn insert(&mut self, key: String, generation: u64, page: RecommendationPage) {
+        self.sync_generation(generation);
+        if self.capacity == 0 {
+            return;
+        }
+        self.tick += 1;
+
+        if !self.entries.contains_key(&key) && self.entries.len() >= self.capacity {
+            let oldest = self.entries.iter()
+                .min_by_key(|(_, entry)| entry.last_used)
+                .map(|(key, _)| key.clone());
+            if let Some(oldest) = oldest {
+                self.entries.remove(&oldest);
+            }
+        }
+
+        self.entries.insert(key, PageCacheEntry {
+            page,
+            created: std::time::Instant::now(),
+            last_used: self.tick,
+        });
+    }
+}
+
+// A client's bucket after a request, reported in the X-RateLimit-* headers
+struct RateLimitStatus {
+    allowed: bool,
+    // Burst size, the most requests that can be made back to back
+    limit: u64,
+    remaining: u64,
+    // Until the next token is available
+    retry_after: std::time::Duration,
+    // Until the bucket is full again
+    reset: std::time::Duration,
+}
+
+impl RateLimitStatus {
+    // Header names are lowercase so they can be used with HeaderName::from_static
+    fn headers(&self) -> [(&'static str, String); 3] {
+        [
+            ("x-ratelimit-limit", self.limit.to_string()),
+            ("x-ratelimit-remaining", self.remaining.to_string()),
+            ("x-ratelimit-reset", (self.reset.as_secs_f64().ceil() as u64).to_string()),
+        ]
+    }
+}
+
+// Per-client-IP token bucket limiter guarding the recommendation endpoint
+struct RateLimiter {
+    buckets: Mutex<HashMap<String, TokenBucket>>,
+    requests_per_second: f64,
+    burst: f64,
+    max_clients: usize,
+    trust_forwarded_for: bool,
+}
+
+impl RateLimiter {
+    fn from_env() -> Result<Self> {
+        let requests_per_second: f64 = env::var("RATE_LIMIT_RPS")
+            .unwrap_or_else(|_| "2".to_string())
+            .parse()?;
+        let burst: f64 = env::var("RATE_LIMIT_BURST")
+            .unwrap_or_else(|_| "10".to_string())
+            .parse()?;
+        let max_clients: usize = env::var("RATE_LIMIT_MAX_CLIENTS")
+            .unwrap_or_else(|_| "10000".to_string())
+            .parse()?;
+
+        if requests_per_second <= 0.0 || burst < 1.0 || max_clients == 0 {
+            return Err(anyhow::anyhow!(
+                "RATE_LIMIT_RPS must be positive, RATE_LIMIT_BURST at least 1 and RATE_LIMIT_MAX_CLIENTS at least 1"
+            ));
+        }
+
+        // Only honor X-Forwarded-For when running behind a trusted load balancer,
+        // otherwise any client could spoof its way around the limit
+        let trust_forwarded_for = env_flag("TRUST_FORWARDED_FOR", false);
+
+        println!("Rate limiting at {} req/s with burst {} (trust X-Forwarded-For: {})",
+                 requests_per_second, burst, trust_forwarded_for);
+
+        Ok(Self {
+            buckets: Mutex::new(HashMap::new()),
+            requests_per_second,
+            burst,
+            max_clients,
+            trust_forwarded_for,
+        })
+    }
+
+    fn client_ip(&self, req: &ServiceRequest) -> String {
+        if self.trust_forwarded_for {
+            let forwarded = req.headers()
+                .get("X-Forwarded-For")
+                .and_then(|h| h.to_str().ok())
+                .and_then(|h| h.split(',').next())
+                .map(|ip| ip.trim().to_string());
+            if let Some(ip) = forwarded.filter(|ip| !ip.is_empty()) {
+                return ip;
+            }
+        }
+
+        req.peer_addr()
+            .map(|addr| addr.ip().to_string())
+            .unwrap_or_else(|| "unknown".to_string())
+    }
+
+    // Take a token for the client if one is available, reporting the bucket's state
+    fn check(&self, ip: &str) -> RateLimitStatus {
+        let now = std::time::Instant::now();
+        let mut buckets = self.buckets.lock();
+
+        if !buckets.contains_key(ip) && buckets.len() >= self.max_clients {
+            self.evict_idle(&mut buckets, now);
+        }
+
+        let bucket = buckets.entry(ip.to_string()).or_insert(TokenBucket {
+            tokens: self.burst,
+            last_refill: now,
+        });
+
+        let elapsed = now.duration_since(bucket.last_refill).as_secs_f64();
+        bucket.tokens = (bucket.tokens + elapsed * self.requests_per_second).min(self.burst);
+        bucket.last_refill = now;
+
+        let allowed = bucket.tokens >= 1.0;
+        if allowed {
+            bucket.tokens -= 1.0;
+        }
+
+        RateLimitStatus {
+            allowed,
+            limit: self.burst.floor() as u64,
+            remaining: bucket.tokens.floor() as u64,
+            retry_after: std::time::Duration::from_secs_f64((1.0 - bucket.tokens).max(0.0) / self.requests_per_second),
+            reset: std::time::Duration::from_secs_f64((self.burst - bucket.tokens) / self.requests_per_second),
+        }
+    }
+
+    // Drop buckets that have refilled completely, since they are equivalent to a new
+    // client. If every client is still active, drop the least recently seen one.
+    fn evict_idle(&self, buckets: &mut HashMap<String, TokenBucket>, now: std::time::Instant) {
+        let refill_secs = self.burst / self.requests_per_second;
+        let before = buckets.len();
+        buckets.retain(|_, b| {
+            now.duration_since(b.last_refill).as_secs_f64() < refill_secs
+        });
+
+        if buckets.len() >= self.max_clients {
+            let oldest = buckets.iter()
+                .min_by_key(|(_, b)| b.last_refill)
+                .map(|(ip, _)| ip.clone());
+            if let Some(ip) = oldest {
+                buckets.remove(&ip);
+            }
+        }
+
+        println!("Evicted {} rate limit buckets", before - buckets.len());
+    }
+}
+
+async fn rate_limit(
+    req: ServiceRequest,
+    next: Next<impl MessageBody + 'static>,
+) -> Result<ServiceResponse<impl MessageBody>, actix_web::Error> {
+    let status = req.app_data::<web::Data<RateLimiter>>().map(|limiter| {
+        let ip = limiter.client_ip(&req);
+        (ip.clone(), limiter.check(&ip))
+    });
+
+    let status = match status {
+        Some((ip, status)) if !status.allowed => {
+            println!("Rate limit exceeded for {}", ip);
+            let retry_secs = status.retry_after.as_secs_f64().ceil().max(1.0) as u64;
+            let mut response = HttpResponse::TooManyRequests();
+            response.insert_header(("Retry-After", retry_secs.to_string()));
+            for header in status.headers() {
+                response.insert_header(header);
+            }
+            let response = response
+                .content_type("application/json")
+                .json(json!({
+                    "error": "Too many requests, please slow down"
+                }));
+            return Ok(req.into_response(response).map_into_right_body());
+        },
+        Some((_, status)) => Some(status),
+        None => None,
+    };
+
+    let mut res = next.call(req).await?;
+    if let Some(status) = status {
+        for (name, value) in status.headers() {
+            if let Ok(value) = actix_web::http::header::HeaderValue::from_str(&value) {
+                res.headers_mut().insert(actix_web::http::header::HeaderName::from_static(name), value);
+            }
+        }
+    }
+    Ok(res.map_into_left_body())
+}
+
+// Answer 503 until the catalog has been populated, rather than serving empty results
+async fn require_ready(
+    req: ServiceRequest,
+    next: Next<impl MessageBody + 'static>,
+) -> Result<ServiceResponse<impl MessageBody>, actix_web::Error> {
+    let ready = req.app_data::<web::Data<ContentService>>()
+        .map_or(true, |service| service.ready.load(std::sync::atomic::Ordering::SeqCst));
+
+    if !ready {
+        let response = HttpResponse::ServiceUnavailable()
+            .insert_header(("Retry-After", "30"))
+            .content_type("application/json")
+            .json(json!({
+                "error": "Content is still loading, try again shortly"
+            }));
+        return Ok(req.into_response(response).map_into_right_body());
+    }
+
+    next.call(req).await.map(|res| res.map_into_left_body())
+}
+
+// One JSON object per line, each line newline-terminated
+fn ndjson_lines<T: Serialize>(items: &[T]) -> Result<String> {
+    let mut body = String::new();
+    for item in items {
+        body.push_str(&serde_json::to_string(item)?);
+        body.push('\n');
+    }
+    Ok(body)
+}
+
+// 400 response for preferences that fail validation
+fn invalid_preferences(prefs: &UserPreferences) -> Option<HttpResponse> {
+    prefs.validate().err().map(|problems| HttpResponse::BadRequest()
+        .content_type("application/json")
+        .json(json!({
+            "error": format!("Invalid preferences: {}", problems)
+        })))
+}
+
+// Answer 504 when an API handler runs longer than REQUEST_TIMEOUT_SECS. The handler
+// runs as its own task and is left to finish rather than cancelled, so a cold
+// /recommendations scrape still fills the cache for the next request.
+async fn request_timeout(
+    req: ServiceRequest,
+    next: Next<impl MessageBody + 'static>,
+) -> Result<ServiceResponse<impl MessageBody>, actix_web::Error> {
+    let timeout = req.app_data::<web::Data<ContentService>>()
+        .and_then(|service| service.request_timeout);
+    let Some(timeout) = timeout else {
+        return next.call(req).await.map(|res| res.map_into_left_body());
+    };
+
+    let request = req.request().clone();
+    let path = req.path().to_string();
+    let handler = actix_web::rt::spawn(async move { next.call(req).await });
+
+    match tokio::time::timeout(timeout, handler).await {
+        Ok(Ok(result)) => result.map(|res| res.map_into_left_body()),
+        Ok(Err(e)) => Err(actix_web::error::ErrorInternalServerError(format!("Request handler failed: {}", e))),
+        Err(_) => {
+            eprintln!("Request to {} timed out after {:?}, letting it finish in the background", path, timeout);
+            let response = HttpResponse::GatewayTimeout()
+                .content_type("application/json")
+                .json(json!({
+                    "error": format!("Request took longer than {} seconds, try again shortly", timeout.as_secs())
+                }));
+            Ok(ServiceResponse::new(request, response).map_into_right_body())
+        },
+    }
+}
+
+async fn get_recommendations(
+    prefs: web::Json<UserPreferences>,
+    query: web::Query<DebugQuery>,
+    service: web::Data<ContentService>,
+) -> HttpResponse {
+    println!("Received recommendation request with preferences: {:?}", prefs);
+    if let Some(response) = invalid_preferences(&prefs) {
+        return response;
+    }
+    recommendations_response(&prefs, query.debug, query.format, service).await
+}
+
+// GET variant taking the preferences from a saved profile or the query string
+async fn get_query_recommendations(
+    query: web::Query<RecommendationQuery>,
+    service: web::Data<ContentService>,
+) -> HttpResponse {
+    let prefs = match &query.profile {
+        Some(name) => match service.get_profile(name) {
+            Some(prefs) => {
+                println!("Received recommendation request for profile {}", name);
+                prefs
+            },
+            None => return HttpResponse::NotFound()
+                .content_type("application/json")
+                .json(json!({
+                    "error": format!("No profile named {}", name)
+                })),
+        },
+        None if query.genres.is_empty() => return HttpResponse::BadRequest()
+            .content_type("application/json")
+            .json(json!({
+                "error": "Either profile or genres is required"
+            })),
+        None => {
+            let prefs = query.preferences();
+            println!("Received recommendation request with preferences: {:?}", prefs);
+            prefs
+        },
+    };
+    if let Some(response) = invalid_preferences(&prefs) {
+        return response;
+    }
+
+    if let Some(max_age_hours) = query.max_age_hours {
+        if let Err(e) = service.refresh_if_older_than(max_age_hours).await {
+            eprintln!("Error refreshing content for request: {}", e);
+        }
+    }
+    recommendations_response(&prefs, query.debug, query.format, service).await
+}
+
+async fn get_recommendation_rows(
+    query: web::Query<RecommendationQuery>,
+    service: web::Data<ContentService>,
+) -> HttpResponse {
+    let prefs = match &query.profile {
+        Some(name) => match service.get_profile(name) {
+            Some(prefs) => prefs,
+            None => return HttpResponse::NotFound()
+                .content_type("application/json")
+                .json(json!({
+                    "error": format!("No profile named {}", name)
+                })),
+        },
+        None => query.preferences(),
+    };
+    if let Some(response) = invalid_preferences(&prefs) {
+        return response;
+    }
+
+    if prefs.favorite_genres.is_empty() {
+        return HttpResponse::BadRequest()
+            .content_type("application/json")
+            .json(json!({
+                "error": "At least one genre is required to build rows"
+            }));
+    }
+
+    let per_row = query.per_row.unwrap_or(DEFAULT_ROW_SIZE).clamp(1, MAX_ROW_SIZE);
+    println!("Building {} recommendation rows of up to {} items", prefs.favorite_genres.len(), per_row);
+
+    let mut rows = service.recommendation_rows(&prefs, per_row, query.allow_duplicates);
+    for row in rows.values_mut() {
+        prefs.display_titles(row);
+    }
+
+    HttpResponse::Ok()
+        .content_type("application/json")
+        .insert_header(("Cache-Control", "no-store"))
+        .json(rows)
+}
+
+// Catalog items fetched after ?since=, for clients syncing a local copy. Every refresh
+// re-fetches the whole catalog, so an item still listed upstream comes back with a
+// newer fetched_at after each refresh, not only when first added. Items dropped from
+// the catalog (no longer listed, or pruned by MAX_ITEM_AGE_DAYS or MAX_CACHE_ITEMS)
+// never appear here, so clients should resync fully now and then to drop them.
+async fn get_new_recommendations(
+    query: web::Query<NewSinceQuery>,
+    service: web::Data<ContentService>,
+) -> HttpResponse {
+    let content: Vec<Content> = {
+        let cache = service.cache.read();
+        cache.data.get("latest").into_iter().flatten()
+            .filter(|c| c.fetched_at.map_or(false, |fetched| fetched > query.since))
+            .filter(|c| c.media_type.is_empty() || service.scrapes(&c.media_type))
+            .cloned()
+            .collect()
+    };
+    println!("Returning {} items fetched since {}", content.len(), query.since);
+
+    HttpResponse::Ok()
+        .content_type("application/json")
+        .json(content)
+}
+
+async fn get_recommendation_page(
+    query: web::Query<RecommendationQuery>,
+    service: web::Data<ContentService>,
+) -> HttpResponse {
+    let prefs = match &query.profile {
+        Some(name) => match service.get_profile(name) {
+            Some(prefs) => prefs,
+            None => return HttpResponse::NotFound()
+                .content_type("application/json")
+                .json(json!({
+                    "error": format!("No profile named {}", name)
+                })),
+        },
+        None if query.genres.is_empty() => return HttpResponse::BadRequest()
+            .content_type("application/json")
+            .json(json!({
+                "error": "Either profile or genres is required"
+            })),
+        None => query.preferences(),
+    };
+    if let Some(response) = invalid_preferences(&prefs) {
+        return response;
+    }
+
+    let page = query.page.unwrap_or(1).max(1);
+    let page_size = match query.page_size {
+        Some(size) if size > service.max_page_size && service.reject_oversized_pages => {
+            return HttpResponse::BadRequest()
+                .content_type("application/json")
+                .json(json!({
+                    "error": format!("page_size must be at most {}", service.max_page_size)
+                }));
+        },
+        Some(size) => size.clamp(1, service.max_page_size),
+        None => DEFAULT_PAGE_SIZE.min(service.max_page_size),
+    };
+    let seed = query.seed.unwrap_or(DETERMINISTIC_SEED);
+
+    match service.recommendation_page(&prefs, seed, page, page_size) {
+        Ok(mut page) => {
+            prefs.display_titles(&mut page.items);
+            let [cache_control, expires] = cache_headers(service.cache.read().last_updated);
+            HttpResponse::Ok()
+                .content_type("application/json")
+                .insert_header(cache_control)
+                .insert_header(expires)
+                .json(page)
+        },
+        Err(e) => {
+            eprintln!("Error getting recommendation page: {}", e);
+            HttpResponse::InternalServerError()
+                .content_type("application/json")
+                .json(json!({
+                    "error": format!("Failed to get recommendation page: {}", e)
+                }))
+        }
+    }
+}
+
+async fn get_batch_recommendations(
+    request: web::Json<BatchRequest>,
+    service: web::Data<ContentService>,
+) -> HttpResponse {
+    if request.profiles.is_empty() || request.profiles.len() > MAX_BATCH_PROFILES {
+        return HttpResponse::BadRequest()
+            .content_type("application/json")
+            .json(json!({
+                "error": format!("Between 1 and {} profiles are required", MAX_BATCH_PROFILES)
+            }));
+    }
+
+    let mut profiles = Vec::new();
+    for name in &request.profiles {
+        match service.get_profile(name) {
+            Some(prefs) => profiles.push((name.clone(), prefs)),
+            None => return HttpResponse::NotFound()
+                .content_type("application/json")
+                .json(json!({
+                    "error": format!("No profile named {}", name)
+                })),
+        }
+    }
+    println!("Received batch recommendation request for {} profiles (shared_dedup: {})",
+             profiles.len(), request.shared_dedup);
+
+    match service.batch_recommendations(&profiles, request.shared_dedup) {
+        Ok(results) => {
+            let results: Vec<Value> = results.into_iter()
+                .zip(&profiles)
+                .map(|((name, mut recommendations), (_, prefs))| {
+                    prefs.display_titles(&mut recommendations.items);
+                    json!({
+                        "profile": name,
+                        "items": recommendations.items,
+                        "total_matching": recommendations.total_matching,
+                        "total_unseen": recommendations.total_unseen,
+                    })
+                })
+                .collect();
+
+            HttpResponse::Ok()
+                .content_type("application/json")
+                .insert_header(("Cache-Control", "no-store"))
+                .json(json!({
+                    "results": results,
+                    "shared_dedup": request.shared_dedup,
+                }))
+        },
+        Err(e) => {
+            eprintln!("Error getting batch recommendations: {}", e);
+            HttpResponse::InternalServerError()
+                .content_type("application/json")
+                .json(json!({
+                    "error": format!("Failed to get batch recommendations: {}", e)
+                }))
+        }
+    }
+}
+
+async fn recommendations_response(
+    prefs: &UserPreferences,
+    debug: bool,
+    format: ResponseFormat,
+    service: web::Data<ContentService>,
+) -> HttpResponse {
+    match service.get_recommendations(prefs, debug).await {
+        Ok(mut content) => {
+            prefs.display_titles(&mut content.items);
+            println!("Returning {} of {} matching recommendations to frontend", content.items.len(), content.total_matching);
+            if content.stale {
+                trigger_background_refresh(service.clone());
+            }
+            // Don't save to blob here since we already did in get_recommendations
+            // Each call marks items as used, so the response must never be reused
+            match format {
+                ResponseFormat::Json => HttpResponse::Ok()
+                    .content_type("application/json")
+                    .insert_header(("Cache-Control", "no-store"))
+                    .json(content),
+                ResponseFormat::Array => HttpResponse::Ok()
+                    .content_type("application/json")
+                    .insert_header(("Cache-Control", "no-store"))
+                    .json(content.items),
+                ResponseFormat::Ndjson => match ndjson_lines(&content.items) {
+                    Ok(body) => HttpResponse::Ok()
+                        .content_type("application/x-ndjson")
+                        .insert_header(("Cache-Control", "no-store"))
+                        .body(body),
+                    Err(e) => {
+                        eprintln!("Error serializing recommendations: {}", e);
+                        HttpResponse::InternalServerError()
+                            .content_type("application/json")
+                            .json(json!({
+                                "error": format!("Failed to serialize recommendations: {}", e)
+                            }))
+                    }
+                },
+            }
+        },
+        Err(e) if e.is::<CatalogNotReady>() => HttpResponse::ServiceUnavailable()
+            .insert_header(("Retry-After", "30"))
+            .content_type("application/json")
+            .json(json!({
+                "error": "Content is still loading, try again shortly"
+            })),
+        Err(e) if e.is::<CatalogTooStale>() => {
+            let age_hours = e.downcast_ref::<CatalogTooStale>().map_or(0, |e| e.age_hours);
+            eprintln!("Refusing recommendations from a catalog {} hours old", age_hours);
+            trigger_background_refresh(service.clone());
+            HttpResponse::ServiceUnavailable()
+                .content_type("application/json")
+                .json(json!({
+                    "error": format!("Content is {} hours out of date and could not be refreshed", age_hours),
+                    "cache_age_hours": age_hours,
+                }))
+        },
+        Err(e) => {
+            eprintln!("Error getting recommendations: {}", e);
+            HttpResponse::InternalServerError()
+                .content_type("application/json")
+                .json(json!({
+                    "error": format!("Failed to get recommendations: {}", e)
+                }))
+        }
+    }
+}
+
+async fn list_profiles(service: web::Data<ContentService>) -> HttpResponse {
+    HttpResponse::Ok()
+        .content_type("application/json")
+        .json(json!({
+            "profiles": service.profile_names(),
+        }))
+}
+
+async fn delete_profile(
+    name: web::Path<String>,
+    service: web::Data<ContentService>,
+) -> HttpResponse {
+    println!("Deleting profile {}", name);
+
+    match service.delete_profile(&name).await {
+        Ok(true) => HttpResponse::NoContent().finish(),
+        Ok(false) => HttpResponse::NotFound()
+            .content_type("application/json")
+            .json(json!({
+                "error": format!("No profile named {}", name)
+            })),
+        Err(e) => {
+            eprintln!("Error deleting profile: {}", e);
+            HttpResponse::InternalServerError()
+                .content_type("application/json")
+                .json(json!({
+                    "error": format!("Failed to delete profile: {}", e)
+                }))
+        }
+    }
+}
+
+async fn save_profile(
+    name: web::Path<String>,
+    prefs: web::Json<UserPreferences>,
+    service: web::Data<ContentService>,
+) -> HttpResponse {
+    if !valid_profile_name(&name) {
+        return HttpResponse::BadRequest()
+            .content_type("application/json")
+            .json(json!({
+                "error": "Profile names must be 1-64 letters, digits, '-' or '_'"
+            }));
+    }
+
+    if let Some(response) = invalid_preferences(&prefs) {
+        return response;
+    }
+
+    println!("Saving profile {}", name);
+    let prefs = prefs.into_inner();
+
+    match service.save_profile(&name, prefs.clone()).await {
+        Ok(false) => HttpResponse::Conflict()
+            .content_type("application/json")
+            .json(json!({
+                "error": format!("Profile limit of {} reached", service.max_profiles)
+            })),
+        Ok(true) => HttpResponse::Ok()
+            .content_type("application/json")
+            .json(json!({
+                "name": name.as_str(),
+                "preferences": prefs,
+            })),
+        Err(e) => {
+            eprintln!("Error saving profile: {}", e);
+            HttpResponse::InternalServerError()
+                .content_type("application/json")
+                .json(json!({
+                    "error": format!("Failed to save profile: {}", e)
+                }))
+        }
+    }
+}
+
+fn request_language(req: &HttpRequest, service: &ContentService) -> String {
+    let header = req.headers()
+        .get("Accept-Language")
+        .and_then(|h| h.to_str().ok());
+    preferred_language(header, &service.language)
+}
+
+async fn trending(
+    query: web::Query<TrendingQuery>,
+    service: web::Data<ContentService>,
+) -> HttpResponse {
+    let valid_type = query.media_type == "movie" || query.media_type == "tv";
+    let valid_window = query.window == "day" || query.window == "week";
+    if !valid_type || !valid_window {
+        return HttpResponse::BadRequest()
+            .content_type("application/json")
+            .json(json!({
+                "error": "type must be movie or tv and window must be day or week"
+            }));
+    }
+
+    match service.trending(&query.media_type, &query.window).await {
+        Ok(content) => HttpResponse::Ok()
+            .content_type("application/json")
+            .json(content),
+        Err(e) => {
+            eprintln!("Error getting trending content: {}", e);
+            HttpResponse::InternalServerError()
+                .content_type("application/json")
+                .json(json!({
+                    "error": format!("Failed to get trending content: {}", e)
+                }))
+        }
+    }
+}
+
+async fn search(
+    req: HttpRequest,
+    query: web::Query<SearchQuery>,
+    service: web::Data<ContentService>,
+) -> HttpResponse {
+    if query.media_type != "movie" && query.media_type != "tv" {
+        return HttpResponse::BadRequest()
+            .content_type("application/json")
+            .json(json!({
+                "error": format!("Unknown media type: {}", query.media_type)
+            }));
+    }
+
+    let region = match query.region.as_deref().map(|r| (r, normalize_region(r))) {
+        Some((_, Some(region))) => Some(region),
+        Some((value, None)) => {
+            return HttpResponse::BadRequest()
+                .content_type("application/json")
+                .json(json!({
+                    "error": format!("Unknown region: {}, expected an ISO 3166-1 alpha-2 code like US or GB", value)
+                }));
+        },
+        None => None,
+    };
+
+    let language = request_language(&req, &service);
+    println!("Searching {} for '{}' in {}", query.media_type, query.query, language);
+
+    match service.search(&query.media_type, &query.query, &language, region.as_deref()).await {
+        Ok(content) => HttpResponse::Ok()
+            .content_type("application/json")
+            .json(content),
+        Err(e) => {
+            eprintln!("Error searching: {}", e);
+            HttpResponse::InternalServerError()
+                .content_type("application/json")
+                .json(json!({
+                    "error": format!("Failed to search: {}", e)
+                }))
+        }
+    }
+}
+
+async fn similar(
+    req: HttpRequest,
+    path: web::Path<(String, i64)>,
+    service: web::Data<ContentService>,
+) -> HttpResponse {
+    let (media_type, id) = path.into_inner();
+    if media_type != "movie" && media_type != "tv" {
+        return HttpResponse::BadRequest()
+            .content_type("application/json")
+            .json(json!({
+                "error": format!("Unknown media type: {}", media_type)
+            }));
+    }
+
+    let language = request_language(&req, &service);
+    println!("Fetching titles similar to {} {} in {}", media_type, id, language);
+
+    match service.similar(&media_type, id, &language).await {
+        Ok(content) => HttpResponse::Ok()
+            .content_type("application/json")
+            .json(content),
+        Err(e) => {
+            eprintln!("Error fetching similar titles: {}", e);
+            HttpResponse::InternalServerError()
+                .content_type("application/json")
+                .json(json!({
+                    "error": format!("Failed to fetch similar titles: {}", e)
+                }))
+        }
+    }
+}
+
+async fn get_genres(service: web::Data<ContentService>) -> HttpResponse {
+    let (genres, last_updated) = {
+        let cache = service.cache.read();
+        (cache.tally(|c| c.genre.clone()), cache.last_updated)
+    };
+    let [cache_control, expires] = cache_headers(last_updated);
+
+    HttpResponse::Ok()
+        .content_type("application/json")
+        .insert_header(cache_control)
+        .insert_header(expires)
+        .json(genres.into_iter()
+            .map(|(name, count)| json!({ "name": name, "count": count }))
+            .collect::<Vec<_>>())
+}
+
+async fn get_providers(service: web::Data<ContentService>) -> HttpResponse {
+    let (providers, last_updated) = {
+        let cache = service.cache.read();
+        (cache.tally(|c| c.where_to_watch.clone()), cache.last_updated)
+    };
+    let [cache_control, expires] = cache_headers(last_updated);
+
+    HttpResponse::Ok()
+        .content_type("application/json")
+        .insert_header(cache_control)
+        .insert_header(expires)
+        .json(providers.into_iter()
+            .map(|(name, count)| json!({ "name": name, "count": count }))
+            .collect::<Vec<_>>())
+}
+
+// Rows per chunk of the CSV export stream
+const EXPORT_CHUNK_ROWS: usize = 500;
+
+// CSV for a run of catalog items, with the header row when it's the first chunk
+fn csv_chunk(items: &[Content], header: bool) -> Result<web::Bytes> {
+    let mut writer = csv::WriterBuilder::new().has_headers(false).from_writer(Vec::new());
+    if header {
+        writer.write_record(["title", "year", "rating", "genres", "providers", "media_type", "tmdb_id", "imdb_id"])?;
+    }
+    for item in items {
+        writer.write_record([
+            item.title.clone(),
+            item.year.clone().unwrap_or_default(),
+            item.rating.map(|r| r.to_string()).unwrap_or_default(),
+            item.genre.join("|"),
+            item.where_to_watch.join("|"),
+            item.media_type.clone(),
+            item.tmdb_id.to_string(),
+            item.imdb_id.clone().unwrap_or_default(),
+        ])?;
+    }
+    Ok(web::Bytes::from(writer.into_inner()?))
+}
+
+// The whole catalog as a CSV download, written out in chunks
+async fn export_csv(service: web::Data<ContentService>) -> HttpResponse {
+    let content = service.cache.read().data.get("latest").cloned().unwrap_or_default();
+    println!("Exporting {} items as CSV", content.len());
+
+    let chunk_count = content.len().div_ceil(EXPORT_CHUNK_ROWS).max(1);
+    let chunks = futures_util::stream::iter(0..chunk_count).map(move |i| {
+        let start = (i * EXPORT_CHUNK_ROWS).min(content.len());
+        let end = (start + EXPORT_CHUNK_ROWS).min(content.len());
+        csv_chunk(&content[start..end], i == 0)
+            .map_err(|e| actix_web::error::ErrorInternalServerError(e.to_string()))
+    });
+
+    HttpResponse::Ok()
+        .content_type("text/csv; charset=utf-8")
+        .insert_header(("Content-Disposition", "attachment; filename=\"catalog.csv\""))
+        .streaming(chunks)
+}
+
+async fn health(service: web::Data<ContentService>) -> HttpResponse {
+    let (items, last_updated) = {
+        let cache = service.cache.read();
+        (cache.data.get("latest").map_or(0, |c| c.len()), cache.last_updated)
+    };
+
+    HttpResponse::Ok()
+        .content_type("application/json")
+        .insert_header(("Cache-Control", "no-store"))
+        .json(json!({
+            "status": "ok",
+            "ready": service.ready.load(std::sync::atomic::Ordering::SeqCst),
+            "items": items,
+            "last_updated": last_updated,
+            "last_refresh_success": *service.last_refresh_success.read(),
+            "refresh_in_progress": service.refresh_in_progress.load(std::sync::atomic::Ordering::SeqCst),
+            "refresh_task_restarts": service.refresh_task_restarts.load(std::sync::atomic::Ordering::SeqCst),
+        }))
+}
+
+// Readiness probe: 503 until the catalog has first been populated
+async fn ready(service: web::Data<ContentService>) -> HttpResponse {
+    if service.ready.load(std::sync::atomic::Ordering::SeqCst) {
+        HttpResponse::Ok()
+            .content_type("application/json")
+            .json(json!({ "ready": true }))
+    } else {
+        HttpResponse::ServiceUnavailable()
+            .content_type("application/json")
+            .json(json!({ "ready": false }))
+    }
+}
+
+async fn version() -> HttpResponse {
+    HttpResponse::Ok()
+        .content_type("application/json")
+        .json(json!({
+            "version": env!("CARGO_PKG_VERSION"),
+            // Set with GIT_COMMIT at build time, e.g. docker build --build-arg GIT_COMMIT=$(git rev-parse HEAD)
+            "git_commit": option_env!("GIT_COMMIT").unwrap_or("unknown"),
+            "schema_version": CACHE_SCHEMA_VERSION,
+            "tmdb_api_version": TMDB_API_VERSION,
+        }))
+}
+
+// One open /ws socket, counted against MAX_WS_CONNECTIONS until dropped
+struct WsSlot(web::Data<ContentService>);
+
+impl WsSlot {
+    fn acquire(service: &web::Data<ContentService>) -> Option<Self> {
+        use std::sync::atomic::Ordering;
+
+        service.ws_connections
+            .fetch_update(Ordering::SeqCst, Ordering::SeqCst, |open| {
+                (open < service.max_ws_connections).then_some(open + 1)
+            })
+            .ok()
+            .map(|_| WsSlot(service.clone()))
+    }
+}
+
+impl Drop for WsSlot {
+    fn drop(&mut self) {
+        self.0.ws_connections.fetch_sub(1, std::sync::atomic::Ordering::SeqCst);
+    }
+}
+
+// Websocket that pushes each CatalogEvent as JSON, e.g.
+// {"event":"refreshed","last_updated":"...","items":1234}, so a UI can offer
+// "new recommendations available" without polling. Client messages other than
+// ping and close are ignored.
+async fn catalog_events_ws(
+    req: HttpRequest,
+    body: web::Payload,
+    service: web::Data<ContentService>,
+) -> HttpResponse {
+    let Some(slot) = WsSlot::acquire(&service) else {
+        return HttpResponse::ServiceUnavailable()
+            .content_type("application/json")
+            .json(json!({
+                "error": format!("Too many open websockets (limit {})", service.max_ws_connections)
+            }));
+    };
+
+    let (response, mut session, mut messages) = match actix_ws::handle(&req, body) {
+        Ok(handshake) => handshake,
+        Err(e) => return e.error_response(),
+    };
+    let mut events = service.events.subscribe();
+
+    actix_web::rt::spawn(async move {
+        let _slot = slot;
+        loop {
+            tokio::select! {
+                event = events.recv() => match event {
+                    Ok(event) => {
+                        let text = match serde_json::to_string(&event) {
+                            Ok(text) => text,
+                            Err(e) => {
+                                eprintln!("Error serializing catalog event: {}", e);
+                                continue;
+                            }
+                        };
+                        if session.text(text).await.is_err() {
+                            return;
+                        }
+                    },
+                    // A missed refresh is superseded by the next one
+                    Err(tokio::sync::broadcast::error::RecvError::Lagged(_)) => continue,
+                    Err(tokio::sync::broadcast::error::RecvError::Closed) => break,
+                },
+                message = messages.recv() => match message {
+                    Some(Ok(actix_ws::Message::Ping(bytes))) => {
+                        if session.pong(&bytes).await.is_err() {
+                            return;
+                        }
+                    },
+                    Some(Ok(actix_ws::Message::Close(reason))) => {
+                        let _ = session.close(reason).await;
+                        return;
+                    },
+                    Some(Ok(_)) => {},
+                    Some(Err(_)) | None => return,
+                },
+            }
+        }
+        let _ = session.close(None).await;
+    });
+
+    response
+}
+
+async fn admin_rebuild(
+    req: HttpRequest,
+    service: web::Data<ContentService>,
+) -> HttpResponse {
+    if !service.is_admin(&req) {
+        return HttpResponse::Unauthorized()
+            .content_type("application/json")
+            .json(json!({
+                "error": "Missing or invalid admin token"
+            }));
+    }
+
+    println!("Received admin rebuild request");
+
+    match service.rebuild_blob().await {
+        Ok(item_count) => HttpResponse::Ok()
+            .content_type("application/json")
+            .json(json!({
+                "items": item_count,
+                "schema_version": CACHE_SCHEMA_VERSION,
+            })),
+        Err(e) => {
+            eprintln!("Error rebuilding blob: {}", e);
+            HttpResponse::InternalServerError()
+                .content_type("application/json")
+                .json(json!({
+                    "error": format!("Failed to rebuild blob: {}", e)
+                }))
+        }
+    }
+}
+
+async fn admin_add_pin(
+    req: HttpRequest,
+    entry: web::Json<PinRequest>,
+    service: web::Data<ContentService>,
+) -> HttpResponse {
+    if !service.is_admin(&req) {
+        return HttpResponse::Unauthorized()
+            .content_type("application/json")
+            .json(json!({
+                "error": "Missing or invalid admin token"
+            }));
+    }
+
+    if entry.media_type != "movie" && entry.media_type != "tv" {
+        return HttpResponse::BadRequest()
+            .content_type("application/json")
+            .json(json!({
+                "error": format!("Unknown media type: {}", entry.media_type)
+            }));
+    }
+
+    println!("Pinning {} {}", entry.media_type, entry.tmdb_id);
+
+    match service.add_pin(&entry.media_type, entry.tmdb_id).await {
+        Ok(pins) => HttpResponse::Ok()
+            .content_type("application/json")
+            .json(json!({ "pins": pins })),
+        Err(e) => {
+            eprintln!("Error saving pin: {}", e);
+            HttpResponse::InternalServerError()
+                .content_type("application/json")
+                .json(json!({
+                    "error": format!("Failed to save pin: {}", e)
+                }))
+        }
+    }
+}
+
+async fn admin_remove_pin(
+    req: HttpRequest,
+    path: web::Path<(String, i64)>,
+    service: web::Data<ContentService>,
+) -> HttpResponse {
+    if !service.is_admin(&req) {
+        return HttpResponse::Unauthorized()
+            .content_type("application/json")
+            .json(json!({
+                "error": "Missing or invalid admin token"
+            }));
+    }
+
+    let (media_type, tmdb_id) = path.into_inner();
+    println!("Unpinning {} {}", media_type, tmdb_id);
+
+    match service.remove_pin(&media_type, tmdb_id).await {
+        Ok(Some(pins)) => HttpResponse::Ok()
+            .content_type("application/json")
+            .json(json!({ "pins": pins })),
+        Ok(None) => HttpResponse::NotFound()
+            .content_type("application/json")
+            .json(json!({
+                "error": format!("{} {} is not pinned", media_type, tmdb_id)
+            })),
+        Err(e) => {
+            eprintln!("Error removing pin: {}", e);
+            HttpResponse::InternalServerError()
+                .content_type("application/json")
+                .json(json!({
+                    "error": format!("Failed to remove pin: {}", e)
+                }))
+        }
+    }
+}
+
+// Dedup state for one user key, for looking into "I keep seeing repeats" reports.
+// reset_imminent is whether their next request will clear the used set, judged
+// from what their last request left unseen; it's null when there has been no
+// request since the server started.
+async fn admin_used(
+    req: HttpRequest,
+    user_key: web::Path<String>,
+    service: web::Data<ContentService>,
+) -> HttpResponse {
+    if !service.is_admin(&req) {
+        return HttpResponse::Unauthorized()
+            .content_type("application/json")
+            .json(json!({
+                "error": "Missing or invalid admin token"
+            }));
+    }
+
+    let cache = service.cache.read();
+    let Some(used) = cache.used_recommendations.get(user_key.as_str()) else {
+        return HttpResponse::NotFound()
+            .content_type("application/json")
+            .json(json!({
+                "error": format!("No used recommendations for {}", user_key)
+            }));
+    };
+
+    let mut titles: Vec<&String> = used.iter().collect();
+    titles.sort();
+    titles.truncate(USED_SAMPLE_SIZE);
+    let sample: Vec<Value> = titles.into_iter()
+        .map(|title| {
+            let ids: Vec<&str> = cache.data.get("latest").into_iter().flatten()
+                .filter(|c| &c.title == title)
+                .map(|c| c.id.as_str())
+                .collect();
+            json!({ "title": title, "ids": ids })
+        })
+        .collect();
+    let unseen_remaining = cache.unseen_remaining.get(user_key.as_str()).copied();
+
+    HttpResponse::Ok()
+        .content_type("application/json")
+        .json(json!({
+            "user_key": user_key.as_str(),
+            "count": used.len(),
+            "sample": sample,
+            "reset_threshold": USED_RESET_THRESHOLD,
+            "unseen_remaining": unseen_remaining,
+            "reset_imminent": unseen_remaining.map(|left| left < USED_RESET_THRESHOLD),
+        }))
+}
+
+async fn tmdb_recommendations(
+    req: HttpRequest,
+    path: web::Path<(String, i64)>,
+    service: web::Data<ContentService>,
+) -> HttpResponse {
+    let (media_type, id) = path.into_inner();
+    if media_type != "movie" && media_type != "tv" {
+        return HttpResponse::BadRequest()
+            .content_type("application/json")
+            .json(json!({
+                "error": format!("Unknown media type: {}", media_type)
+            }));
+    }
+
+    let language = request_language(&req, &service);
+    println!("Fetching TMDB recommendations for {} {} in {}", media_type, id, language);
+
+    match service.tmdb_recommendations(&media_type, id, &language).await {
+        Ok(content) => HttpResponse::Ok()
+            .content_type("application/json")
+            .json(content),
+        Err(e) => {
+            eprintln!("Error fetching TMDB recommendations: {}", e);
+            HttpResponse::InternalServerError()
+                .content_type("application/json")
+                .json(json!({
+                    "error": format!("Failed to fetch TMDB recommendations: {}", e)
+                }))
+        }
+    }
+}
+
+async fn get_watchlist_recommendations(
+    req: HttpRequest,
+    user_id: web::Path<String>,
+    service: web::Data<ContentService>,
+) -> HttpResponse {
+    let language = request_language(&req, &service);
+
+    match service.watchlist_recommendations(&user_id, &language).await {
+        Ok(rows) => HttpResponse::Ok()
+            .content_type("application/json")
+            .json(json!({
+                "user_id": user_id.as_str(),
+                "rows": rows.into_iter()
+                    .map(|(because, items)| json!({ "because": because, "items": items }))
+                    .collect::<Vec<_>>(),
+            })),
+        Err(e) => {
+            eprintln!("Error building watchlist recommendations: {}", e);
+            HttpResponse::InternalServerError()
+                .content_type("application/json")
+                .json(json!({
+                    "error": format!("Failed to build watchlist recommendations: {}", e)
+                }))
+        }
+    }
+}
+
+async fn get_watchlist(
+    user_id: web::Path<String>,
+    service: web::Data<ContentService>,
+) -> HttpResponse {
+    HttpResponse::Ok()
+        .content_type("application/json")
+        .json(json!({
+            "user_id": user_id.as_str(),
+            "ids": service.get_watchlist(&user_id),
+        }))
+}
+
+async fn add_to_watchlist(
+    user_id: web::Path<String>,
+    entry: web::Json<WatchlistEntry>,
+    service: web::Data<ContentService>,
+) -> HttpResponse {
+    println!("Adding {} {} to watchlist for {}", entry.media_type, entry.tmdb_id, user_id);
+
+    if entry.media_type != "movie" && entry.media_type != "tv" {
+        return HttpResponse::BadRequest()
+            .content_type("application/json")
+            .json(json!({
+                "error": format!("Unknown media type: {}", entry.media_type)
+            }));
+    }
+
+    match service.add_to_watchlist(&user_id, &entry.media_type, entry.tmdb_id).await {
+        Ok(()) => HttpResponse::Ok()
+            .content_type("application/json")
+            .json(json!({
+                "user_id": user_id.as_str(),
+                "ids": service.get_watchlist(&user_id),
+            })),
+        Err(e) => {
+            eprintln!("Error saving watchlist: {}", e);
+            HttpResponse::InternalServerError()
+                .content_type("application/json")
+                .json(json!({
+                    "error": format!("Failed to save watchlist: {}", e)
+                }))
+        }
+    }
+}
+
+async fn get_watched(
+    user_id: web::Path<String>,
+    service: web::Data<ContentService>,
+) -> HttpResponse {
+    HttpResponse::Ok()
+        .content_type("application/json")
+        .json(json!({
+            "user_id": user_id.as_str(),
+            "ids": service.get_watched(&user_id),
+        }))
+}
+
+async fn mark_watched(
+    entry: web::Json<WatchedEntry>,
+    service: web::Data<ContentService>,
+) -> HttpResponse {
+    if entry.user_id.is_empty() {
+        return HttpResponse::BadRequest()
+            .content_type("application/json")
+            .json(json!({
+                "error": "user_id is required"
+            }));
+    }
+    if entry.media_type != "movie" && entry.media_type != "tv" {
+        return HttpResponse::BadRequest()
+            .content_type("application/json")
+            .json(json!({
+                "error": format!("Unknown media type: {}", entry.media_type)
+            }));
+    }
+
+    println!("Marking {} {} watched for {}", entry.media_type, entry.tmdb_id, entry.user_id);
+
+    match service.set_watched(&entry.user_id, &entry.media_type, entry.tmdb_id, true).await {
+        Ok(_) => HttpResponse::Ok()
+            .content_type("application/json")
+            .json(json!({
+                "user_id": entry.user_id,
+                "ids": service.get_watched(&entry.user_id),
+            })),
+        Err(e) => {
+            eprintln!("Error saving watched items: {}", e);
+            HttpResponse::InternalServerError()
+                .content_type("application/json")
+                .json(json!({
+                    "error": format!("Failed to save watched items: {}", e)
+                }))
+        }
+    }
+}
+
+async fn unmark_watched(
+    path: web::Path<(String, String, i64)>,
+    service: web::Data<ContentService>,
+) -> HttpResponse {
+    let (user_id, media_type, tmdb_id) = path.into_inner();
+    println!("Unmarking {} {} watched for {}", media_type, tmdb_id, user_id);
+
+    match service.set_watched(&user_id, &media_type, tmdb_id, false).await {
+        Ok(false) => HttpResponse::NotFound()
+            .content_type("application/json")
+            .json(json!({
+                "error": format!("{} {} is not marked watched for {}", media_type, tmdb_id, user_id)
+            })),
+        Ok(true) => HttpResponse::Ok()
+            .content_type("application/json")
+            .json(json!({
+                "user_id": user_id,
+                "ids": service.get_watched(&user_id),
+            })),
+        Err(e) => {
+            eprintln!("Error saving watched items: {}", e);
+            HttpResponse::InternalServerError()
+                .content_type("application/json")
+                .json(json!({
+                    "error": format!("Failed to save watched items: {}", e)
+                }))
+        }
+    }
+}
+
+async fn pick(
+    request: web::Json<PickRequest>,
+    service: web::Data<ContentService>,
+) -> HttpResponse {
+    println!("Received pick request with preferences: {:?}", request.preferences);
+    if let Some(response) = invalid_preferences(&request.preferences) {
+        return response;
+    }
+
+    match service.pick(&request.preferences, request.seed) {
+        Some(content) => HttpResponse::Ok()
+            .content_type("application/json")
+            .insert_header(("Cache-Control", "no-store"))
+            .json(content),
+        None => HttpResponse::NotFound()
+            .content_type("application/json")
+            .json(json!({
+                "error": "No content matched preferences"
+            })),
+    }
+}
+
+async fn explain(
+    request: web::Json<ExplainRequest>,
+    service: web::Data<ContentService>,
+) -> HttpResponse {
+    println!("Received explain request for tmdb_id {}", request.tmdb_id);
+    if let Some(response) = invalid_preferences(&request.preferences) {
+        return response;
+    }
+
+    let content = {
+        let cache = service.cache.read();
+        match &request.media_type {
+            Some(media_type) => cache.find(media_type, request.tmdb_id).cloned(),
+            None => cache.find("movie", request.tmdb_id)
+                .or_else(|| cache.find("tv", request.tmdb_id))
+                .cloned(),
+        }
+    };
+
+    match content {
+        Some(content) => HttpResponse::Ok()
+            .content_type("application/json")
+            .json(json!({
+                "content": content,
+                "score": score_content(&content, &request.preferences, &request.preferences.favorite_genre_set()),
+            })),
+        None => HttpResponse::NotFound()
+            .content_type("application/json")
+            .json(json!({
+                "error": format!("No content found with tmdb_id {}", request.tmdb_id)
+            })),
+    }
+}
+
+async fn hydrate(
+    request: web::Json<HydrateRequest>,
+    service: web::Data<ContentService>,
+) -> HttpResponse {
+    println!("Received hydrate request for {} ids", request.ids.len());
+
+    match service.hydrate(&request.ids).await {
+        Ok(content) => HttpResponse::Ok()
+            .content_type("application/json")
+            .json(content),
+        Err(e) => {
+            eprintln!("Error hydrating content: {}", e);
+            HttpResponse::InternalServerError()
+                .content_type("application/json")
+                .json(json!({
+                    "error": format!("Failed to hydrate content: {}", e)
+                }))
+        }
+    }
+}
+
+// Malformed query strings get a 400 with the same JSON error shape as everything else
+fn query_config() -> web::QueryConfig {
+    web::QueryConfig::default().error_handler(|err, _req| {
+        let response = HttpResponse::BadRequest()
+            .content_type("application/json")
+            .json(json!({
+                "error": format!("Invalid query string: {}", err)
+            }));
+        actix_web::error::InternalError::from_response(err, response).into()
+    })
+}
+
+// Health, readiness and version endpoints, which stay outside API_PREFIX unless PREFIX_OPS_ROUTES is set
+fn ops_routes(cfg: &mut web::ServiceConfig) {
+    cfg
+        .route("/health", web::get().to(health))
+        .route("/ready", web::get().to(ready))
+        .route("/version", web::get().to(version));
+}
+
+fn api_routes(cfg: &mut web::ServiceConfig) {
+    cfg
+        .service(
+            web::resource("/recommendations")
+                .wrap(from_fn(rate_limit))
+                .wrap(from_fn(require_ready))
+                .route(web::post().to(get_recommendations))
+                .route(web::get().to(get_query_recommendations))
+        )
+        .service(
+            web::resource("/recommendations/page")
+                .wrap(from_fn(rate_limit))
+                .wrap(from_fn(require_ready))
+                .route(web::get().to(get_recommendation_page))
+        )
+        .service(
+            web::resource("/recommendations/batch")
+                .wrap(from_fn(rate_limit))
+                .wrap(from_fn(require_ready))
+                .route(web::post().to(get_batch_recommendations))
+        )
+        .service(
+            web::resource("/recommendations/new")
+                .wrap(from_fn(rate_limit))
+                .wrap(from_fn(require_ready))
+                .route(web::get().to(get_new_recommendations))
+        )
+        .service(
+            web::resource("/recommendations/rows")
+                .wrap(from_fn(rate_limit))
+                .wrap(from_fn(require_ready))
+                .route(web::get().to(get_recommendation_rows))
+        )
+        .route("/profiles", web::get().to(list_profiles))
+        .route("/profiles/{name}", web::put().to(save_profile))
+        .route("/profiles/{name}", web::delete().to(delete_profile))
+        .route("/genres", web::get().to(get_genres))
+        .route("/export.csv", web::get().to(export_csv))
+        .route("/providers", web::get().to(get_providers))
+        .route("/trending", web::get().to(trending))
+        .route("/search", web::get().to(search))
+        .route("/similar/{media_type}/{id}", web::get().to(similar))
+        .service(
+            web::resource("/tmdb-recommendations/{media_type}/{id}")
+                .wrap(from_fn(rate_limit))
+                .route(web::get().to(tmdb_recommendations))
+        )
+        .service(
+            web::resource("/watchlist/{user_id}/recommendations")
+                .wrap(from_fn(rate_limit))
+                .route(web::get().to(get_watchlist_recommendations))
+        )
+        .route("/hydrate", web::post().to(hydrate))
+        .route("/pick", web::post().to(pick))
+        .route("/explain", web::post().to(explain))
+        .route("/watchlist/{user_id}", web::get().to(get_watchlist))
+        .route("/watchlist/{user_id}", web::post().to(add_to_watchlist))
+        .route("/watched", web::post().to(mark_watched))
+        .route("/watched/{user_id}", web::get().to(get_watched))
+        .route("/watched/{user_id}/{media_type}/{tmdb_id}", web::delete().to(unmark_watched))
+        .route("/ws", web::get().to(catalog_events_ws))
+        .route("/admin/rebuild", web::post().to(admin_rebuild))
+        .route("/admin/pin", web::post().to(admin_add_pin))
+        .route("/admin/pin/{media_type}/{id}", web::delete().to(admin_remove_pin))
+        .route("/admin/used/{user_key}", web::get().to(admin_used));
+}
+
+// Which browser origins may call the API. With CORS_ALLOWED_ORIGINS set only those
+// origins are allowed; otherwise cross-origin requests are refused unless
+// CORS_PERMISSIVE is set for local development.
+#[derive(Debug, Clone)]
+struct CorsConfig {
+    allowed_origins: Vec<String>,
+    allowed_methods: Vec<actix_web::http::Method>,
+    permissive: bool,
+    max_age: usize,
+}
+
+impl CorsConfig {
+    fn from_env() -> Result<Self> {
+        let allowed_origins = match env::var("CORS_ALLOWED_ORIGINS") {
+            Ok(value) => value.split(',')
+                .map(|origin| origin.trim())
+                .filter(|origin| !origin.is_empty())
+                .map(parse_origin)
+                .collect::<Result<Vec<_>>>()?,
+            Err(_) => Vec::new(),
+        };
+
+        let allowed_methods = match env::var("CORS_ALLOWED_METHODS") {
+            Ok(value) => value.split(',')
+                .map(|method| method.trim().to_ascii_uppercase())
+                .filter(|method| !method.is_empty())
+                .map(|method| actix_web::http::Method::from_bytes(method.as_bytes())
+                    .map_err(|_| anyhow::anyhow!("Invalid CORS_ALLOWED_METHODS entry: {}", method)))
+                .collect::<Result<Vec<_>>>()?,
+            Err(_) => ["GET", "POST", "PUT", "DELETE"].iter()
+                .map(|m| actix_web::http::Method::from_bytes(m.as_bytes()).expect("valid method"))
+                .collect(),
+        };
+
+        let max_age = match env::var("CORS_MAX_AGE") {
+            Ok(value) => value.parse::<usize>()
+                .map_err(|_| anyhow::anyhow!("CORS_MAX_AGE must be a number of seconds, got: {}", value))?,
+            Err(_) => 3600,
+        };
+
+        let permissive = env_flag("CORS_PERMISSIVE", false);
+        if !allowed_origins.is_empty() {
+            println!("Allowing CORS requests from: {}", allowed_origins.join(", "));
+            if permissive {
+                println!("CORS_PERMISSIVE is ignored because CORS_ALLOWED_ORIGINS is set");
+            }
+        } else if permissive {
+            println!("WARNING: CORS_PERMISSIVE is set, allowing requests from any origin. Don't use this in production.");
+        } else {
+            println!("No CORS_ALLOWED_ORIGINS set, cross-origin browser requests will be refused");
+        }
+
+        Ok(Self { allowed_origins, allowed_methods, permissive, max_age })
+    }
+
+    fn build(&self) -> Cors {
+        if self.allowed_origins.is_empty() && self.permissive {
+            return Cors::default()
+                .allow_any_origin()
+                .allow_any_method()
+                .allow_any_header()
+                .expose_any_header()
+                .max_age(self.max_age);
+        }
+
+        let mut cors = Cors::default()
+            .allowed_methods(self.allowed_methods.clone())
+            .allowed_headers([
+                actix_web::http::header::CONTENT_TYPE,
+                actix_web::http::header::AUTHORIZATION,
+                actix_web::http::header::ACCEPT,
+                actix_web::http::header::ACCEPT_LANGUAGE,
+            ])
+            // Let browser clients read the rate limit state
+            .expose_headers(["x-ratelimit-limit", "x-ratelimit-remaining", "x-ratelimit-reset", "retry-after"])
+            .max_age(self.max_age);
+        for origin in &self.allowed_origins {
+            cors = cors.allowed_origin(origin);
+        }
+        cors
+    }
+}
+
+// Validate an allowed origin, returning it in the form browsers send, e.g.
+// "https://example.com" or "http://localhost:5173"
+fn parse_origin(value: &str) -> Result<String> {
+    let url = Url::parse(value)
+        .map_err(|e| anyhow::anyhow!("Invalid CORS_ALLOWED_ORIGINS entry {}: {}", value, e))?;
+
+    let bare = url.path() == "/" && url.query().is_none() && url.fragment().is_none() &&
+        url.username().is_empty() && url.password().is_none();
+    if !matches!(url.scheme(), "http" | "https") || url.host_str().is_none() || !bare {
+        return Err(anyhow::anyhow!(
+            "CORS_ALLOWED_ORIGINS entries must be a scheme and host like https://example.com, got: {}", value
+        ));
+    }
+
+    Ok(url.origin().ascii_serialization())
+}
+
+#[actix_web::main]
+async fn main() -> Result<()> {
+    dotenv().ok();
+
+    // Ensure TMDB_API_KEY is set
+    if env::var("TMDB_API_KEY").is_err() {
+        eprintln!("TMDB_API_KEY must be set in environment");
+        std::process::exit(1);
+    }
+
+    let service = ContentService::new().await?;
+
+    // Fail fast on bad TMDB or storage configuration, unless STARTUP_SELFTEST=false
+    if env_flag("STARTUP_SELFTEST", true) {
+        if let Err(e) = service.self_test().await {
+            eprintln!("Startup self-test failed: {}", e);
+            std::process::exit(1);
+        }
+    }
+
+    // Start from the saved blob if there is one, so a redeploy can serve immediately
+    let loaded = match service.load_from_blob().await {
+        Ok(loaded) => loaded,
+        Err(e) => {
+            eprintln!("Error loading content from blob: {}", e);
+            false
+        }
+    };
+
+    match service.load_profiles().await {
+        Ok(count) => println!("Loaded {} saved profiles", count),
+        Err(e) => eprintln!("Error loading profiles from blob: {}", e),
+    }
+
+    let service = web::Data::new(service);
+
+    if loaded {
+        if service.cache.read().needs_update() {
+            println!("Saved content is stale, refreshing in the background");
+            trigger_background_refresh(service.clone());
+        }
+    } else {
+        // Perform initial content update
+        println!("Performing initial content update...");
+        service.update_content().await?;
+        println!("Initial content update completed");
+    }
+
+    let rate_limiter = web::Data::new(RateLimiter::from_env()?);
+    let cors_config = CorsConfig::from_env()?;
+
+    // Update content periodically
+    tokio::spawn(supervise_refresh(service.clone()));
+    if let Some(interval) = service.incremental_refresh_interval {
+        println!("Applying TMDB changes every {} minutes", interval.as_secs() / 60);
+        tokio::spawn(incremental_refresh_loop(service.clone(), interval));
+    }
+
+    // Log every catalog refresh, whichever path triggered it
+    let mut events = service.events.subscribe();
+    tokio::spawn(async move {
+        loop {
+            match events.recv().await {
+                Ok(CatalogEvent::Refreshed { last_updated, items }) => {
+                    println!("Catalog refreshed at {} with {} items", last_updated, items)
+                },
+                Ok(CatalogEvent::Updated { changed }) => {
+                    println!("Catalog updated with {} changed items", changed)
+                },
+                Err(tokio::sync::broadcast::error::RecvError::Lagged(missed)) => {
+                    eprintln!("Refresh event logger missed {} events", missed)
+                },
+                Err(tokio::sync::broadcast::error::RecvError::Closed) => return,
+            }
+        }
+    });
+
+    // Number of HTTP worker threads, defaulting to actix's one per CPU core
+    let http_workers = match env::var("HTTP_WORKERS") {
+        Ok(value) => match value.parse::<usize>() {
+            Ok(n) if n > 0 => Some(n),
+            _ => return Err(anyhow::anyhow!("HTTP_WORKERS must be a positive integer, got: {}", value)),
+        },
+        Err(_) => None,
+    };
+
+    // Base path for all routes, e.g. "/api/v1", when hosted behind shared ingress
+    let api_prefix = env::var("API_PREFIX")
+        .map(|p| format!("/{}", p.trim_matches('/')))
+        .map(|p| if p == "/" { String::new() } else { p })
+        .unwrap_or_default();
+    let prefix_ops_routes = env_flag("PREFIX_OPS_ROUTES", false);
+    if !api_prefix.is_empty() {
+        println!("Serving API under {}", api_prefix);
+    }
+
+    println!("Starting HTTP server on 0.0.0.0:8080");
+    let mut server = HttpServer::new(move || {
+        App::new()
+            .wrap(cors_config.build())
+            .app_data(service.clone())
+            .app_data(rate_limiter.clone())
+            .app_data(query_config())
+            // Registered ahead of the scope, which would otherwise 404 them when the prefix is empty
+            .configure(|cfg| if !prefix_ops_routes { ops_routes(cfg) })
+            .service(
+                web::scope(&api_prefix)
+                    .wrap(from_fn(request_timeout))
+                    .configure(|cfg| if prefix_ops_routes { ops_routes(cfg) })
+                    .configure(api_routes)
+            )
+    });
+
+    if let Some(workers) = http_workers {
+        println!("Using {} HTTP workers", workers);
+        server = server.workers(workers);
+    }
+
+    server
+        .bind("0.0.0.0:8080")?
+        .run()
+        .await?;
+    Ok(())
+}
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    // A service with the default configuration. Its blob client points at Azurite but
+    // nothing here talks to it.
+    fn test_service() -> ContentService {
+        let credentials = StorageCredentials::access_key(AZURITE_ACCOUNT_NAME, AZURITE_ACCOUNT_KEY);
+        let blob_client = BlobServiceClient::new(AZURITE_ACCOUNT_NAME, credentials).container_client("content-data");
+        ContentService::from_env("test-key".to_string(), blob_client).unwrap()
+    }
+
+    // A catalog item with only the required fields set
+    fn content(media_type: &str, tmdb_id: i64) -> Content {
+        serde_json::from_value(json!({
+            "id": content_id(media_type, tmdb_id),
+            "title": format!("{} {}", media_type, tmdb_id),
+            "genre": [],
+            "description": "",
+            "where_to_watch": [],
+            "media_type": media_type,
+            "tmdb_id": tmdb_id,
+        })).unwrap()
+    }
+
+    // Start a mock TMDB that answers every request with status and body, returning its
+    // base URL
+    fn mock_tmdb(status: u16, body: Value) -> String {
+        let server = HttpServer::new(move || {
+            let body = body.clone();
+            App::new().default_service(web::to(move || {
+                let body = body.clone();
+                async move {
+                    HttpResponse::build(actix_web::http::StatusCode::from_u16(status).unwrap()).json(body)
+                }
+            }))
+        })
+            .workers(1)
+            .disable_signals()
+            .bind(("127.0.0.1", 0))
+            .unwrap();
+        let url = format!("http://{}", server.addrs()[0]);
+        actix_web::rt::spawn(server.run());
+        url
+    }
+
+    // Requests a mock server is handling right now, and the most it handled at once
+    #[derive(Default)]
+    struct InFlight {
+        current: AtomicUsize,
+        peak: AtomicUsize,
+    }
+
+    #[actix_web::test]
+    async fn tmdb_requests_stay_within_scrape_concurrency() {
+        const CEILING: usize = 3;
+
+        let in_flight = web::Data::new(InFlight::default());
+        let server = HttpServer::new({
+            let in_flight = in_flight.clone();
+            move || App::new()
+                .app_data(in_flight.clone())
+                .default_service(web::to(|in_flight: web::Data<InFlight>| async move {
+                    let now = in_flight.current.fetch_add(1, Ordering::SeqCst) + 1;
+                    in_flight.peak.fetch_max(now, Ordering::SeqCst);
+                    tokio::time::sleep(std::time::Duration::from_millis(50)).await;
+                    in_flight.current.fetch_sub(1, Ordering::SeqCst);
+                    HttpResponse::Ok().json(json!({ "results": [], "total_pages": 1 }))
+                }))
+        })
+            .workers(1)
+            .disable_signals()
+            .bind(("127.0.0.1", 0))
+            .unwrap();
+        let url = format!("http://{}/3/movie/popular", server.addrs()[0]);
+        actix_web::rt::spawn(server.run());
+
+        let mut service = test_service();
+        service.scrape_concurrency = CEILING;
+        service.scrape_permits = tokio::sync::Semaphore::new(CEILING);
+
+        let client = reqwest::Client::new();
+        let results = futures_util::future::join_all(
+            (0..CEILING * 4).map(|_| service.tmdb_get(&client, "Bearer test-key", &url))
+        ).await;
+
+        assert!(results.iter().all(|r| matches!(r, Ok(Some(_)))));
+        assert_eq!(in_flight.peak.load(Ordering::SeqCst), CEILING);
+    }
+
+    #[test]
+    fn set_latest_evicts_down_to_cache_limit() {
+        let older = chrono::Utc::now() - chrono::Duration::hours(12);
+        let newer = chrono::Utc::now();
+        let items: Vec<Content> = [(1, older, 90.0), (2, newer, 10.0), (3, newer, 50.0), (4, older, 99.0), (5, newer, 30.0)]
+            .into_iter()
+            .map(|(id, fetched_at, popularity)| Content {
+                fetched_at: Some(fetched_at),
+                popularity: Some(popularity),
+                ..content("movie", id)
+            })
+            .collect();
+
+        let mut cache = ContentCache::new();
+        cache.set_latest(items, 100.0, Some(2));
+
+        // Both items from the older refresh go first, however popular, then the least
+        // popular of the newer. The rest keep their order.
+        let kept: Vec<i64> = cache.data["latest"].iter().map(|c| c.tmdb_id).collect();
+        assert_eq!(kept, vec![3, 5]);
+        assert_eq!(cache.by_id.len(), 2);
+        assert!(cache.find("movie", 1).is_none());
+    }
+
+    #[test]
+    fn connection_string_keeps_key_padding() {
+        let info = parse_connection_string(
+            "DefaultEndpointsProtocol=https;AccountName=scout;AccountKey=c2VjcmV0a2V5==;EndpointSuffix=core.windows.net"
+        ).unwrap();
+
+        assert_eq!(info.account_name, "scout");
+        assert_eq!(info.account_key, "c2VjcmV0a2V5==");
+        assert_eq!(info.endpoint_suffix.as_deref(), Some("core.windows.net"));
+        assert_eq!(info.default_endpoints_protocol.as_deref(), Some("https"));
+        assert_eq!(info.custom_blob_endpoint(), None);
+    }
+
+    #[test]
+    fn connection_string_with_sovereign_endpoint_suffix() {
+        let info = parse_connection_string(
+            "AccountName=scout;AccountKey=a2V5;EndpointSuffix=core.usgovcloudapi.net"
+        ).unwrap();
+
+        assert_eq!(info.endpoint_suffix.as_deref(), Some("core.usgovcloudapi.net"));
+        assert_eq!(info.custom_blob_endpoint().as_deref(), Some("https://scout.blob.core.usgovcloudapi.net"));
+    }
 
-    async fn update_content(&self) -> Result<()> {
-        // Check if update is needed
-        {
-            let cache = self.cache.read();
-            if !cache.needs_update() {
-                return Ok(());
-            }
+    #[test]
+    fn connection_string_trims_whitespace() {
+        let info = parse_connection_string(
+            " AccountName = scout ; AccountKey = a2V5== ;\n EndpointSuffix = core.chinacloudapi.cn ; "
+        ).unwrap();
+
+        assert_eq!(info.account_name, "scout");
+        assert_eq!(info.account_key, "a2V5==");
+        assert_eq!(info.endpoint_suffix.as_deref(), Some("core.chinacloudapi.cn"));
+    }
+
+    #[test]
+    fn connection_string_requires_name_and_key() {
+        for connection_string in [
+            "AccountName=;AccountKey=a2V5==",
+            "AccountName=scout;AccountKey=",
+            "AccountName=  ;AccountKey=a2V5==",
+            "AccountKey=a2V5==",
+            "AccountName=scout",
+            "",
+        ] {
+            assert!(parse_connection_string(connection_string).is_err(), "accepted {:?}", connection_string);
         }
+    }
 
-        println!("Starting content scraping...");
-        let content = self.scrape_content().await?;
-        println!("Scraped {} items", content.len());
+    #[test]
+    fn azurite_connection_strings_use_emulator_endpoint() {
+        // The shorthand and the full form Azurite's documentation gives
+        let shorthand = parse_connection_string("UseDevelopmentStorage=true").unwrap();
+        let full = parse_connection_string(&format!(
+            "DefaultEndpointsProtocol=http;AccountName={};AccountKey={};BlobEndpoint={}/;",
+            AZURITE_ACCOUNT_NAME, AZURITE_ACCOUNT_KEY, AZURITE_BLOB_ENDPOINT
+        )).unwrap();
 
-        // Create cache data outside the lock
-        let cache_data = {
-            let mut cache = self.cache.write();
-            cache.data.insert("latest".to_string(), content);
-            cache.used_recommendations.clear();
-            cache.last_updated = chrono::Utc::now();
+        for info in [shorthand, full] {
+            assert_eq!(info.account_name, "devstoreaccount1");
+            assert_eq!(info.account_key, AZURITE_ACCOUNT_KEY);
+            assert_eq!(info.custom_blob_endpoint().as_deref(), Some("http://127.0.0.1:10000/devstoreaccount1"));
+        }
+    }
 
-            CacheData {
-                content: cache.data.get("latest").cloned().unwrap_or_default(),
-                used_recommendations: cache.used_recommendations.clone(),
-                last_updated: cache.last_updated,
-            }
-        }; // Lock is dropped here
+    #[test]
+    fn blob_blocks_split_at_block_size() {
+        let payload: Vec<u8> = (0..10).collect();
 
-        // Save to blob after releasing the lock
-        self.save_to_blob(&cache_data).await?;
+        let blocks = blob_blocks(&payload, 4);
+        let sizes: Vec<usize> = blocks.iter().map(|(_, chunk)| chunk.len()).collect();
+        assert_eq!(sizes, vec![4, 4, 2]);
+        assert_eq!(blocks.iter().map(|(id, _)| id.as_str()).collect::<Vec<_>>(),
+                   vec!["block-000000", "block-000001", "block-000002"]);
+        assert_eq!(blocks.iter().flat_map(|(_, chunk)| chunk.iter().copied()).collect::<Vec<_>>(), payload);
+    }
 
-        Ok(())
+    #[test]
+    fn blob_blocks_exact_multiple_has_no_empty_block() {
+        let payload = vec![7u8; 12];
+
+        let sizes: Vec<usize> = blob_blocks(&payload, 4).iter().map(|(_, chunk)| chunk.len()).collect();
+        assert_eq!(sizes, vec![4, 4, 4]);
     }
 
-    async fn save_to_blob(&self, cache_data: &CacheData) -> Result<()> {
-        let json = serde_json::to_string(cache_data)?;
-        println!("JSON serialized, size: {} bytes", json.len());
+    #[test]
+    fn blob_blocks_short_payload_is_one_block() {
+        let payload = vec![7u8; 3];
 
-        let mut encoder = flate2::write::GzEncoder::new(Vec::new(), flate2::Compression::default());
-        encoder.write_all(json.as_bytes())?;
-        let compressed = encoder.finish()?;
-        println!("Compressed size: {} bytes", compressed.len());
+        let blocks = blob_blocks(&payload, 4);
+        assert_eq!(blocks.len(), 1);
+        assert_eq!(blocks[0].1, &payload[..]);
+        assert_eq!(blob_blocks(&payload, 3).len(), 1);
+    }
 
-        // Create a blob client for our file
-        let blob_name = "latest.json.gz";
-        let blob_client = self.blob_client.blob_client(blob_name);
+    #[test]
+    fn detail_genres_are_unique() {
+        let service = test_service();
+        let details = json!({
+            "genres": [{ "id": 18, "name": "Drama" }, { "id": 80, "name": "Crime" }, { "id": 18, "name": "Drama" }],
+        });
 
-        println!("Attempting to upload blob: {}", blob_name);
+        assert_eq!(service.parse_enrichment(&details).genres, vec!["Drama", "Crime"]);
+    }
 
-        // Create metadata using standard Headers
-        use azure_core::headers::Headers;
-        let mut metadata = Headers::new();
-        metadata.insert("encoding", "gzip");
-        metadata.insert("items", &cache_data.content.len().to_string());
-        metadata.insert("last-updated", &cache_data.last_updated.to_rfc3339());
+    #[test]
+    fn list_genres_are_unique() {
+        let service = test_service();
+        // Two ids naming the same genre, as well as a repeated id
+        *service.genre_names.write() = HashMap::from([
+            (18, "Drama".to_string()),
+            (80, "Crime".to_string()),
+            (10766, "Drama".to_string()),
+        ]);
+        let item = json!({ "genre_ids": [18, 80, 10766, 18] });
 
-        // Try to upload with retries
-        let mut retry_count = 0;
-        let max_retries = 3;
-        let mut last_error = None;
+        assert_eq!(service.list_enrichment(&item).genres, vec!["Drama", "Crime"]);
+    }
 
-        while retry_count < max_retries {
-            match blob_client.put_block_blob(compressed.clone())
-                .content_type("application/gzip")
-                .metadata(&metadata)
-                .await
-            {
-                Ok(_) => {
-                    println!("Successfully uploaded blob: {}", blob_name);
-                    return Ok(());
-                },
-                Err(e) => {
-                    println!("Upload attempt {} failed: {}", retry_count + 1, e);
-                    last_error = Some(e);
-                    retry_count += 1;
-
-                    if retry_count < max_retries {
-                        let delay = std::time::Duration::from_secs(2u64.pow(retry_count as u32));
-                        println!("Waiting {:?} before retry...", delay);
-                        tokio::time::sleep(delay).await;
-                    }
-                }
+    #[actix_web::test]
+    async fn empty_list_is_not_a_failure() {
+        let service = test_service();
+        let url = format!("{}/3/movie/popular?language=en-US", mock_tmdb(200, json!({ "results": [], "total_pages": 1 })));
+
+        let (content, outcome) = service.fetch_list(&reqwest::Client::new(), "Bearer test-key", &Mutex::new(ContentTracker::new()),
+                                                    "movie", url, false, MAX_LIST_PAGES).await.unwrap();
+
+        assert!(content.is_empty());
+        assert_eq!(outcome, ListOutcome::Empty);
+        assert!(service.circuit_breaker.failures.lock().is_empty());
+    }
+
+    #[actix_web::test]
+    async fn malformed_list_is_a_failure() {
+        let service = test_service();
+        let url = format!("{}/3/movie/popular?language=en-US",
+                          mock_tmdb(200, json!({ "status_code": 34, "status_message": "The resource could not be found." })));
+
+        let (content, outcome) = service.fetch_list(&reqwest::Client::new(), "Bearer test-key", &Mutex::new(ContentTracker::new()),
+                                                    "movie", url, false, MAX_LIST_PAGES).await.unwrap();
+
+        assert!(content.is_empty());
+        assert_eq!(outcome, ListOutcome::Failed);
+        assert_eq!(service.circuit_breaker.failures.lock().len(), 1);
+    }
+
+    #[actix_web::test]
+    async fn unsuccessful_list_response_is_a_failure() {
+        let service = test_service();
+        let url = format!("{}/3/movie/popular?language=en-US", mock_tmdb(404, json!({ "success": false })));
+
+        let (_, outcome) = service.fetch_list(&reqwest::Client::new(), "Bearer test-key", &Mutex::new(ContentTracker::new()),
+                                              "movie", url, false, MAX_LIST_PAGES).await.unwrap();
+
+        assert_eq!(outcome, ListOutcome::Failed);
+    }
+
+    #[test]
+    fn backoff_delay_stays_within_jittered_bounds() {
+        let base = std::time::Duration::from_millis(500);
+        let max = std::time::Duration::from_secs(10);
+
+        for retry in 1..=8 {
+            // 500ms doubling per retry, capped at 10s, then jittered down to half
+            let full = (base * 2u32.pow(retry - 1)).min(max);
+            for _ in 0..100 {
+                let delay = backoff_delay(retry, base, max);
+                assert!(delay >= full / 2 && delay <= full, "retry {}: {:?} outside {:?}..={:?}", retry, delay, full / 2, full);
             }
         }
+    }
+
+    #[test]
+    fn backoff_delay_never_exceeds_max_for_large_retries() {
+        let max = std::time::Duration::from_secs(30);
+
+        assert!(backoff_delay(u32::MAX, std::time::Duration::from_secs(2), max) <= max);
+    }
+
+    #[test]
+    fn integer_vote_average_parses() {
+        let movie = json!({ "vote_average": 8 });
+
+        assert_eq!(json_number(&movie["vote_average"]), Some(8.0));
+    }
+
+    #[test]
+    fn json_number_accepts_floats_and_numeric_strings() {
+        assert_eq!(json_number(&json!(7.5)), Some(7.5));
+        assert_eq!(json_number(&json!(" 6.4 ")), Some(6.4));
+        assert_eq!(json_number(&json!("n/a")), None);
+        assert_eq!(json_number(&Value::Null), None);
+    }
+
+    fn recommendation_query(query: &str) -> std::result::Result<RecommendationQuery, actix_web::error::QueryPayloadError> {
+        web::Query::<RecommendationQuery>::from_query(query).map(web::Query::into_inner)
+    }
+
+    #[test]
+    fn query_lists_are_trimmed_and_skip_empty_entries() {
+        let query = recommendation_query("genres=Action,%20Comedy,,Drama,&spoken_languages=en,+fr&minimum_rating=7.5").unwrap();
+
+        assert_eq!(query.genres, vec!["Action", "Comedy", "Drama"]);
+        assert_eq!(query.spoken_languages, vec!["en", "fr"]);
+        assert_eq!(query.minimum_rating, Some(7.5));
+    }
 
-        if let Some(e) = last_error {
-            println!("Failed to upload blob after {} attempts", max_retries);
-            return Err(anyhow::anyhow!("Failed to upload blob after {} attempts: {}", max_retries, e));
+    #[test]
+    fn query_list_entries_may_contain_encoded_separators() {
+        let query = recommendation_query("genres=Sci-Fi%20%26%20Fantasy,War%20%26%20Politics").unwrap();
+
+        assert_eq!(query.genres, vec!["Sci-Fi & Fantasy", "War & Politics"]);
+    }
+
+    #[test]
+    fn empty_query_uses_defaults() {
+        for query in ["", "genres=", "genres=,,"] {
+            let query = recommendation_query(query).unwrap();
+            assert!(query.genres.is_empty());
+            assert_eq!(query.format, ResponseFormat::Json);
+            assert!(!query.in_theaters_only);
+            assert_eq!(query.preferences().minimum_rating, 0.0);
         }
+    }
 
-        Ok(())
+    #[test]
+    fn malformed_query_values_are_rejected() {
+        for query in [
+            "genres=Action&minimum_rating=high",
+            "genres=Action&page=-1",
+            "genres=Action&page_size=1.5",
+            "genres=Action&min_year=199x",
+            "genres=Action&in_theaters_only=yes",
+            "genres=Action&strategy=random",
+            "genres=Action&format=xml",
+            "genres=Action&seed=18446744073709551616",
+        ] {
+            assert!(recommendation_query(query).is_err(), "accepted {:?}", query);
+        }
     }
 
-    fn process_blob_data(&self, data: &[u8]) -> Result<CacheData> {
-        let mut decoder = flate2::read::GzDecoder::new(data);
-        let mut decompressed = String::new();
-        std::io::Read::read_to_string(&mut decoder, &mut decompressed)?;
+    #[actix_web::test]
+    async fn malformed_query_gets_json_400() {
+        let app = actix_web::test::init_service(
+            App::new()
+                .app_data(query_config())
+                .app_data(web::Data::new(test_service()))
+                .route("/recommendations", web::get().to(get_query_recommendations))
+        ).await;
 
-        let cache_data: CacheData = serde_json::from_str(&decompressed)?;
-        Ok(cache_data)
+        let request = actix_web::test::TestRequest::get()
+            .uri("/recommendations?genres=Action&minimum_rating=high")
+            .to_request();
+        let response = actix_web::test::call_service(&app, request).await;
+
+        assert_eq!(response.status(), actix_web::http::StatusCode::BAD_REQUEST);
+        let body: Value = actix_web::test::read_body_json(response).await;
+        assert!(body["error"].as_str().unwrap().starts_with("Invalid query string"));
     }
 
-    async fn get_recommendations(&self, prefs: &UserPreferences) -> Result<Vec<Content>> {
-        println!("ContentService: Processing recommendation request");
-        let user_key = self.generate_user_key(prefs);
+    #[test]
+    fn state_hash_ignores_save_time_but_not_state() {
+        let mut cache = ContentCache::new();
+        cache.set_latest(vec![content("movie", 1), content("tv", 2)], 100.0, None);
+        for id in ["movie:1", "tv:2"] {
+            cache.used_recommendations.entry("user".to_string()).or_default().insert(id.to_string());
+        }
+        let first = cache.to_cache_data();
 
-        // Try to load from cache first
-        let content = {
-            let cache = self.cache.read();
-            if !cache.needs_update() {
-                cache.data.get("latest").cloned()
-            } else {
-                None
-            }
-        };
+        // A later snapshot of the same state, with its sets rebuilt, hashes the same
+        let mut later = cache.to_cache_data();
+        later.last_updated = first.last_updated + chrono::Duration::hours(6);
+        later.used_recommendations = first.used_recommendations.iter()
+            .map(|(user, ids)| (user.clone(), ids.iter().cloned().collect()))
+            .collect();
+        assert_eq!(state_hash(&first).unwrap(), state_hash(&later).unwrap());
 
-        let recommendations = if let Some(content) = content {
-            // Use cached content
-            self.filter_recommendations(content, prefs, &user_key)?
-        } else {
-            // Fetch fresh content
-            println!("Starting fresh content fetch");
-            let content = self.scrape_content().await?;
+        // Clearing a user's used recommendations is a change worth saving
+        later.used_recommendations.clear();
+        assert_ne!(state_hash(&first).unwrap(), state_hash(&later).unwrap());
+    }
 
-            // Update cache
-            {
-                let mut cache = self.cache.write();
-                cache.data.insert("latest".to_string(), content.clone());
-                cache.used_recommendations.clear();
-                cache.last_updated = chrono::Utc::now();
-
-                // Create cache data and drop lock before saving
-                let cache_data = CacheData {
-                    content: cache.data.get("latest").cloned().unwrap_or_default(),
-                    used_recommendations: cache.used_recommendations.clone(),
-                    last_updated: cache.last_updated,
-                };
-                drop(cache);
+    #[test]
+    fn equally_rated_items_keep_a_deterministic_order() {
+        let items: Vec<Content> = [("movie", 4, 100, "2020"), ("movie", 2, 500, "2010"), ("tv", 1, 100, "2022"),
+            ("movie", 3, 100, "2022"), ("movie", 1, 100, "2022")]
+            .into_iter()
+            .map(|(media_type, id, votes, year)| Content {
+                rating: Some(7.0),
+                vote_count: Some(votes),
+                year: Some(year.to_string()),
+                ..content(media_type, id)
+            })
+            .collect();
 
-                // Save to blob outside the lock
-                self.save_to_blob(&cache_data).await?;
-            }
+        // Most votes, then newest, then media type and id, whatever the catalog order
+        let service = test_service();
+        let prefs: UserPreferences = serde_json::from_value(json!({"favorite_genres": []})).unwrap();
+        let mut orders = Vec::new();
+        for catalog in [items.clone(), items.into_iter().rev().collect()] {
+            service.cache.write().set_latest(catalog, 100.0, None);
+            let order: Vec<String> = service.fallback_recommendations(&prefs).into_iter().map(|c| c.id).collect();
+            orders.push(order);
+        }
+        assert_eq!(orders[0], vec!["movie:2", "movie:1", "movie:3", "tv:1", "movie:4"]);
+        assert_eq!(orders[0], orders[1]);
+    }
 
-            // Filter recommendations
-            self.filter_recommendations(content, prefs, &user_key)?
-        };
+    // A yearless item, one from 2015, and the item order under a Year-first tie-break
+    fn yearless_cases(policy: YearlessPolicy) -> (Content, Content, std::cmp::Ordering) {
+        let yearless = content("movie", 1);
+        let dated = Content { year: Some("2015".to_string()), ..content("movie", 2) };
+        let order = break_tie(&yearless, &dated, &[TieBreaker::Year], policy);
+        (yearless, dated, order)
+    }
 
-        Ok(recommendations)
+    #[test]
+    fn yearless_include_passes_year_filters_and_sorts_last() {
+        let (yearless, dated, order) = yearless_cases(YearlessPolicy::Include);
+        assert!(YearlessPolicy::Include.within(&yearless, Some(2000), Some(2010)));
+        assert!(!YearlessPolicy::Include.within(&dated, Some(2000), Some(2010)));
+        assert_eq!(order, std::cmp::Ordering::Greater);
     }
 
-    fn filter_recommendations(&self, content: Vec<Content>, prefs: &UserPreferences, user_key: &str) -> Result<Vec<Content>> {
-        println!("Starting content filtering with {} items", content.len());
+    #[test]
+    fn yearless_exclude_fails_year_filters_and_sorts_last() {
+        let (yearless, dated, order) = yearless_cases(YearlessPolicy::Exclude);
+        assert!(!YearlessPolicy::Exclude.within(&yearless, None, None));
+        assert!(YearlessPolicy::Exclude.within(&dated, None, None));
+        assert_eq!(order, std::cmp::Ordering::Greater);
+    }
 
-        // Filter content before taking the lock
-        let mut available: Vec<_> = content.into_iter()
-            .filter(|c| {
-                c.rating.unwrap_or(0.0) >= prefs.minimum_rating &&
-                    c.genre.iter().any(|g| prefs.favorite_genres.contains(g))
+    #[test]
+    fn yearless_oldest_passes_only_open_minimums_and_sorts_last() {
+        let (yearless, _, order) = yearless_cases(YearlessPolicy::TreatAsOldest);
+        assert!(YearlessPolicy::TreatAsOldest.within(&yearless, None, Some(1900)));
+        assert!(!YearlessPolicy::TreatAsOldest.within(&yearless, Some(1900), None));
+        assert_eq!(order, std::cmp::Ordering::Greater);
+    }
+
+    #[test]
+    fn yearless_newest_passes_only_open_maximums_and_sorts_first() {
+        let (yearless, _, order) = yearless_cases(YearlessPolicy::TreatAsNewest);
+        assert!(YearlessPolicy::TreatAsNewest.within(&yearless, Some(2100), None));
+        assert!(!YearlessPolicy::TreatAsNewest.within(&yearless, None, Some(2100)));
+        assert_eq!(order, std::cmp::Ordering::Less);
+    }
+
+    // Two batch profiles after the same genre, against a catalog of three Action items
+    fn batch_of_two(shared_dedup: bool) -> Vec<(String, FilteredRecommendations)> {
+        let service = test_service();
+        let catalog = (1..=3)
+            .map(|id| Content { genre: vec!["Action".to_string()], rating: Some(8.0), ..content("movie", id) })
+            .collect();
+        service.cache.write().set_latest(catalog, 100.0, None);
+
+        let profiles: Vec<(String, UserPreferences)> = [("first", 1.0), ("second", 2.0)].into_iter()
+            .map(|(name, minimum_rating)| {
+                let prefs = serde_json::from_value(json!({
+                    "favorite_genres": ["Action"],
+                    "minimum_rating": minimum_rating,
+                    "allow_fallback": false,
+                })).unwrap();
+                (name.to_string(), prefs)
             })
             .collect();
+        service.batch_recommendations(&profiles, shared_dedup).unwrap()
+    }
 
-        println!("Found {} items matching rating and genre criteria", available.len());
+    fn batch_ids(result: &FilteredRecommendations) -> HashSet<String> {
+        result.items.iter().map(|c| c.id.clone()).collect()
+    }
 
-        // Take a write lock only when needed
-        {
-            let mut cache = self.cache.write();
-            let used_recs = cache.used_recommendations
-                .entry(user_key.to_string())
-                .or_insert_with(HashSet::new);
+    #[test]
+    fn shared_dedup_keeps_later_profiles_off_earlier_picks() {
+        let results = batch_of_two(true);
 
-            // Filter out used recommendations
-            available.retain(|c| !used_recs.contains(&c.title));
-            println!("After filtering used recommendations: {} items remain", available.len());
+        assert_eq!(batch_ids(&results[0].1).len(), 3);
+        assert!(results[1].1.items.is_empty());
+    }
 
-            // Reset if running low
-            if available.len() < 10 {
-                println!("Running low on recommendations, resetting for user");
-                used_recs.clear();
-                drop(cache);
+    #[test]
+    fn without_shared_dedup_profiles_pick_independently() {
+        let results = batch_of_two(false);
 
-                let cache_read = self.cache.read();
-                if let Some(latest_content) = cache_read.data.get("latest") {
-                    available = latest_content.iter()
-                        .filter(|c| {
-                            c.rating.unwrap_or(0.0) >= prefs.minimum_rating &&
-                                c.genre.iter().any(|g| prefs.favorite_genres.contains(g))
-                        })
-                        .cloned()
-                        .collect();
+        assert_eq!(batch_ids(&results[0].1).len(), 3);
+        assert_eq!(batch_ids(&results[0].1), batch_ids(&results[1].1));
+    }
+
+    #[test]
+    fn refresh_invalidates_cached_pages() {
+        let service = test_service();
+        let action = |id| Content { genre: vec!["Action".to_string()], rating: Some(8.0), ..content("movie", id) };
+        let prefs: UserPreferences = serde_json::from_value(json!({"favorite_genres": ["Action"]})).unwrap();
+        service.cache.write().set_latest((1..=2).map(action).collect(), 100.0, None);
+        assert_eq!(service.recommendation_page(&prefs, 7, 1, 10).unwrap().total_matching, 2);
+
+        // Within a generation the page is served from the cache, even if the catalog moved
+        service.cache.write().data.get_mut("latest").unwrap().push(action(3));
+        assert_eq!(service.recommendation_page(&prefs, 7, 1, 10).unwrap().total_matching, 2);
+
+        // A refresh starts a new generation, so the page is recomputed
+        service.cache.write().set_latest((1..=4).map(action).collect(), 100.0, None);
+        let page = service.recommendation_page(&prefs, 7, 1, 10).unwrap();
+        assert_eq!(page.total_matching, 4);
+        assert_eq!(page.items.len(), 4);
+    }
+
+    #[test]
+    fn bare_tmdb_id_watchlists_load_as_content_ids() {
+        let blob = json!({
+            "schema_version": 1,
+            "content": [content("movie", 5), content("tv", 7), content("movie", 9), content("tv", 9)],
+            "used_recommendations": {},
+            "watchlists": {"user": [5, 7, 8, 9]},
+            "last_updated": chrono::Utc::now(),
+        });
+        let mut encoder = flate2::write::GzEncoder::new(Vec::new(), flate2::Compression::default());
+        encoder.write_all(&serde_json::to_vec(&blob).unwrap()).unwrap();
+        let data = encoder.finish().unwrap();
+
+        // Movies win as they used to, shows are found when there's no such movie, and
+        // ids in neither are taken to be movies
+        let cache_data = test_service().process_blob_data(&data, BlobFormat::Json).unwrap();
+        let expected: HashSet<String> = ["movie:5", "tv:7", "movie:8", "movie:9"].map(String::from).into();
+        assert_eq!(cache_data.watchlists["user"], expected);
+    }
+
+    #[test]
+    fn watchlisted_items_are_excluded_by_media_type() {
+        let service = test_service();
+        let catalog = [("movie", 1), ("tv", 1)]
+            .map(|(media_type, id)| Content { genre: vec!["Drama".to_string()], ..content(media_type, id) });
+        service.cache.write().set_latest(catalog.to_vec(), 100.0, None);
+        service.cache.write().watchlists.insert("user".to_string(), HashSet::from(["tv:1".to_string()]));
+
+        let prefs: UserPreferences = serde_json::from_value(json!({
+            "favorite_genres": ["Drama"],
+            "user_id": "user",
+            "exclude_watchlisted": true,
+        })).unwrap();
+        let eligible = service.eligibility(&prefs);
+        assert!(eligible(&catalog[0]));
+        assert!(!eligible(&catalog[1]));
+    }
+
+    #[test]
+    fn fnv1a_matches_reference_vectors() {
+        assert_eq!(fnv1a(b""), 0xcbf29ce484222325);
+        assert_eq!(fnv1a(b"a"), 0xaf63dc4c8601ec8c);
+        assert_eq!(fnv1a(b"foobar"), 0x85944171f73967e8);
+    }
+
+    #[test]
+    fn user_key_is_pinned_for_known_preferences() {
+        // Saved used_recommendations are keyed by this, so it must never change
+        let prefs: UserPreferences = serde_json::from_value(json!({
+            "favorite_genres": ["Drama", "Action"],
+            "minimum_rating": 7.0,
+        })).unwrap();
+        assert_eq!(test_service().generate_user_key(&prefs), "user_b7b50b56eb52448a");
+    }
+
+    #[actix_web::test]
+    async fn older_snapshot_never_overwrites_a_newer_save() {
+        let service = test_service();
+        let older = service.cache.write().to_cache_data();
+        let newer = service.cache.write().to_cache_data();
+        assert!(older.snapshot < newer.snapshot);
+
+        // The newer snapshot has been saved. Saves of the older one racing each other
+        // must all stand down without touching storage, which isn't reachable here.
+        *service.last_saved_snapshot.lock().await = newer.snapshot;
+        let results = futures_util::future::join_all(
+            (0..4).map(|i| {
+                let (service, older) = (&service, &older);
+                async move {
+                    if i % 2 == 0 { service.save_to_blob(older).await } else { service.touch_blob(older).await }
                 }
-            }
-        }
+            })
+        ).await;
 
-        // Shuffle and select recommendations
-        use rand::seq::SliceRandom;
-        let mut rng = rand::thread_rng();
-        available.shuffle(&mut rng);
+        assert!(results.iter().all(|r| r.is_ok()));
+        assert_eq!(*service.last_saved_snapshot.lock().await, newer.snapshot);
+    }
 
-        let recommendations: Vec<_> = available.into_iter().take(20).collect();
-        println!("Selected {} recommendations", recommendations.len());
+    // Validation error for preferences overriding the valid base below with fields
+    fn validation_error(fields: Value) -> String {
+        let mut prefs = json!({"favorite_genres": ["Action"], "minimum_rating": 6.0});
+        prefs.as_object_mut().unwrap().extend(fields.as_object().unwrap().clone());
+        let prefs: UserPreferences = serde_json::from_value(prefs).unwrap();
+        prefs.validate().unwrap_err()
+    }
 
-        // Mark selected items as used
-        {
-            let mut cache = self.cache.write();
-            let used_recs = cache.used_recommendations
-                .entry(user_key.to_string())
-                .or_insert_with(HashSet::new);
+    #[test]
+    fn valid_preferences_pass_validation() {
+        let prefs: UserPreferences = serde_json::from_value(json!({
+            "favorite_genres": ["Action"],
+            "minimum_rating": 6.0,
+            "min_year": 2000,
+            "max_year": 2000,
+            "expand_genres": true,
+        })).unwrap();
+        assert_eq!(prefs.validate(), Ok(()));
+    }
 
-            for content in &recommendations {
-                used_recs.insert(content.title.clone());
-            }
-        }
+    #[test]
+    fn minimum_rating_out_of_range_is_invalid() {
+        assert_eq!(validation_error(json!({"minimum_rating": 11.0})), "minimum_rating must be between 0 and 10, got 11");
+    }
 
-        Ok(recommendations)
+    #[test]
+    fn min_year_after_max_year_is_invalid() {
+        assert_eq!(validation_error(json!({"min_year": 2020, "max_year": 2010})), "min_year (2020) is after max_year (2010)");
     }
-}
 
-async fn get_recommendations(
-    prefs: web::Json<UserPreferences>,
-    service: web::Data<ContentService>,
-) -> HttpResponse {
-    println!("Received recommendation request with preferences: {:?}", prefs);
+    #[test]
+    fn expand_genres_without_genres_is_invalid() {
+        assert_eq!(
+            validation_error(json!({"favorite_genres": [], "expand_genres": true})),
+            "expand_genres needs at least one favorite genre to expand"
+        );
+    }
 
-    match service.get_recommendations(&prefs).await {
-        Ok(content) => {
-            println!("Returning {} recommendations to frontend", content.len());
-            // Don't save to blob here since we already did in get_recommendations
-            HttpResponse::Ok()
-                .content_type("application/json")
-                .json(content)
-        },
-        Err(e) => {
-            eprintln!("Error getting recommendations: {}", e);
-            HttpResponse::InternalServerError()
-                .content_type("application/json")
-                .json(json!({
-                    "error": format!("Failed to get recommendations: {}", e)
-                }))
+    #[test]
+    fn min_quality_out_of_range_is_invalid() {
+        assert_eq!(validation_error(json!({"min_quality": -1.0})), "min_quality must be between 0 and 10, got -1");
+    }
+
+    #[test]
+    fn zero_max_per_provider_is_invalid() {
+        assert_eq!(validation_error(json!({"max_per_provider": 0})), "max_per_provider must be at least 1");
+    }
+
+    #[test]
+    fn every_problem_is_reported() {
+        let error = validation_error(json!({"min_year": 2020, "max_year": 2010, "max_per_provider": 0}));
+        assert_eq!(error, "min_year (2020) is after max_year (2010); max_per_provider must be at least 1");
+    }
+
+    // Timing of the genre filter against scanning the favorites list per genre. Run with
+    // cargo test --release -- --ignored genre_filter_timing --nocapture
+    #[test]
+    #[ignore]
+    fn genre_filter_timing() {
+        const PASSES: u32 = 50;
+        let catalog: Vec<Content> = (0..20_000)
+            .map(|id| Content {
+                genre: (0..3).map(|g| format!("Genre {}", (id * 7 + g * 13) % 60)).collect(),
+                rating: Some(8.0),
+                ..content("movie", id)
+            })
+            .collect();
+        let prefs: UserPreferences = serde_json::from_value(json!({
+            "favorite_genres": (0..30).map(|g| format!("Genre {}", g)).collect::<Vec<_>>(),
+        })).unwrap();
+
+        let started = std::time::Instant::now();
+        let mut scanned = 0;
+        for _ in 0..PASSES {
+            scanned = catalog.iter()
+                .filter(|c| c.genre.iter().any(|g| prefs.favorite_genres.contains(g)))
+                .count();
+        }
+        let scan_time = started.elapsed() / PASSES;
+
+        let started = std::time::Instant::now();
+        let mut matched = 0;
+        for _ in 0..PASSES {
+            let genres = prefs.favorite_genre_set();
+            matched = catalog.iter().filter(|c| prefs.matches_genres(c, &genres)).count();
         }
+        let set_time = started.elapsed() / PASSES;
+
+        println!("{} items, {} favorites: list scan {:?}, set {:?} per pass",
+                 catalog.len(), prefs.favorite_genres.len(), scan_time, set_time);
+        assert_eq!(scanned, matched);
     }
-}
 
-#[actix_web::main]
-async fn main() -> Result<()> {
-    dotenv().ok();
+    // US and GB providers that overlap on Netflix, plus a CA region that isn't asked for
+    fn overlapping_region_providers() -> std::collections::BTreeMap<String, Vec<String>> {
+        [("US", vec!["Netflix", "Hulu"]), ("GB", vec!["BBC iPlayer", "Netflix"]), ("CA", vec!["Crave"])]
+            .into_iter()
+            .map(|(region, names)| (region.to_string(), names.into_iter().map(String::from).collect()))
+            .collect()
+    }
 
-    // Ensure TMDB_API_KEY is set
-    if env::var("TMDB_API_KEY").is_err() {
-        eprintln!("TMDB_API_KEY must be set in environment");
-        std::process::exit(1);
+    #[test]
+    fn union_flattening_lists_shared_providers_once_in_region_order() {
+        let regions = ["US".to_string(), "GB".to_string()];
+        let flattened = flatten_providers(&overlapping_region_providers(), &regions, ProviderFlattening::Union);
+        assert_eq!(flattened, vec!["Netflix", "Hulu", "BBC iPlayer"]);
     }
 
-    let service = ContentService::new().await?;
+    #[test]
+    fn primary_flattening_lists_only_the_first_region() {
+        let regions = ["GB".to_string(), "US".to_string()];
+        let flattened = flatten_providers(&overlapping_region_providers(), &regions, ProviderFlattening::Primary);
+        assert_eq!(flattened, vec!["BBC iPlayer", "Netflix"]);
+    }
 
-    // Perform initial content update
-    println!("Performing initial content update...");
-    service.update_content().await?;
-    println!("Initial content update completed");
+    #[test]
+    fn fallback_leaves_out_items_the_user_watched() {
+        let service = test_service();
+        let catalog = [("movie", 1, 9.0), ("tv", 1, 8.0), ("movie", 2, 7.0)]
+            .map(|(media_type, id, rating)| Content { rating: Some(rating), ..content(media_type, id) });
+        service.cache.write().set_latest(catalog.to_vec(), 100.0, None);
+        service.cache.write().watched.insert("user".to_string(), HashSet::from(["movie:1".to_string()]));
 
-    let service = web::Data::new(service);
-    let service_clone = service.clone();
+        let fallback = |prefs: Value| -> Vec<String> {
+            let prefs: UserPreferences = serde_json::from_value(prefs).unwrap();
+            service.fallback_recommendations(&prefs).into_iter().map(|c| c.id).collect()
+        };
+        assert_eq!(fallback(json!({"favorite_genres": [], "user_id": "user"})), vec!["tv:1", "movie:2"]);
+        assert_eq!(fallback(json!({"favorite_genres": []})), vec!["movie:1", "tv:1", "movie:2"]);
+    }
 
-    // Update content periodically
-    tokio::spawn(async move {
-        loop {
-            tokio::time::sleep(tokio::time::Duration::from_secs(12 * 3600)).await;
-            println!("Starting periodic content update...");
-            if let Err(e) = service_clone.update_content().await {
-                eprintln!("Error updating content: {}", e);
-            }
-        }
-    });
+    #[test]
+    fn bare_tmdb_id_watched_items_and_pins_load_as_content_ids() {
+        let blob = json!({
+            "content": [content("tv", 3), content("movie", 4)],
+            "used_recommendations": {},
+            "watched": {"user": [3, 4]},
+            "pins": [4, 3, "movie:4"],
+            "last_updated": chrono::Utc::now(),
+        });
+        let mut encoder = flate2::write::GzEncoder::new(Vec::new(), flate2::Compression::default());
+        encoder.write_all(&serde_json::to_vec(&blob).unwrap()).unwrap();
+        let data = encoder.finish().unwrap();
 
-    println!("Starting HTTP server on 0.0.0.0:8080");
-    HttpServer::new(move || {
-        let cors = Cors::default()
-            .allow_any_origin()
-            .allow_any_method()
-            .allow_any_header()
-            .max_age(3600);
+        // A pin saved both ways is kept once, in its first position
+        let cache_data = test_service().process_blob_data(&data, BlobFormat::Json).unwrap();
+        let watched: HashSet<String> = ["tv:3", "movie:4"].map(String::from).into();
+        assert_eq!(cache_data.watched["user"], watched);
+        assert_eq!(cache_data.pins, vec!["movie:4", "tv:3"]);
+    }
 
-        App::new()
-            .wrap(cors)
-            .app_data(service.clone())
-            .route("/recommendations", web::post().to(get_recommendations))
-    })
-        .bind("0.0.0.0:8080")?
-        .run()
-        .await?;
-    Ok(())
-}
\ No newline at end of file
+    #[actix_web::test]
+    async fn empty_catalog_during_a_scrape_is_503_with_retry_after() {
+        let service = web::Data::new(test_service());
+        service.refresh_in_progress.store(true, Ordering::SeqCst);
+        let prefs: UserPreferences = serde_json::from_value(json!({"favorite_genres": ["Action"]})).unwrap();
+
+        let response = recommendations_response(&prefs, false, ResponseFormat::Json, service).await;
+        assert_eq!(response.status(), actix_web::http::StatusCode::SERVICE_UNAVAILABLE);
+        assert_eq!(response.headers().get("Retry-After").unwrap(), "30");
+    }
+
+    #[actix_web::test]
+    async fn populated_catalog_without_matches_is_200_and_empty() {
+        let service = web::Data::new(test_service());
+        {
+            let mut cache = service.cache.write();
+            cache.set_latest(vec![Content { genre: vec!["Drama".to_string()], ..content("movie", 1) }], 100.0, None);
+            cache.last_updated = chrono::Utc::now();
+        }
+        let prefs: UserPreferences = serde_json::from_value(json!({
+            "favorite_genres": ["Action"],
+            "allow_fallback": false,
+        })).unwrap();
+
+        let response = recommendations_response(&prefs, false, ResponseFormat::Json, service).await;
+        assert_eq!(response.status(), actix_web::http::StatusCode::OK);
+        assert_eq!(response.headers().get("Cache-Control").unwrap(), "no-store");
+        let body: Value = serde_json::from_slice(&actix_web::body::to_bytes(response.into_body()).await.unwrap()).unwrap();
+        assert_eq!(body["items"], json!([]));
+        assert_eq!(body["fallback"], json!(false));
+    }
+}